@@ -0,0 +1,82 @@
+//! Whole-stream vs chunked parallel blocks for the stages that support `--chunk-size`,
+//! on a serialized 2048x1536 (~3 MP) image
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use wackypixels::transform::Transform;
+use wackypixels::transforms::{image, lzma::LzmaTransform, unicode::UnicodeTransform, wav::WavTransform};
+
+const WIDTH: u32 = 2048;
+const HEIGHT: u32 = 1536;
+const CHUNK_SIZE: usize = 1 << 20;
+
+/// What `ImageTransform` would produce for a noisy gradient, so compression has some work to do:
+/// magic, format version, width, height and channel format, then RGBA pixels
+fn serialized_image() -> Vec<u8> {
+  let mut data = Vec::with_capacity(14 + (WIDTH * HEIGHT * 4) as usize);
+  data.extend_from_slice(b"WPIM");
+  data.push(image::FORMAT_VERSION as u8);
+  data.extend_from_slice(&WIDTH.to_le_bytes());
+  data.extend_from_slice(&HEIGHT.to_le_bytes());
+  data.push(4);
+  let mut noise: u32 = 0x9E37_79B9;
+  for y in 0..HEIGHT {
+    for x in 0..WIDTH {
+      noise ^= noise << 13;
+      noise ^= noise >> 17;
+      noise ^= noise << 5;
+      let grain = (noise & 0x0F) as u8;
+      data.extend_from_slice(&[
+        (x / 8) as u8 ^ grain,
+        (y / 6) as u8 ^ grain,
+        ((x + y) / 14) as u8,
+        255,
+      ]);
+    }
+  }
+  data
+}
+
+fn bench_transform<T: Transform>(c: &mut Criterion, name: &str, whole: T, chunked: T, input: &[u8]) {
+  let mut group = c.benchmark_group(name);
+  group.sample_size(10);
+
+  for (label, transform) in [("whole", &whole), ("chunked", &chunked)] {
+    group.bench_with_input(BenchmarkId::new("encode", label), input, |b, input| {
+      b.iter(|| transform.encode(input.to_vec()).unwrap())
+    });
+
+    let encoded = transform.encode(input.to_vec()).unwrap();
+    group.bench_with_input(BenchmarkId::new("decode", label), &encoded, |b, encoded| {
+      b.iter(|| transform.decode(encoded.clone()).unwrap())
+    });
+  }
+
+  group.finish();
+}
+
+fn chunking(c: &mut Criterion) {
+  let image = serialized_image();
+  bench_transform(
+    c,
+    "lzma",
+    LzmaTransform::new(),
+    LzmaTransform::new().chunk_size(Some(CHUNK_SIZE)),
+    &image,
+  );
+  bench_transform(
+    c,
+    "unicode",
+    UnicodeTransform::new(),
+    UnicodeTransform::new().chunk_size(Some(CHUNK_SIZE)),
+    &image,
+  );
+  bench_transform(
+    c,
+    "wav",
+    WavTransform::new(),
+    WavTransform::new().chunk_size(Some(CHUNK_SIZE)),
+    &image,
+  );
+}
+
+criterion_group!(benches, chunking);
+criterion_main!(benches);
@@ -0,0 +1,13 @@
+//! Shared body of the `decode_*` fuzz targets
+use wackypixels::cli::TransformOptions;
+use wackypixels::pipeline_builder::builtin;
+
+/// Feed `data` to everything of the built-in transform `id` that reads untrusted input.
+/// Errors are fine, panics are what the fuzzer is looking for
+pub fn decode(id: &str, data: &[u8]) {
+  let transform = (builtin(id).expect("unknown built-in transform").build)(&TransformOptions::default());
+  let _ = transform.decode(data.to_vec());
+  let _ = transform.decode_partial(data.to_vec());
+  let _ = transform.sniff(data);
+  let _ = transform.stats(data);
+}
@@ -0,0 +1,116 @@
+//! Example wackypixels plugin that XORs every byte with a key.
+//!
+//! Built normally it's a shared library for `--plugin target/release/libwackypixels_xor.so`,
+//! built with `--target wasm32-unknown-unknown` it's a sandboxed `.wasm` module instead
+use std::ffi::c_char;
+
+const INFO: &str = concat!(
+  r#"{"id":"xor","name":"XOR","extension":"xor","description":"XOR every byte with a key","#,
+  r#""parameters":[{"name":"key","description":"Byte to XOR with","default":"42"}]}"#,
+  "\0",
+);
+
+fn xor(input: &[u8], params: &[u8]) -> Result<Vec<u8>, String> {
+  let params: serde_json::Value = serde_json::from_slice(params).map_err(|e| e.to_string())?;
+  let key = params["key"].as_str().unwrap_or("42");
+  let key: u8 = key.parse().map_err(|_| format!("key must be 0-255, not `{}`", key))?;
+  Ok(input.iter().map(|byte| byte ^ key).collect())
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+mod native {
+  use super::*;
+  use std::ffi::CStr;
+
+  #[repr(C)]
+  pub struct PluginBuffer {
+    ptr: *mut u8,
+    len: usize,
+    cap: usize,
+  }
+
+  type TransformFn = unsafe extern "C" fn(*const u8, usize, *const c_char, *mut PluginBuffer) -> i32;
+
+  #[repr(C)]
+  pub struct PluginVTable {
+    abi_version: u32,
+    info: *const c_char,
+    encode: TransformFn,
+    decode: TransformFn,
+    free: unsafe extern "C" fn(PluginBuffer),
+  }
+
+  // Only ever read
+  unsafe impl Sync for PluginVTable {}
+
+  static VTABLE: PluginVTable = PluginVTable {
+    abi_version: 1,
+    info: INFO.as_ptr() as *const c_char,
+    encode: run,
+    decode: run,
+    free,
+  };
+
+  #[unsafe(no_mangle)]
+  pub extern "C" fn wackypixels_plugin() -> *const PluginVTable {
+    &VTABLE
+  }
+
+  unsafe extern "C" fn run(input: *const u8, len: usize, params: *const c_char, out: *mut PluginBuffer) -> i32 {
+    let input = unsafe { std::slice::from_raw_parts(input, len) };
+    let params = unsafe { CStr::from_ptr(params) }.to_bytes();
+    let (status, data) = match xor(input, params) {
+      Ok(data) => (0, data),
+      Err(e) => (1, e.into_bytes()),
+    };
+    let mut data = std::mem::ManuallyDrop::new(data);
+    unsafe { *out = PluginBuffer { ptr: data.as_mut_ptr(), len: data.len(), cap: data.capacity() } };
+    status
+  }
+
+  unsafe extern "C" fn free(buffer: PluginBuffer) {
+    if !buffer.ptr.is_null() {
+      drop(unsafe { Vec::from_raw_parts(buffer.ptr, buffer.len, buffer.cap) });
+    }
+  }
+}
+
+#[cfg(target_arch = "wasm32")]
+mod wasm {
+  use super::*;
+
+  /// `(ptr << 32) | len`, leaking `data` since the instance is thrown away after every call
+  fn pack(data: Vec<u8>) -> i64 {
+    let data = data.leak();
+    ((data.as_ptr() as i64) << 32) | data.len() as i64
+  }
+
+  #[unsafe(no_mangle)]
+  pub extern "C" fn wp_alloc(len: i32) -> i32 {
+    Vec::<u8>::with_capacity(len as usize).leak().as_mut_ptr() as i32
+  }
+
+  #[unsafe(no_mangle)]
+  pub extern "C" fn wp_info() -> i64 {
+    pack(INFO.trim_end_matches('\0').as_bytes().to_vec())
+  }
+
+  unsafe fn run(ptr: i32, len: i32, params_ptr: i32, params_len: i32) -> i64 {
+    let input = unsafe { std::slice::from_raw_parts(ptr as *const u8, len as usize) };
+    let params = unsafe { std::slice::from_raw_parts(params_ptr as *const u8, params_len as usize) };
+    match xor(input, params) {
+      Ok(data) => pack(data),
+      Err(e) => -pack(e.into_bytes()),
+    }
+  }
+
+  #[unsafe(no_mangle)]
+  pub extern "C" fn wp_encode(ptr: i32, len: i32, params_ptr: i32, params_len: i32) -> i64 {
+    unsafe { run(ptr, len, params_ptr, params_len) }
+  }
+
+  #[unsafe(no_mangle)]
+  pub extern "C" fn wp_decode(ptr: i32, len: i32, params_ptr: i32, params_len: i32) -> i64 {
+    unsafe { run(ptr, len, params_ptr, params_len) }
+  }
+}
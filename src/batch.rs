@@ -0,0 +1,153 @@
+//! Run a pipeline over every matching file in a directory, several files at a time
+use crate::error::{Detail, PipelineError, Result};
+use crate::pipeline::Pipeline;
+use crate::report::{self, Direction, Reporter, FileRecord};
+use glob::Pattern;
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/// Directory settings for a batch run
+pub struct Batch<'a> {
+  pub input_dir: &'a Path,
+  pub pattern: &'a str,
+  pub output_dir: &'a Path,
+  /// Worker threads, the number of CPU cores when unset
+  pub jobs: Option<usize>,
+}
+
+/// Every file under `dir`, as paths relative to it, in a stable order
+fn walk(dir: &Path, relative: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+  let mut entries = fs::read_dir(dir.join(relative))?
+    .collect::<std::io::Result<Vec<_>>>()?;
+  entries.sort_by_key(|entry| entry.file_name());
+  for entry in entries {
+    let path = relative.join(entry.file_name());
+    if entry.file_type()?.is_dir() {
+      walk(dir, &path, files)?;
+    } else {
+      files.push(path);
+    }
+  }
+  Ok(())
+}
+
+/// Patterns with a `/` match the relative path, anything else just the file name
+fn matches(pattern: &Pattern, path: &Path) -> bool {
+  if pattern.as_str().contains('/') {
+    pattern.matches_path(path)
+  } else {
+    path.file_name().is_some_and(|name| pattern.matches(&name.to_string_lossy()))
+  }
+}
+
+/// Output folder for one file: its relative path, extension included so `cat.png` and
+/// `cat.jpg` don't share one. `cat.png/encrypted.wav` from an earlier batch encode just
+/// becomes `cat.png`
+fn output_subdir(relative: &Path) -> PathBuf {
+  match relative.parent() {
+    Some(parent) if relative.file_stem().is_some_and(|n| n == "encrypted") && !parent.as_os_str().is_empty() => {
+      parent.to_path_buf()
+    }
+    _ => relative.to_path_buf(),
+  }
+}
+
+impl Batch<'_> {
+  fn files(&self) -> Result<Vec<PathBuf>> {
+    let pattern = Pattern::new(self.pattern)
+      .map_err(|e| PipelineError::Usage(format!("Invalid glob `{}`: {}", self.pattern, e).into()))?;
+    let mut files = Vec::new();
+    walk(self.input_dir, Path::new(""), &mut files)?;
+    files.retain(|path| matches(&pattern, path));
+    Ok(files)
+  }
+
+  /// Run `pipeline` over every matching file, continuing past failures,
+  /// and fail at the end if any file did
+  pub fn run(
+    &self,
+    pipeline: &Pipeline,
+    direction: Direction,
+    output_file: Option<&Path>,
+    reporter: Reporter,
+  ) -> Result<()> {
+    pipeline.validate()?;
+    if crate::pipeline::is_stdio(self.output_dir) {
+      return Err(PipelineError::Usage("Batch runs need an output directory, not stdout".into()));
+    }
+    crate::runs::create_output_dir(self.output_dir)?;
+    let files = self.files()?;
+    if files.is_empty() {
+      return Err(PipelineError::Usage(format!(
+        "No files in {} match `{}`",
+        self.input_dir.display(),
+        self.pattern
+      ).into()));
+    }
+    let jobs = self.jobs
+      .or_else(|| std::thread::available_parallelism().ok().map(|n| n.get()))
+      .unwrap_or(1);
+    let pool = rayon::ThreadPoolBuilder::new()
+      .num_threads(jobs)
+      .build()
+      .map_err(|e| PipelineError::InvalidData(Detail::wrap(e)))?;
+
+    reporter.line(format!(
+      "  {} files from {} on {} workers\n",
+      files.len(),
+      self.input_dir.display(),
+      jobs
+    ));
+
+    // Files that would still share a folder, like `cat.png/encrypted.wav` and
+    // `cat.png/encrypted.gz`, fail instead of overwriting each other
+    let mut claimed: HashMap<PathBuf, Vec<&Path>> = HashMap::new();
+    for relative in &files {
+      claimed.entry(output_subdir(relative)).or_default().push(relative);
+    }
+
+    let started = Instant::now();
+    let records: Vec<FileRecord> = pool.install(|| {
+      files.par_iter().map(|relative| {
+        let input = self.input_dir.join(relative);
+        let subdir = output_subdir(relative);
+        let output_dir = self.output_dir.join(&subdir);
+        let file_started = Instant::now();
+        let others: Vec<String> = claimed[&subdir].iter()
+          .filter(|&&other| other != relative)
+          .map(|other| other.display().to_string())
+          .collect();
+        let result = if !others.is_empty() {
+          Err(PipelineError::Usage(format!(
+            "{} would share the output folder {} with {}",
+            relative.display(),
+            output_dir.display(),
+            others.join(", ")
+          ).into()))
+        } else {
+          match direction {
+            Direction::Encode => pipeline.encode(&input, &output_dir),
+            Direction::Decode => pipeline.decode(&input, &output_dir, output_file),
+          }
+        };
+        let record = FileRecord::new(&input, result, file_started.elapsed());
+        reporter.file(&record);
+        record
+      }).collect()
+    });
+
+    let failed = records.iter().filter(|record| record.error.is_some()).count();
+    reporter.batch(&records, report::millis(started.elapsed()));
+    if failed > 0 {
+      return Err(PipelineError::InvalidData(format!(
+        "{} of {} files failed",
+        failed,
+        records.len()
+      ).into()));
+    }
+    Ok(())
+  }
+}
@@ -0,0 +1,233 @@
+//! Time every stage of a pipeline in both directions, keep track of how much memory each one
+//! needs, and compare against a saved baseline to catch regressions
+use crate::error::{Detail, PipelineError, Result};
+use crate::report::{self, Direction};
+use crate::transform::Transform;
+use serde::{Deserialize, Serialize};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+/// The system allocator, counting how many bytes are live and the most there have been.
+/// Peak memory is only measured when the binary registers it as its `#[global_allocator]`
+pub struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+  unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+    let ptr = unsafe { System.alloc(layout) };
+    if !ptr.is_null() {
+      let allocated = ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+      PEAK.fetch_max(allocated, Ordering::Relaxed);
+    }
+    ptr
+  }
+
+  unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    unsafe { System.dealloc(ptr, layout) };
+    ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+  }
+
+  unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+    let new_ptr = unsafe { System.realloc(ptr, layout, new_size) };
+    if !new_ptr.is_null() {
+      if new_size > layout.size() {
+        let allocated = ALLOCATED.fetch_add(new_size - layout.size(), Ordering::Relaxed) + new_size - layout.size();
+        PEAK.fetch_max(allocated, Ordering::Relaxed);
+      } else {
+        ALLOCATED.fetch_sub(layout.size() - new_size, Ordering::Relaxed);
+      }
+    }
+    new_ptr
+  }
+}
+
+/// Start a new peak measurement from what is live right now, returning that
+fn reset_peak() -> usize {
+  let allocated = ALLOCATED.load(Ordering::Relaxed);
+  PEAK.store(allocated, Ordering::Relaxed);
+  allocated
+}
+
+/// Most bytes live since `reset_peak` returned `baseline`, on top of it
+fn peak_since(baseline: usize) -> u64 {
+  PEAK.load(Ordering::Relaxed).saturating_sub(baseline) as u64
+}
+
+/// One stage in one direction, over every iteration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StageBench {
+  pub direction: Direction,
+  pub transform: String,
+  pub input_bytes: u64,
+  pub output_bytes: u64,
+  pub min_ms: f64,
+  pub median_ms: f64,
+  pub mean_ms: f64,
+  /// Input MB (10^6 bytes) per second at the median time
+  pub throughput_mbps: f64,
+  /// Most memory the stage allocated on top of what was live before it, over all iterations
+  pub peak_bytes: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Bench {
+  pub input: String,
+  pub pipeline: Vec<String>,
+  pub iterations: usize,
+  pub stages: Vec<StageBench>,
+}
+
+/// A stage that got slower or hungrier than its baseline allows
+#[derive(Debug, Serialize)]
+pub struct Regression {
+  pub direction: Direction,
+  pub transform: String,
+  pub metric: &'static str,
+  pub baseline: f64,
+  pub current: f64,
+}
+
+#[derive(Default)]
+struct Samples {
+  input_bytes: u64,
+  output_bytes: u64,
+  millis: Vec<f64>,
+  peak_bytes: u64,
+}
+
+impl Samples {
+  fn finish(mut self, direction: Direction, transform: String) -> StageBench {
+    self.millis.sort_by(f64::total_cmp);
+    let median_ms = self.millis[self.millis.len() / 2];
+    StageBench {
+      direction,
+      transform,
+      input_bytes: self.input_bytes,
+      output_bytes: self.output_bytes,
+      min_ms: self.millis[0],
+      median_ms,
+      mean_ms: self.millis.iter().sum::<f64>() / self.millis.len() as f64,
+      throughput_mbps: if median_ms > 0.0 { self.input_bytes as f64 / 1000.0 / median_ms } else { 0.0 },
+      peak_bytes: self.peak_bytes,
+    }
+  }
+}
+
+/// Run `input` through `transforms` and back `iterations` times, `pipeline` names them
+pub fn bench(
+  transforms: &[Box<dyn Transform>],
+  pipeline: Vec<String>,
+  input: &Path,
+  data: Vec<u8>,
+  iterations: usize,
+) -> Result<Bench> {
+  let iterations = iterations.max(1);
+  let mut encodes: Vec<Samples> = transforms.iter().map(|_| Samples::default()).collect();
+  let mut decodes: Vec<Samples> = transforms.iter().map(|_| Samples::default()).collect();
+
+  for _ in 0..iterations {
+    let mut current = data.clone();
+    for (transform, samples) in transforms.iter().zip(&mut encodes) {
+      samples.input_bytes = current.len() as u64;
+      let baseline = reset_peak();
+      let started = Instant::now();
+      current = transform.encode(current)?;
+      samples.millis.push(report::millis(started.elapsed()));
+      samples.peak_bytes = samples.peak_bytes.max(peak_since(baseline));
+      samples.output_bytes = current.len() as u64;
+    }
+    for (transform, samples) in transforms.iter().zip(&mut decodes).rev() {
+      samples.input_bytes = current.len() as u64;
+      let baseline = reset_peak();
+      let started = Instant::now();
+      current = transform.decode(current)?;
+      samples.millis.push(report::millis(started.elapsed()));
+      samples.peak_bytes = samples.peak_bytes.max(peak_since(baseline));
+      samples.output_bytes = current.len() as u64;
+    }
+  }
+
+  let names = || transforms.iter().map(|t| t.name().to_string());
+  let mut stages: Vec<_> = encodes.into_iter().zip(names())
+    .map(|(samples, name)| samples.finish(Direction::Encode, name))
+    .collect();
+  stages.extend(decodes.into_iter().zip(names()).rev()
+    .map(|(samples, name)| samples.finish(Direction::Decode, name)));
+
+  Ok(Bench {
+    input: input.display().to_string(),
+    pipeline,
+    iterations,
+    stages,
+  })
+}
+
+impl Bench {
+  pub fn load(path: &Path) -> Result<Self> {
+    let json = std::fs::read_to_string(path)?;
+    serde_json::from_str(&json)
+      .map_err(|e| PipelineError::InvalidData(format!("Bad baseline {}: {}", path.display(), e).into()))
+  }
+
+  pub fn save(&self, path: &Path) -> Result<()> {
+    let json = serde_json::to_string_pretty(self).map_err(|e| PipelineError::InvalidData(Detail::wrap(e)))?;
+    std::fs::write(path, json)?;
+    Ok(())
+  }
+
+  /// Stages whose median time or peak memory grew by more than `threshold` percent over `baseline`.
+  /// Stages the baseline doesn't have are left alone
+  pub fn regressions(&self, baseline: &Bench, threshold: f64) -> Vec<Regression> {
+    let limit = 1.0 + threshold / 100.0;
+    let mut regressions = Vec::new();
+    for stage in &self.stages {
+      let Some(old) = baseline.stages.iter()
+        .find(|old| old.direction == stage.direction && old.transform == stage.transform) else {
+        continue;
+      };
+      let metrics = [
+        ("median_ms", old.median_ms, stage.median_ms),
+        ("peak_bytes", old.peak_bytes as f64, stage.peak_bytes as f64),
+      ];
+      for (metric, baseline, current) in metrics {
+        if baseline > 0.0 && current > baseline * limit {
+          regressions.push(Regression {
+            direction: stage.direction,
+            transform: stage.transform.clone(),
+            metric,
+            baseline,
+            current,
+          });
+        }
+      }
+    }
+    regressions
+  }
+
+  pub fn print(&self) {
+    let width = self.stages.iter()
+      .map(|stage| stage.transform.len())
+      .max()
+      .unwrap_or(0)
+      .max(5);
+    println!(
+      "  {:6}  {:width$}  {:>10}  {:>10}  {:>10}  {:>10}  {:>10}  {:>12}",
+      "", "Stage", "Input", "Min", "Median", "Mean", "MB/s", "Peak memory",
+    );
+    for stage in &self.stages {
+      let direction = match stage.direction {
+        Direction::Encode => "encode",
+        Direction::Decode => "decode",
+      };
+      println!(
+        "  {:6}  {:width$}  {:>10}  {:>7.1} ms  {:>7.1} ms  {:>7.1} ms  {:>10.1}  {:>12}",
+        direction, stage.transform, stage.input_bytes, stage.min_ms, stage.median_ms, stage.mean_ms,
+        stage.throughput_mbps, stage.peak_bytes,
+      );
+    }
+  }
+}
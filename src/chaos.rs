@@ -0,0 +1,143 @@
+//! `--chaos`: a random but valid pipeline with random settings, made up from a seed and
+//! recorded in a manifest that rebuilds it for decoding
+use crate::cli::TransformOptions;
+use crate::error::{Detail, PipelineError, Result};
+use crate::pipeline::Pipeline;
+use crate::pipeline_builder::{BUILTINS, Builtin};
+use crate::plan::SAMPLE_BYTES;
+use crate::registry::Registry;
+use crate::transform::{self, Kind};
+use crate::transforms::pdf::{PdfPreview, PdfStrategy};
+use clap::{Args, FromArgMatches, ValueEnum};
+use rand::seq::{IndexedRandom, SliceRandom};
+use rand::{Rng, RngExt};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Name of the manifest in the output directory
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// Largest estimated output of any stage, unless `--chaos-max-bytes` says otherwise
+pub const DEFAULT_MAX_BYTES: u64 = 64 * 1024 * 1024;
+
+/// One stage of the pipeline, with its settings as the command line flags that give them
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Stage {
+  pub id: String,
+  #[serde(default)]
+  pub args: Vec<String>,
+}
+
+impl Stage {
+  pub fn options(&self) -> Result<TransformOptions> {
+    let command = TransformOptions::augment_args(clap::Command::new("stage").no_binary_name(true));
+    command.try_get_matches_from(&self.args)
+      .and_then(|matches| TransformOptions::from_arg_matches(&matches))
+      .map_err(|e| {
+        let message = e.to_string();
+        let message = message.lines().next().unwrap_or_default().trim_start_matches("error: ");
+        PipelineError::Usage(format!("Bad settings for `{}`: {}", self.id, message).into())
+      })
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+  /// Seed the stages and their settings were picked with
+  pub seed: u64,
+  pub stages: Vec<Stage>,
+}
+
+impl Manifest {
+  /// Pick `length` stages for `input` one at a time, each taking what the previous one makes
+  /// and, going by `Transform::estimate`, growing the data to at most `max_bytes`
+  pub fn generate(length: usize, seed: u64, max_bytes: u64, input: &[u8]) -> Result<Self> {
+    let mut rng = transform::rng(Some(seed));
+    let mut kind = if ::image::guess_format(input).is_ok() { Kind::Image } else { Kind::Bytes };
+    let mut size = input.len() as u64;
+    // A prefix of the data at every stage, like `plan` keeps
+    let mut sample = input.to_vec();
+    let mut stages = Vec::new();
+
+    while stages.len() < length {
+      let mut candidates: Vec<&Builtin> = BUILTINS.iter().collect();
+      candidates.shuffle(&mut rng);
+      let next = candidates.into_iter().find_map(|builtin| {
+        let stage = Stage { id: builtin.id.to_string(), args: random_args(builtin, seed, &mut rng) };
+        let transform = (builtin.build)(&stage.options().ok()?);
+        if !transform.input_kind().accepts(kind) {
+          return None;
+        }
+        let bytes = transform.estimate(size, &sample).ok()?.bytes;
+        let fits = bytes <= max_bytes && transform.capacity().is_none_or(|capacity| size <= capacity);
+        fits.then_some((stage, transform, bytes))
+      });
+      let Some((stage, transform, bytes)) = next else {
+        return Err(PipelineError::Usage(format!(
+          "No transform keeps step {} under {} bytes, allow more with --chaos-max-bytes or ask for fewer stages",
+          stages.len() + 1, max_bytes,
+        ).into()));
+      };
+
+      kind = transform.output_kind();
+      size = bytes;
+      sample = transform.encode(sample).map_err(|e| {
+        PipelineError::InvalidData(format!("Can't sample {}: {}", transform.name(), e).into())
+      })?;
+      sample.truncate(SAMPLE_BYTES);
+      stages.push(stage);
+    }
+    Ok(Self { seed, stages })
+  }
+
+  /// `--pipeline` names of the stages
+  pub fn ids(&self) -> Vec<&str> {
+    self.stages.iter().map(|stage| stage.id.as_str()).collect()
+  }
+
+  pub fn pipeline(&self, registry: &Registry) -> Result<Pipeline> {
+    self.stages.iter()
+      .map(|stage| registry.build(&stage.id, &stage.options()?))
+      .try_fold(Pipeline::new(), |pipeline, transform| Ok(pipeline.add_boxed(transform?)))
+  }
+
+  pub fn load(path: &Path) -> Result<Self> {
+    let json = std::fs::read_to_string(path)?;
+    serde_json::from_str(&json)
+      .map_err(|e| PipelineError::InvalidData(format!("Bad manifest {}: {}", path.display(), e).into()))
+  }
+
+  pub fn save(&self, path: &Path) -> Result<()> {
+    let json = serde_json::to_string_pretty(self).map_err(|e| PipelineError::InvalidData(Detail::wrap(e)))?;
+    std::fs::write(path, json)?;
+    Ok(())
+  }
+}
+
+/// Command line name of a random variant
+fn pick<T: ValueEnum>(rng: &mut impl Rng) -> String {
+  let variant = T::value_variants().choose(rng).expect("value enums have variants");
+  variant.to_possible_value().expect("variants aren't skipped").get_name().to_string()
+}
+
+/// Random settings for the flags a built-in transform reads. Flags that need something
+/// from the user, like a cover PDF or a password, are left alone
+fn random_args(builtin: &Builtin, seed: u64, rng: &mut impl Rng) -> Vec<String> {
+  let mut args = Vec::new();
+  for flag in builtin.flags {
+    let value = match *flag {
+      "chunk-size" if rng.random_bool(0.5) => Some(format!("{}", 1u64 << rng.random_range(10..=20))),
+      "unicode-wrap" if rng.random_bool(0.3) => Some(rng.random_range(8..=200).to_string()),
+      "unicode-split" if rng.random_bool(0.3) => Some(rng.random_range(280..=4000).to_string()),
+      "pdf-strategy" => Some(pick::<PdfStrategy>(rng)),
+      "pdf-preview" => Some(pick::<PdfPreview>(rng)),
+      "unicode-headers" | "pdf-compress" | "pdf-object-streams" if rng.random_bool(0.5) => None,
+      // The same seed makes the same files
+      "seed" => Some(seed.to_string()),
+      _ => continue,
+    };
+    args.push(format!("--{}", flag));
+    args.extend(value);
+  }
+  args
+}
@@ -1,5 +1,13 @@
-use clap::{Parser, Subcommand, ValueEnum};
+use clap::builder::{NonEmptyStringValueParser, PossibleValue, TypedValueParser};
+use clap::{Arg, Args, Command, Parser, Subcommand};
+use clap_complete::Shell;
+use std::ffi::OsStr;
 use std::path::PathBuf;
+use crate::chaos::DEFAULT_MAX_BYTES;
+use crate::pipeline_builder::BUILTINS;
+use crate::report::{ReportFormat, Reporter, Verbosity};
+use crate::runs::RUNS_DIR;
+use crate::transforms::pdf::{PdfEncryption, PdfPreview, PdfStrategy, parse_pdf_date};
 
 #[derive(Parser)]
 #[command(name = "wackypixels")]
@@ -8,16 +16,45 @@ use std::path::PathBuf;
 pub struct Cli {
   #[command(subcommand)]
   pub command: Commands,
+  
+  /// Only print errors
+  #[arg(short, long, global = true, conflicts_with = "verbose")]
+  pub quiet: bool,
+  
+  /// Also print timings and transform details
+  #[arg(short, long, global = true)]
+  pub verbose: bool,
+  
+  /// How to report progress
+  #[arg(long, value_enum, global = true, default_value_t = ReportFormat::Text)]
+  pub format: ReportFormat,
+  
+  /// Load a transform plugin, a shared library or a `.wasm` module (repeatable)
+  #[arg(long = "plugin", value_name = "PATH", global = true)]
+  pub plugins: Vec<PathBuf>,
+}
+
+impl Cli {
+  pub fn reporter(&self) -> Reporter {
+    let verbosity = if self.quiet {
+      Verbosity::Quiet
+    } else if self.verbose {
+      Verbosity::Verbose
+    } else {
+      Verbosity::Normal
+    };
+    Reporter::new(self.format, verbosity)
+  }
 }
 
 #[derive(Subcommand)]
 pub enum Commands {
   /// Encode an image through the pipeline
   Encode {
-    /// Input file to encode
+    /// Input file to encode (`-` for stdin)
     #[arg(short = 'i', long, default_value = "inputs/image.png")]
     input: PathBuf,
-    /// Output directory for encoding
+    /// Output directory for encoding (`-` to write the result to stdout)
     #[arg(short = 'o', long, default_value = "outputs")]
     output: PathBuf,
     /// Whether or not to seave intermediate files
@@ -26,15 +63,24 @@ pub enum Commands {
     
     /// Custom pipeline (comma-separated)
     /// Example: image,pdf,lzma,unicode,wav
-    #[arg(short = 'p', long, value_delimiter = ',')]
-    pipeline: Option<Vec<TransformType>>,
+    #[arg(short = 'p', long, value_delimiter = ',', value_parser = TransformParser)]
+    pipeline: Option<Vec<String>>,
+    
+    #[command(flatten)]
+    batch: BatchOptions,
+    
+    #[command(flatten)]
+    chaos: ChaosOptions,
+    
+    #[command(flatten)]
+    options: TransformOptions,
   },
   /// Decode an image through the pipeline
   Decode {
-    /// Path to encrypted file to decode
+    /// Path to encrypted file to decode (`-` for stdin)
     #[arg(short = 'i', long, default_value = "inputs/image.png")]
     input: PathBuf,
-    /// Output directory for decoding
+    /// Output directory for decoding (`-` to write the result to stdout)
     #[arg(short = 'o', long, default_value = "decrypted")]
     output: PathBuf,
     /// Whether or not to save intermediate files
@@ -44,42 +90,156 @@ pub enum Commands {
     /// Custom pipeline (comma-separated) in the forward direction
     /// Decoding happens in the reverse of whatever pipeline is given
     /// Example: image,pdf,lzma,unicode,wav
-    #[arg(short = 'p', long, value_delimiter = ',')]
-    pipeline: Option<Vec<TransformType>>,
+    #[arg(short = 'p', long, value_delimiter = ',', value_parser = TransformParser)]
+    pipeline: Option<Vec<String>>,
+    
+    /// Rebuild the pipeline from the manifest `--chaos` wrote
+    #[arg(long, value_name = "FILE", conflicts_with = "pipeline")]
+    manifest: Option<PathBuf>,
     
     #[arg(short = 'f', long, default_value = "decrypted.png")]
     output_file: PathBuf,
+    
+    /// Decode as the input arrives, keeping a preview.png of the image so far
+    /// when reading from stdin, and write out whatever can be recovered
+    /// from a truncated input
+    #[arg(long)]
+    progressive: bool,
+    
+    #[command(flatten)]
+    batch: BatchOptions,
+    
+    #[command(flatten)]
+    options: TransformOptions,
   },
   
   /// Clean output directories
   Clean {
     /// Directories to clean 
-    /// (Defaults to outputs/ and decrypted/)
+    /// (Defaults to outputs/, decrypted/ and runs/)
     /// (Comma separated)
+    /// Only directories with a .wackypixels marker, or the marked folders inside them, are deleted
     #[arg(short, long, value_delimiter = ',')]
     dirs: Option<Vec<PathBuf>>,
     
+    /// Spare the N most recently created of the directories that would be deleted
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    keep_last: usize,
+    
+    /// Only show what would be deleted
+    #[arg(long)]
+    dry_run: bool,
+    
     /// Skip confirmation prompt
     #[arg(short = 'y', long)]
     yes: bool,
   },
   
-  /// List available transforms
+  /// List available transforms with their parameters and examples
   List,
   
-  /// Run the default full pipeline (encode + decode)
+  /// Print a shell completion script, e.g. `wackypixels completions bash > /etc/bash_completion.d/wackypixels`
+  Completions {
+    #[arg(value_enum)]
+    shell: Shell,
+  },
+  
+  /// Predict each stage's output size without encoding anything to disk
+  Plan {
+    /// File to plan for (`-` for stdin)
+    #[arg(short = 'i', long, default_value = "inputs/image.png")]
+    input: PathBuf,
+    
+    /// Custom pipeline (comma-separated)
+    /// Example: image,pdf,lzma,unicode,wav
+    #[arg(short = 'p', long, value_delimiter = ',', value_parser = TransformParser)]
+    pipeline: Option<Vec<String>>,
+    
+    #[command(flatten)]
+    options: TransformOptions,
+  },
+  
+  /// Time every stage in both directions and measure their peak memory
+  Bench {
+    /// File to benchmark with (`-` for stdin)
+    #[arg(short = 'i', long, default_value = "inputs/image.png")]
+    input: PathBuf,
+    
+    /// Custom pipeline (comma-separated)
+    /// Example: image,pdf,lzma,unicode,wav
+    #[arg(short = 'p', long, value_delimiter = ',', value_parser = TransformParser)]
+    pipeline: Option<Vec<String>>,
+    
+    /// How many times to run the whole round trip
+    #[arg(short = 'n', long, default_value_t = 5)]
+    iterations: usize,
+    
+    /// Save the results as a JSON baseline
+    #[arg(long, value_name = "FILE")]
+    save_baseline: Option<PathBuf>,
+    
+    /// Compare against a saved baseline and fail on regressions
+    #[arg(long, value_name = "FILE")]
+    baseline: Option<PathBuf>,
+    
+    /// How many percent slower or bigger than the baseline counts as a regression
+    #[arg(long, default_value_t = 10.0)]
+    threshold: f64,
+    
+    #[command(flatten)]
+    options: TransformOptions,
+  },
+  
+  /// Check a pipeline for transforms that can't or shouldn't follow each other
+  Lint {
+    /// Custom pipeline (comma-separated)
+    /// Example: image,pdf,lzma,unicode,wav
+    #[arg(short = 'p', long, value_delimiter = ',', value_parser = TransformParser)]
+    pipeline: Option<Vec<String>>,
+    
+    #[command(flatten)]
+    options: TransformOptions,
+  },
+  
+  /// Identify an unknown file by peeling off as many layers as possible
+  Inspect {
+    /// File to inspect (`-` for stdin)
+    file: PathBuf,
+    
+    #[command(flatten)]
+    options: TransformOptions,
+  },
+  
+  /// Compare a decoded file with the original: pixel by pixel for images, by SHA-256 otherwise
+  Verify {
+    /// The file that was encoded
+    original: PathBuf,
+    /// What decoding gave back
+    decoded: PathBuf,
+  },
+  
+  /// Run the default full pipeline (encode + decode) and verify the result
+  #[command(alias = "roundtrip")]
   Run {
     /// Input file for everything
     #[arg(short = 'i', long, default_value = "inputs/image.png")]
     input: PathBuf,
     
-    /// Encoding file directories
-    #[arg(short = 'e', long, default_value = "outputs/")]
-    encode_output: PathBuf,
+    /// Encoding file directories (defaults to `encoded/` in the run folder)
+    #[arg(short = 'e', long)]
+    encode_output: Option<PathBuf>,
+    
+    /// Output directory after decoding (defaults to `decoded/` in the run folder)
+    #[arg(short = 'd', long)]
+    decode_output: Option<PathBuf>,
     
-    /// Output directory after decoding
-    #[arg(short = 'd', long, default_value = "decrypted/")]
-    decode_output: PathBuf,
+    /// Where every run gets a folder of its own, named after the time and the input's hash
+    #[arg(long, value_name = "DIR", default_value = RUNS_DIR)]
+    runs_dir: PathBuf,
+    
+    /// Afterwards, delete all but the N newest run folders
+    #[arg(long, value_name = "N")]
+    keep_last: Option<usize>,
     
     #[arg(short = 'f', long, default_value = "decrypted.png")]
     output_file: PathBuf,
@@ -87,38 +247,154 @@ pub enum Commands {
     /// Custom pipeline (comma-separated) in the forward direction
     /// Decoding happens in the reverse of whatever pipeline is given
     /// Example: image,pdf,lzma,unicode,wav
-    #[arg(short = 'p', long, value_delimiter = ',')]
-    pipeline: Option<Vec<TransformType>>,
+    #[arg(short = 'p', long, value_delimiter = ',', value_parser = TransformParser)]
+    pipeline: Option<Vec<String>>,
     
     /// Whether or not to save intermediate files
     #[arg(short, long, default_value_t = true)]
     save_intermediates: bool,
     
-    /// Skip confirmation before cleaning
+    /// Skip confirmation before cleaning the -e/-d directories of an earlier run
     #[arg(short = 'y', long)]
     yes: bool,
+    
+    #[command(flatten)]
+    chaos: ChaosOptions,
+    
+    #[command(flatten)]
+    options: TransformOptions,
   },
 }
 
-#[derive(Debug, Clone, ValueEnum)]
-pub enum TransformType {
-  Image,
-  Pdf,
-  Lzma,
-  Unicode,
-  Wav,
-  Gzip,
+/// Processing a whole directory instead of a single `--input`
+#[derive(Args, Debug, Clone, Default)]
+pub struct BatchOptions {
+  /// Process every matching file in this directory (and its subdirectories),
+  /// each into its own folder under the output directory
+  #[arg(long, value_name = "DIR", conflicts_with = "input")]
+  pub input_dir: Option<PathBuf>,
+  
+  /// Which files to process, matched against the file name, or the path
+  /// relative to --input-dir if it contains a `/`
+  /// (defaults to `*` for encode and `encrypted.*` for decode)
+  #[arg(long, value_name = "PATTERN", requires = "input_dir")]
+  pub glob: Option<String>,
+  
+  /// How many files to process at once (defaults to the number of CPU cores)
+  #[arg(short = 'j', long, requires = "input_dir")]
+  pub jobs: Option<usize>,
+}
+
+/// Encoding through a made up pipeline instead of `--pipeline`
+#[derive(Args, Debug, Clone, Default)]
+pub struct ChaosOptions {
+  /// Encode through a random pipeline of this many stages with random settings, picked
+  /// from --seed (or a random one) and saved to manifest.json in the output directory
+  #[arg(long, value_name = "STAGES", conflicts_with = "pipeline")]
+  pub chaos: Option<usize>,
+  
+  /// Leave out stages whose output is estimated to be bigger than this
+  #[arg(long, value_name = "BYTES", default_value_t = DEFAULT_MAX_BYTES, requires = "chaos")]
+  pub chaos_max_bytes: u64,
+}
+
+/// Per-transform settings, shared by every command that builds a pipeline
+#[derive(Args, Debug, Clone, Default)]
+pub struct TransformOptions {
+  /// Split the lzma, unicode and wav stages into independent blocks of this
+  /// many bytes, processed in parallel
+  #[arg(long, value_name = "BYTES")]
+  pub chunk_size: Option<usize>,
+  
+  /// Wrap unicode output into lines of this many graphemes
+  #[arg(long, value_name = "GRAPHEMES")]
+  pub unicode_wrap: Option<usize>,
+  
+  /// Split unicode output into numbered parts of at most this many code points
+  /// (e.g. 280 for a tweet, 2000 for a Discord message)
+  #[arg(long, value_name = "CODE_POINTS")]
+  pub unicode_split: Option<usize>,
+  
+  /// Add a `[wackypixels i/N]` header to unicode output even when it isn't split
+  #[arg(long)]
+  pub unicode_headers: bool,
+  
+  /// Where the PDF stage hides its payload (decoding detects it automatically)
+  #[arg(long, value_enum, default_value_t = PdfStrategy::Info)]
+  pub pdf_strategy: PdfStrategy,
+  
+  /// Hide the PDF payload in this existing PDF through an incremental update,
+  /// leaving its original bytes untouched
+  #[arg(long = "cover", value_name = "PDF")]
+  pub pdf_cover: Option<PathBuf>,
+  
+  /// Draw the image visibly on the generated PDF page
+  #[arg(long, value_enum, default_value_t = PdfPreview::None)]
+  pub pdf_preview: PdfPreview,
+  
+  /// Visible text on the generated PDF page (defaults to "Hello, World!"), Western European characters only
+  #[arg(long, value_name = "TEXT")]
+  pub pdf_caption: Option<String>,
+  
+  /// PDF document title
+  #[arg(long, value_name = "TEXT")]
+  pub pdf_title: Option<String>,
+  
+  /// PDF document author
+  #[arg(long, value_name = "TEXT")]
+  pub pdf_author: Option<String>,
+  
+  /// PDF creation date (YYYY-MM-DD or YYYY-MM-DDTHH:MM:SS, UTC)
+  #[arg(long, value_name = "DATE", value_parser = parse_pdf_date)]
+  pub pdf_created: Option<String>,
+  
+  /// PDF modification date (YYYY-MM-DD or YYYY-MM-DDTHH:MM:SS, UTC)
+  #[arg(long, value_name = "DATE", value_parser = parse_pdf_date)]
+  pub pdf_modified: Option<String>,
+  
+  /// Flate compress the hidden PDF payload and page content streams
+  #[arg(long)]
+  pub pdf_compress: bool,
+  
+  /// Encrypt the PDF with the standard security handler (needs --pdf-password)
+  #[arg(long, value_enum)]
+  pub pdf_encrypt: Option<PdfEncryption>,
+  
+  /// PDF user password, for encrypting and for decoding encrypted PDFs
+  #[arg(long, value_name = "PASSWORD")]
+  pub pdf_password: Option<String>,
+  
+  /// Pack PDF objects into object streams
+  #[arg(long)]
+  pub pdf_object_streams: bool,
+  
+  /// Seed everything random in the output (currently the PDF file identifier), so the same
+  /// input, pipeline and seed always give byte-identical files
+  #[arg(long, value_name = "N")]
+  pub seed: Option<u64>,
+  
+  /// Set a plugin parameter (repeatable)
+  #[arg(long = "plugin-param", value_name = "ID.NAME=VALUE")]
+  pub plugin_params: Vec<String>,
 }
 
-impl TransformType {
-  pub fn description(&self) -> &str {
-    match self {
-      TransformType::Image => "Image serialization (PNG -> binary)",
-      TransformType::Pdf => "PDF, stored in the /Info metadata",
-      TransformType::Lzma => "LZMA/XZ compression",
-      TransformType::Unicode => "Unicode, multimode encoding (CJK, Emojis, Hidden characters, etc)",
-      TransformType::Wav => "WAV audio encoding (amplitude modulation)",
-      TransformType::Gzip => "Gzip compression",
-    }
+/// Takes any transform name, since plugins are only loaded after parsing, but offers the
+/// built-in ones to help text and shell completions
+#[derive(Clone)]
+pub struct TransformParser;
+
+impl TypedValueParser for TransformParser {
+  type Value = String;
+  
+  fn parse_ref(&self, cmd: &Command, arg: Option<&Arg>, value: &OsStr) -> Result<String, clap::Error> {
+    NonEmptyStringValueParser::new().parse_ref(cmd, arg, value)
   }
-}
\ No newline at end of file
+  
+  fn possible_values(&self) -> Option<Box<dyn Iterator<Item = PossibleValue> + '_>> {
+    Some(Box::new(BUILTINS.iter().map(|builtin| {
+      PossibleValue::new(builtin.id)
+        .help(builtin.description)
+        .aliases(builtin.aliases.iter().copied())
+    })))
+  }
+}
@@ -1,17 +1,111 @@
 //! Storing custom errors to better track the errors happening where in the pipeline
+use crate::report::Direction;
+use serde::Serialize;
+use std::error::Error;
 use std::fmt;
 
+/// Where in a stage's input something went wrong
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Offset {
+  Byte(u64),
+  /// Sample index into a WAV's interleaved samples
+  Sample(u64),
+}
+
+impl fmt::Display for Offset {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Offset::Byte(offset) => write!(f, "byte {}", offset),
+      Offset::Sample(offset) => write!(f, "sample {}", offset),
+    }
+  }
+}
+
+/// What went wrong inside one kind of error, and whatever library error caused it
+#[derive(Debug)]
+pub struct Detail {
+  pub message: String,
+  pub offset: Option<Offset>,
+  pub source: Option<Box<dyn Error + Send + Sync>>,
+}
+
+impl Detail {
+  /// Wrap a library error, keeping it around as the `source()`
+  pub fn wrap(error: impl Error + Send + Sync + 'static) -> Self {
+    Self { message: error.to_string(), offset: None, source: Some(Box::new(error)) }
+  }
+
+  pub fn at(mut self, offset: Offset) -> Self {
+    self.offset = Some(offset);
+    self
+  }
+}
+
+impl From<String> for Detail {
+  fn from(message: String) -> Self {
+    Self { message, offset: None, source: None }
+  }
+}
+
+impl From<&str> for Detail {
+  fn from(message: &str) -> Self {
+    message.to_string().into()
+  }
+}
+
+impl fmt::Display for Detail {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.message)
+  }
+}
+
+/// An error from one stage of a pipeline run
+#[derive(Debug)]
+pub struct StageError {
+  pub direction: Direction,
+  /// 1-based, in the order the stages ran
+  pub step: usize,
+  pub total: usize,
+  pub transform: String,
+  pub error: PipelineError,
+}
+
+impl fmt::Display for StageError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let direction = match self.direction {
+      Direction::Encode => "encode",
+      Direction::Decode => "decode",
+    };
+    write!(f, "{} step {}/{} ({})", direction, self.step, self.total, self.transform)?;
+    if let Some(offset) = self.error.offset() {
+      write!(f, " at {}", offset)?;
+    }
+    write!(f, ": {}", self.error)
+  }
+}
+
 #[derive(Debug)]
 pub enum PipelineError {
   Io(std::io::Error),
-  Image(String),
-  Pdf(String),
-  Lzma(String),
-  Unicode(String),
-  Wav(String),
+  Image(Detail),
+  Pdf(Detail),
+  /// A PDF password is missing or wrong
+  Password(Detail),
+  Lzma(Detail),
+  Unicode(Detail),
+  Wav(Detail),
   Utf8(std::string::FromUtf8Error),
-  Flate(String),
-  InvalidData(String),
+  Flate(Detail),
+  Plugin(Detail),
+  /// The input ended before a transform expected it to
+  Truncated(Detail),
+  /// Options or a pipeline that can't work
+  Usage(Detail),
+  /// A round trip didn't give back the input
+  Mismatch(Detail),
+  InvalidData(Detail),
+  Stage(Box<StageError>),
 }
 
 impl fmt::Display for PipelineError {
@@ -20,18 +114,110 @@ impl fmt::Display for PipelineError {
             PipelineError::Io(e) => write!(f, "IO error: {}", e),
             PipelineError::Image(e) => write!(f, "Image encoding error: {}", e),
             PipelineError::Pdf(e) => write!(f, "PDF transformation error: {}", e),
+            PipelineError::Password(e) => write!(f, "PDF password error: {}", e),
             PipelineError::Lzma(e) => write!(f, "LZMA compression error: {}", e),
             PipelineError::Unicode(e) => write!(f, "Unicode encoding error: {}", e),
             PipelineError::Wav(e) => write!(f, "WAV audio error: {}", e),
             PipelineError::Utf8(e) => write!(f, "UTF-8 conversion error: {}", e),
             PipelineError::Flate(e) => write!(f, "Flate compression error: {}", e),
+            PipelineError::Plugin(e) => write!(f, "Plugin error: {}", e),
+            PipelineError::Truncated(e) => write!(f, "Truncated input: {}", e),
+            PipelineError::Usage(e) => write!(f, "{}", e),
+            PipelineError::Mismatch(e) => write!(f, "Verification failed: {}", e),
             PipelineError::InvalidData(msg) => write!(f, "Invalid data: {}", msg),
+            PipelineError::Stage(e) => write!(f, "{}", e),
         }
     }
 }
 
+impl PipelineError {
+  fn detail(&self) -> Option<&Detail> {
+    match self {
+      PipelineError::Image(d) | PipelineError::Pdf(d) | PipelineError::Password(d)
+      | PipelineError::Lzma(d) | PipelineError::Unicode(d) | PipelineError::Wav(d)
+      | PipelineError::Flate(d) | PipelineError::Plugin(d) | PipelineError::Truncated(d)
+      | PipelineError::Usage(d) | PipelineError::Mismatch(d) | PipelineError::InvalidData(d) => Some(d),
+      PipelineError::Io(_) | PipelineError::Utf8(_) | PipelineError::Stage(_) => None,
+    }
+  }
+
+  /// Where in its input the failing stage gave up, if it knows
+  pub fn offset(&self) -> Option<Offset> {
+    match self {
+      PipelineError::Stage(e) => e.error.offset(),
+      _ => self.detail().and_then(|d| d.offset),
+    }
+  }
 
-impl std::error::Error for PipelineError {}
+  /// The stage this happened in, for errors from a pipeline run
+  pub fn stage(&self) -> Option<&StageError> {
+    match self {
+      PipelineError::Stage(e) => Some(e),
+      _ => None,
+    }
+  }
+
+  /// Add the stage an error came from
+  pub fn in_stage(self, direction: Direction, step: usize, total: usize, transform: &str) -> Self {
+    if let PipelineError::Stage(_) = self {
+      return self;
+    }
+    PipelineError::Stage(Box::new(StageError {
+      direction,
+      step,
+      total,
+      transform: transform.to_string(),
+      error: self,
+    }))
+  }
+
+  /// Process exit code, stable so scripts can tell failures apart:
+  ///
+  /// | Code | Meaning |
+  /// |------|---------|
+  /// | 1    | anything else |
+  /// | 2    | bad options or pipeline |
+  /// | 3    | I/O |
+  /// | 4    | missing or wrong PDF password |
+  /// | 5    | truncated input |
+  /// | 6    | decoded output doesn't match the input |
+  /// | 10   | image |
+  /// | 11   | PDF |
+  /// | 12   | LZMA |
+  /// | 13   | unicode and UTF-8 |
+  /// | 14   | WAV |
+  /// | 15   | gzip |
+  /// | 16   | plugin |
+  pub fn exit_code(&self) -> i32 {
+    match self {
+      PipelineError::InvalidData(_) => 1,
+      PipelineError::Usage(_) => 2,
+      PipelineError::Io(_) => 3,
+      PipelineError::Password(_) => 4,
+      PipelineError::Truncated(_) => 5,
+      PipelineError::Mismatch(_) => 6,
+      PipelineError::Image(_) => 10,
+      PipelineError::Pdf(_) => 11,
+      PipelineError::Lzma(_) => 12,
+      PipelineError::Unicode(_) | PipelineError::Utf8(_) => 13,
+      PipelineError::Wav(_) => 14,
+      PipelineError::Flate(_) => 15,
+      PipelineError::Plugin(_) => 16,
+      PipelineError::Stage(e) => e.error.exit_code(),
+    }
+  }
+}
+
+impl Error for PipelineError {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    match self {
+      PipelineError::Io(e) => Some(e),
+      PipelineError::Utf8(e) => Some(e),
+      PipelineError::Stage(e) => Some(&e.error),
+      _ => self.detail()?.source.as_deref().map(|e| e as &(dyn Error + 'static)),
+    }
+  }
+}
 
 // Convenient conversions
 impl From<std::io::Error> for PipelineError {
@@ -46,11 +232,4 @@ impl From<std::string::FromUtf8Error> for PipelineError {
     }
 }
 
-// Helper for String errors
-impl From<String> for PipelineError {
-    fn from(s: String) -> Self {
-        PipelineError::InvalidData(s)
-    }
-}
-
-pub type Result<T> = std::result::Result<T, PipelineError>;
\ No newline at end of file
+pub type Result<T> = std::result::Result<T, PipelineError>;
@@ -0,0 +1,111 @@
+//! Identify an artifact of unknown origin by sniffing it and peeling layers until
+//! nothing recognizable is left
+use crate::cli::TransformOptions;
+use crate::registry::Registry;
+use serde::Serialize;
+
+/// Stop peeling after this many layers, nothing real is this deep
+const MAX_LAYERS: usize = 32;
+
+/// Most distinctive formats first, raw image serialization has the weakest signature.
+/// Anything else in the registry gets tried after these
+const SNIFF_ORDER: [&str; 6] = ["wav", "pdf", "lzma", "gzip", "unicode", "image"];
+
+/// One peeled layer, outermost first
+#[derive(Debug, Serialize)]
+pub struct Layer {
+  pub transform: String,
+  pub description: String,
+  pub input_bytes: usize,
+  pub output_bytes: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Inspection {
+  pub layers: Vec<Layer>,
+  /// What was left once nothing else could be peeled off
+  pub payload: String,
+  pub payload_bytes: usize,
+  /// A layer that was recognized but couldn't be decoded
+  pub failure: Option<String>,
+  /// The likely encode pipeline, in `--pipeline` order
+  pub pipeline: Vec<String>,
+}
+
+fn describe_payload(data: &[u8]) -> String {
+  if let Ok(format) = image::guess_format(data) {
+    return format!("{:?} image", format).to_lowercase();
+  }
+  if std::str::from_utf8(data).is_ok() {
+    return "text".to_string();
+  }
+  "unrecognized binary data".to_string()
+}
+
+pub fn inspect(registry: &Registry, mut data: Vec<u8>, options: &TransformOptions) -> Inspection {
+  let mut layers = Vec::new();
+  let mut failure = None;
+  let ids: Vec<&str> = SNIFF_ORDER.into_iter()
+    .chain(registry.entries().iter()
+      .map(|entry| entry.info.id.as_str())
+      .filter(|id| !SNIFF_ORDER.contains(id)))
+    .collect();
+
+  'peel: while layers.len() < MAX_LAYERS {
+    let mut last_error = None;
+    for id in &ids {
+      let Ok(transform) = registry.build(id, options) else {
+        continue;
+      };
+      let Some(description) = transform.sniff(&data) else {
+        continue;
+      };
+      let input_bytes = data.len();
+      match transform.decode(data.clone()) {
+        Ok(decoded) => {
+          layers.push(Layer {
+            transform: id.to_string(),
+            description,
+            input_bytes,
+            output_bytes: decoded.len(),
+          });
+          data = decoded;
+          continue 'peel;
+        }
+        Err(e) => {
+          last_error.get_or_insert(format!("looks like {} but doesn't decode: {}", description, e));
+        }
+      }
+    }
+    failure = last_error;
+    break;
+  }
+
+  let pipeline = layers.iter().rev().map(|layer| layer.transform.clone()).collect();
+  Inspection {
+    layers,
+    payload: describe_payload(&data),
+    payload_bytes: data.len(),
+    failure,
+    pipeline,
+  }
+}
+
+impl Inspection {
+  pub fn print(&self) {
+    for (i, layer) in self.layers.iter().enumerate() {
+      println!("  {}. {:8} {}", i + 1, layer.transform, layer.description);
+      println!("     {} -> {} bytes", layer.input_bytes, layer.output_bytes);
+    }
+    if let Some(failure) = &self.failure {
+      println!("  X {}", failure);
+    }
+    println!("  = {} ({} bytes)\n", self.payload, self.payload_bytes);
+
+    if !self.pipeline.is_empty() {
+      println!("Likely pipeline: {}", self.pipeline.join(","));
+    } else if self.failure.is_none() {
+      println!("Nothing wackypixels made was found.");
+    }
+  }
+}
@@ -0,0 +1,30 @@
+//! The wackypixels pipeline and its transforms, shared by the CLI and the benchmarks
+pub mod transforms {
+  pub mod image;
+  pub mod pdf;
+  pub mod lzma;
+  pub mod unicode;
+  pub mod wav;
+  pub mod flate;
+}
+pub mod plugins {
+  #[cfg(feature = "native-plugins")]
+  pub mod native;
+  #[cfg(feature = "wasm-plugins")]
+  pub mod wasm;
+}
+pub mod pipeline;
+pub mod transform;
+pub mod error;
+pub mod cli;
+pub mod pipeline_builder;
+pub mod report;
+pub mod inspect;
+pub mod batch;
+pub mod plan;
+pub mod bench;
+pub mod registry;
+pub mod lint;
+pub mod chaos;
+pub mod verify;
+pub mod runs;
@@ -0,0 +1,70 @@
+//! Check a pipeline before it touches any data: chains that can't work are errors,
+//! chains that work but make no sense are warnings
+use crate::error::{PipelineError, Result};
+use crate::transform::{Kind, Transform};
+use serde::Serialize;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+  Warning,
+  Error,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Finding {
+  pub severity: Severity,
+  /// 1-based stage the finding is about
+  pub step: usize,
+  pub transform: String,
+  pub message: String,
+}
+
+impl fmt::Display for Finding {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let severity = match self.severity {
+      Severity::Warning => "warning",
+      Severity::Error => "error",
+    };
+    write!(f, "{} at step {} ({}): {}", severity, self.step, self.transform, self.message)
+  }
+}
+
+pub fn lint(transforms: &[Box<dyn Transform>]) -> Vec<Finding> {
+  let mut findings = Vec::new();
+  let mut given: Option<(Kind, &str)> = None;
+
+  for (i, transform) in transforms.iter().enumerate() {
+    let mut finding = |severity, message| findings.push(Finding {
+      severity,
+      step: i + 1,
+      transform: transform.name().to_string(),
+      message,
+    });
+    let (input, output) = (transform.input_kind(), transform.output_kind());
+
+    if let Some((kind, from)) = given {
+      if !input.accepts(kind) {
+        finding(Severity::Error, format!("needs {} but gets {} from {}", input, kind, from));
+      } else if kind == Kind::Compressed && output == Kind::Compressed {
+        finding(Severity::Warning, format!("compresses the already compressed output of {}", from));
+      } else if kind == output && output != Kind::Bytes {
+        finding(Severity::Warning, format!(
+          "turns {} from {} into {} again, which only makes it bigger",
+          kind, from, output
+        ));
+      }
+    }
+    given = Some((output, transform.name()));
+  }
+  findings
+}
+
+/// Fail on the first finding that makes the pipeline impossible to run
+pub fn check(transforms: &[Box<dyn Transform>]) -> Result<()> {
+  match lint(transforms).into_iter().find(|finding| finding.severity == Severity::Error) {
+    Some(error) => Err(PipelineError::Usage(format!("Invalid pipeline, {}", error).into())),
+    None => Ok(()),
+  }
+}
@@ -1,176 +1,348 @@
-mod transforms {
-  pub mod image;
-  pub mod pdf;
-  pub mod lzma;
-  pub mod unicode;
-  pub mod wav;
-  pub mod flate;
-}
-mod pipeline;
-mod transform;
-mod error;
-mod cli;
-mod pipeline_builder;
-
-use clap::Parser;
-use cli::*;
+use clap::{CommandFactory, Parser};
+use wackypixels::{batch, bench, chaos, cli::*, error, inspect, lint, pipeline, plan, registry, report, runs, verify};
 use std::{fs, io::{self, Write}};
-use error::Result;
-use std::path::PathBuf;
+use error::{Detail, PipelineError, Result};
+use report::{Direction, Reporter};
+use std::path::{Path, PathBuf};
+
+#[global_allocator]
+static ALLOCATOR: bench::CountingAllocator = bench::CountingAllocator;
 
 fn main() {
-  if let Err(e) = run() {
-    eprintln!("  Error: {}", e);
-    std::process::exit(1);
+  let cli = Cli::parse();
+  let reporter = cli.reporter();
+  if let Err(e) = run(cli) {
+    reporter.fatal(&e);
+    std::process::exit(e.exit_code());
   }
 }
-fn run() -> Result<()> {
-  let cli = Cli::parse();
+
+fn json(value: &impl serde::Serialize) -> Result<String> {
+  serde_json::to_string(value).map_err(|e| PipelineError::InvalidData(Detail::wrap(e)))
+}
+
+/// Make up the `--chaos` pipeline for `input` and save its manifest to `output`
+fn chaos_pipeline(
+  registry: &registry::Registry,
+  chaos: &ChaosOptions,
+  stages: usize,
+  seed: Option<u64>,
+  input: &Path,
+  output: &Path,
+  reporter: Reporter,
+) -> Result<pipeline::Pipeline> {
+  if pipeline::is_stdio(input) || pipeline::is_stdio(output) {
+    return Err(PipelineError::Usage("--chaos looks at the input and writes a manifest, it needs files".into()));
+  }
+  let seed = seed.unwrap_or_else(rand::random);
+  let manifest = chaos::Manifest::generate(stages, seed, chaos.chaos_max_bytes, &fs::read(input)?)?;
+  runs::create_output_dir(output)?;
+  let path = output.join(chaos::MANIFEST_FILE);
+  manifest.save(&path)?;
+  reporter.line(format!("Chaos seed {}: {}", seed, manifest.ids().join(",")));
+  reporter.line(format!("  Manifest: {}\n", path.display()));
+  manifest.pipeline(registry)
+}
+
+/// Ask a yes/no question on stderr, `default` being the answer to just pressing enter
+fn confirm(question: &str, default: bool) -> bool {
+  eprint!("{}", question);
+  io::stderr().flush().unwrap();
+  
+  let mut response = String::new();
+  io::stdin().read_line(&mut response).unwrap();
+  match response.trim() {
+    answer if answer.eq_ignore_ascii_case("y") => true,
+    answer if answer.eq_ignore_ascii_case("n") => false,
+    _ => default,
+  }
+}
+
+/// Report whether `decoded` is what was encoded, failing if it isn't
+fn verify(original: &Path, decoded: &Path, reporter: Reporter) -> Result<()> {
+  let verification = verify::verify(original, decoded)?;
+  reporter.verification(&verification);
+  if !verification.matches {
+    return Err(PipelineError::Mismatch(
+      format!("{} doesn't match {}", decoded.display(), original.display()).into()
+    ));
+  }
+  Ok(())
+}
+
+fn run(cli: Cli) -> Result<()> {
+  let reporter = cli.reporter();
+  let format = cli.format;
+  let registry = registry::Registry::with_plugins(&cli.plugins)?;
   
   match cli.command {
-    Commands::Encode { input, output, save_intermediates, pipeline } => {
-      let mut pipe = if let Some(types) = pipeline {
-        pipeline_builder::build_custom_pipeline(&types)
-      } else {
-        pipeline_builder::build_default_pipeline()
+    Commands::Encode { input, output, save_intermediates, pipeline, batch, chaos, options } => {
+      let mut pipe = match chaos.chaos {
+        Some(_) if batch.input_dir.is_some() => {
+          return Err(PipelineError::Usage("--chaos makes up a pipeline for one --input, not a directory".into()));
+        }
+        Some(stages) => chaos_pipeline(&registry, &chaos, stages, options.seed, &input, &output, reporter)?,
+        None => registry.pipeline(pipeline.as_deref(), &options)?,
       };
       
-      pipe = pipe.save_intermediates(save_intermediates);
-      println!("--- ENCODING ---");
-      pipe.print_summary();
+      pipe = pipe.save_intermediates(save_intermediates).reporter(reporter);
+      reporter.line("--- ENCODING ---");
+      pipe.print_summary(&output);
       
-      pipe.encode(&input, &output)?;
+      if let Some(input_dir) = &batch.input_dir {
+        batch::Batch {
+          input_dir,
+          pattern: batch.glob.as_deref().unwrap_or("*"),
+          output_dir: &output,
+          jobs: batch.jobs,
+        }.run(&pipe.reporter(Reporter::quiet()), Direction::Encode, None, reporter)?;
+      } else {
+        pipe.encode(&input, &output)?;
+      }
     }
     
-    Commands::Decode { input, output, save_intermediates, pipeline, output_file } => {
-      let mut pipe = if let Some(types) = pipeline {
-        pipeline_builder::build_custom_pipeline(&types)
-      } else {
-        pipeline_builder::build_default_pipeline()
+    Commands::Decode { input, output, save_intermediates, pipeline, manifest, output_file, progressive, batch, options } => {
+      let mut pipe = match &manifest {
+        Some(path) => chaos::Manifest::load(path)?.pipeline(&registry)?,
+        None => registry.pipeline(pipeline.as_deref(), &options)?,
       };
       
-      pipe = pipe.save_intermediates(save_intermediates);
+      pipe = pipe.save_intermediates(save_intermediates).progressive(progressive).reporter(reporter);
       
-      println!("--- DECODING ---");
-      pipe.print_summary();
+      reporter.line("--- DECODING ---");
+      pipe.print_summary(&output);
       
-      pipe.decode(&input, &output, Some(&output_file))?;
+      if let Some(input_dir) = &batch.input_dir {
+        batch::Batch {
+          input_dir,
+          pattern: batch.glob.as_deref().unwrap_or("encrypted.*"),
+          output_dir: &output,
+          jobs: batch.jobs,
+        }.run(&pipe.reporter(Reporter::quiet()), Direction::Decode, Some(&output_file), reporter)?;
+      } else {
+        pipe.decode(&input, &output, Some(&output_file))?;
+      }
     }
     
-    Commands::Clean { dirs, yes } => {
+    Commands::Clean { dirs, keep_last, dry_run, yes } => {
       let directories = dirs.unwrap_or_else(|| {
-        vec!["outputs".into(), "decrypted".into()]
+        vec!["outputs".into(), "decrypted".into(), runs::RUNS_DIR.into()]
       });
+      let cleanup = runs::plan_cleanup(&directories, keep_last)?;
+      reporter.cleanup(&cleanup, dry_run);
       
-      if !yes {
-        println!("About to delete:");
-        for dir in &directories {
-          if dir.exists() {
-            println!("  - {}/", dir.display());
-          }
-        }
-        println!("\nContinue? [y/N] ");
-        io::Write::flush(&mut std::io::stdout()).unwrap();
-        
-        let mut response = String::new();
-        io::stdin().read_line(&mut response).unwrap();
-        
-        if !response.trim().eq_ignore_ascii_case("y") {
-          println!("Cancelled.");
-          return Ok(());
-        }
+      if cleanup.remove.is_empty() || dry_run {
+        reporter.cleaned(&report::CleanRecord::new(&cleanup, &cleanup.remove, dry_run));
+        return Ok(());
       }
-      
-      for dir in directories {
-        if dir.exists() {
-          fs::remove_dir_all(&dir)
-            .map_err(|e| format!("Failed to remove{}: {}", dir.display(), e))?;
-          println!("  Removed {}/", dir.display());
-        } else {
-          println!("X {} (doesn't exist)", dir.display());
-        }
+      if !yes && !confirm("\nContinue? [y/N] ", false) {
+        reporter.line("Cancelled.");
+        reporter.cleaned(&report::CleanRecord::new(&cleanup, &[], dry_run));
+        return Ok(());
       }
       
-      println!("Cleaned!")
+      for dir in &cleanup.remove {
+        runs::remove(dir)?;
+        reporter.removed(dir);
+      }
+      reporter.cleaned(&report::CleanRecord::new(&cleanup, &cleanup.remove, dry_run));
     }
     
     Commands::List => {
+      if format == report::ReportFormat::Json {
+        let infos: Vec<_> = registry.entries().iter().map(|entry| &entry.info).collect();
+        println!("{}", json(&infos)?);
+        return Ok(());
+      }
       println!("Available transforms: \n");
       
-      let all_types = [
-          TransformType::Image,
-          TransformType::Pdf,
-          TransformType::Lzma,
-          TransformType::Unicode,
-          TransformType::Wav,
-          TransformType::Gzip,
-      ];
+      for entry in registry.entries() {
+        let info = &entry.info;
+        println!("  {:14} - {}", info.id, info.description);
+        println!("  {:14}   {} -> {}", "", info.input, info.output);
+        if !info.aliases.is_empty() {
+          println!("  {:14}   also: {}", "", info.aliases.join(", "));
+        }
+        match &entry.source {
+          registry::Source::Builtin => {}
+          registry::Source::Native(path) | registry::Source::Wasm(path) => {
+            println!("  {:14}   plugin: {}", "", path.display());
+          }
+        }
+        for parameter in &info.parameters {
+          let flag = match (&entry.source, &parameter.value_name) {
+            (registry::Source::Builtin, Some(value)) => format!("--{} <{}>", parameter.name, value),
+            (registry::Source::Builtin, None) => format!("--{}", parameter.name),
+            _ => format!("--plugin-param {}.{}=<value>", info.id, parameter.name),
+          };
+          let description = parameter.description.lines().collect::<Vec<_>>().join(" ");
+          match &parameter.default {
+            Some(default) => println!("  {:14}     {}  {} (default {})", "", flag, description, default),
+            None => println!("  {:14}     {}  {}", "", flag, description),
+          }
+        }
+        for example in &info.examples {
+          println!("  {:14}   $ {}", "", example);
+        }
+        println!();
+      }
+      
+      println!("Default pipeline: {}", registry::default_ids().join(","));
+    }
+    
+    Commands::Completions { shell } => {
+      clap_complete::generate(shell, &mut Cli::command(), "wackypixels", &mut io::stdout());
+    }
+    
+    Commands::Plan { input, pipeline, options } => {
+      let transforms = registry.transforms(pipeline.as_deref(), &options)?;
+      let data = pipeline::read_input(&input)?;
+      let plan = plan::plan(&transforms, data)?;
       
-      for t in all_types {
-        println!("  {:14} - {}", format!("{:?}", t).to_lowercase(), t.description());
+      if format == report::ReportFormat::Json {
+        println!("{}", json(&plan)?);
+      } else {
+        println!("Plan for {} ({} bytes)\n", input.display(), plan.input_bytes);
+        plan.print();
+      }
+      if plan.stages.iter().any(|stage| !stage.fits) {
+        return Err(PipelineError::Usage("The payload is too big for this pipeline".into()));
       }
+    }
+    
+    Commands::Bench { input, pipeline, iterations, save_baseline, baseline, threshold, options } => {
+      let transforms = registry.transforms(pipeline.as_deref(), &options)?;
+      let data = pipeline::read_input(&input)?;
+      reporter.line(format!("Benchmarking {} ({} bytes), {} iterations\n", input.display(), data.len(), iterations));
+      let results = bench::bench(&transforms, pipeline.unwrap_or_else(registry::default_ids), &input, data, iterations)?;
       
-      println!("\nExample usage:");
-      println!("  wackypixels encode --pipeline image,lzma,unicode")
+      if format == report::ReportFormat::Json {
+        println!("{}", json(&results)?);
+      } else {
+        results.print();
+      }
+      if let Some(path) = &save_baseline {
+        results.save(path)?;
+        reporter.line(format!("\n  Baseline saved: {}", path.display()));
+      }
+      if let Some(path) = &baseline {
+        let regressions = results.regressions(&bench::Bench::load(path)?, threshold);
+        for regression in &regressions {
+          reporter.line(format!(
+            "  X {:?} {} {}: {:.2} -> {:.2}",
+            regression.direction, regression.transform, regression.metric, regression.baseline, regression.current,
+          ));
+        }
+        if !regressions.is_empty() {
+          return Err(PipelineError::InvalidData(
+            format!("{} regressions against {}", regressions.len(), path.display()).into()
+          ));
+        }
+        reporter.line(format!("\n  No regressions against {}", path.display()));
+      }
     }
     
-    Commands::Run { input, encode_output, decode_output, output_file, pipeline, save_intermediates, yes } => {
-      println!("!! Running Full Pipeline\n");
-      
-      // Clean first
-      let dirs: Vec<PathBuf> = vec!["outputs".into(), "decrypted".into()];
-      if !yes {
-        print!("Clean output directories first? [Y/n] ");
-        io::stdout().flush().unwrap();
-        
-        let mut response = String::new();
-        io::stdin().read_line(&mut response).unwrap();
-        
-        if !response.trim().eq_ignore_ascii_case("n") {
-          for dir in &dirs {
-            if dir.exists() {
-              fs::remove_dir_all(&dir)
-                .map_err(|e| format!("Failed to remove{}: {}", dir.display(), e))?;
-              println!("  Removed {}/", dir.display());
-            }
-          }
-          println!("  Cleaned!\n");
+    Commands::Lint { pipeline, options } => {
+      let transforms = registry.transforms(pipeline.as_deref(), &options)?;
+      let findings = lint::lint(&transforms);
+      
+      if format == report::ReportFormat::Json {
+        println!("{}", json(&findings)?);
+      } else if findings.is_empty() {
+        println!("No problems found.");
+      } else {
+        for finding in &findings {
+          println!("  {}", finding);
         }
+      }
+      let errors = findings.iter().filter(|finding| finding.severity == lint::Severity::Error).count();
+      if errors > 0 {
+        return Err(PipelineError::Usage(format!("{} of the transforms can't work in this order", errors).into()));
+      }
+    }
+    
+    Commands::Inspect { file, options } => {
+      let data = pipeline::read_input(&file)?;
+      let inspection = inspect::inspect(&registry, data, &options);
+      
+      if format == report::ReportFormat::Json {
+        println!("{}", json(&inspection)?);
       } else {
-        for dir in &dirs {
-          if dir.exists() {
-            fs::remove_dir_all(dir)
-              .map_err(|e| format!("Failed to remove{}: {}", dir.display(), e))?;
-            println!("  Removed {}/", dir.display());
-          }
+        println!("Inspecting: {}\n", file.display());
+        inspection.print();
+        if !inspection.pipeline.is_empty() {
+          println!("Decode with: wackypixels decode -i {} -p {}", file.display(), inspection.pipeline.join(","));
+        }
+      }
+    }
+    
+    Commands::Verify { original, decoded } => {
+      // The verdict is the point, so it shows even with --quiet
+      verify(&original, &decoded, Reporter::new(format, report::Verbosity::Normal))?;
+    }
+    
+    Commands::Run {
+      input, encode_output, decode_output, runs_dir, keep_last, output_file, pipeline, save_intermediates, yes, chaos, options,
+    } => {
+      reporter.line("!! Running Full Pipeline\n");
+      
+      let explicit = [&encode_output, &decode_output];
+      if pipeline::is_stdio(&input) || explicit.iter().copied().flatten().any(|dir| pipeline::is_stdio(dir)) {
+        return Err(PipelineError::Usage("`run` works on files, use `encode`/`decode` for stdin/stdout".into()));
+      }
+      
+      // -e/-d may point at an earlier run, which gets cleaned first if wackypixels made it
+      let stale: Vec<&PathBuf> = explicit.into_iter().flatten().filter(|dir| runs::is_marked(dir)).collect();
+      if !stale.is_empty() && (yes || confirm("Clean output directories first? [Y/n] ", true)) {
+        for dir in stale {
+          runs::remove(dir)?;
+          reporter.line(format!("  Removed {}/", dir.display()));
         }
+        reporter.line("  Cleaned!\n");
       }
       
+      let (encode_output, decode_output) = match (encode_output, decode_output) {
+        (Some(encode), Some(decode)) => (encode, decode),
+        (encode, decode) => {
+          let run = runs::create_run_dir(&runs_dir, &fs::read(&input)?)?;
+          reporter.line(format!("  Run folder: {}\n", run.display()));
+          (encode.unwrap_or_else(|| run.join("encoded")), decode.unwrap_or_else(|| run.join("decoded")))
+        }
+      };
+      
       // Encode
-      let mut pipeline = if let Some(types) = pipeline {
-        pipeline_builder::build_custom_pipeline(&types)
-      } else {
-        pipeline_builder::build_default_pipeline()
+      let mut pipeline = match chaos.chaos {
+        Some(stages) => chaos_pipeline(&registry, &chaos, stages, options.seed, &input, &encode_output, reporter)?,
+        None => registry.pipeline(pipeline.as_deref(), &options)?,
       };
-      pipeline = pipeline.save_intermediates(save_intermediates);
+      pipeline = pipeline.save_intermediates(save_intermediates).reporter(reporter);
       
-      println!("--- ENCODING ---");
-      pipeline.print_summary();
+      reporter.line("--- ENCODING ---");
+      pipeline.print_summary(&encode_output);
       
       let encrypted = pipeline.encode(&input, &encode_output)?;
       
-      println!("\n{}\n", "-".repeat(60));
+      reporter.line(format!("\n{}\n", "-".repeat(60)));
       
       // Decode
-      println!("--- DECODING ---");
-      pipeline.print_summary();
+      reporter.line("--- DECODING ---");
+      pipeline.print_summary(&decode_output);
       
       let decrypted = pipeline.decode(&encrypted, &decode_output, Some(&output_file))?;
+      verify(&input, &decrypted, reporter)?;
+      
+      if let Some(keep_last) = keep_last {
+        // Never the run that just finished
+        for dir in runs::plan_cleanup(&[runs_dir], keep_last.max(1))?.remove {
+          runs::remove(&dir)?;
+          reporter.line(format!("  Removed old run {}/", dir.display()));
+        }
+      }
       
-      println!("--- SUCCESS ---");
-      println!("Original:  {}", input.display());
-      println!("Encrypted: {}", encrypted.display());
-      println!("Decrypted: {}", decrypted.display());
+      reporter.line("\n--- SUCCESS ---");
+      reporter.line(format!("Original:  {}", input.display()));
+      reporter.line(format!("Encrypted: {}", encrypted.display()));
+      reporter.line(format!("Decrypted: {}", decrypted.display()));
     }
   }
   
@@ -1,11 +1,63 @@
-use crate::error::{Result};
-use crate::transform::Transform;
+use crate::error::{PipelineError, Result};
+use crate::lint::{self, Finding};
+use crate::report::{self, Direction, Reporter, StageRecord, SummaryRecord};
+use crate::runs;
+use crate::transform::{Partial, Transform};
 use std::path::{Path, PathBuf};
 use std::fs;
+use std::io::{self, Read, Write};
+use std::time::Instant;
 
+/// `-` in place of an input or output path means stdin/stdout
+pub fn is_stdio(path: &Path) -> bool {
+  path == Path::new("-")
+}
+
+pub fn read_input(input: &Path) -> Result<Vec<u8>> {
+  if is_stdio(input) {
+    let mut data = Vec::new();
+    io::stdin().lock().read_to_end(&mut data)?;
+    return Ok(data);
+  }
+  Ok(fs::read(input)?)
+}
+
+fn write_stdout(data: &[u8]) -> Result<()> {
+  let mut stdout = io::stdout().lock();
+  stdout.write_all(data)?;
+  stdout.flush()?;
+  Ok(())
+}
+
+/// Input read between preview attempts when decoding progressively
+const PREVIEW_STEP: usize = 64 * 1024;
+
+/// Intermediate file name stem for a transform, e.g. `lzma_compression`
+fn slug(name: &str) -> String {
+  name.replace(" ", "_").to_lowercase()
+}
+
+/// Split an intermediate file name like `003_lzma_compression.xz` or
+/// `003_lzma_compression_decoded.xz` into (step, slug, decoded)
+fn parse_intermediate_name(path: &Path) -> Option<(usize, &str, bool)> {
+  let stem = path.file_stem()?.to_str()?;
+  let (step, rest) = stem.split_once('_')?;
+  if step.len() < 3 || !step.bytes().all(|b| b.is_ascii_digit()) {
+    return None;
+  }
+  let step = step.parse().ok()?;
+  match rest.strip_suffix("_decoded") {
+    Some(name) => Some((step, name, true)),
+    None => Some((step, rest, false)),
+  }
+}
+
+#[derive(Default)]
 pub struct Pipeline {
   transforms: Vec<Box<dyn Transform>>,
   save_intermediates: bool,
+  progressive: bool,
+  reporter: Reporter,
 }
 
 impl Pipeline {
@@ -13,147 +65,403 @@ impl Pipeline {
     Self {
       transforms: vec![],
       save_intermediates: false,
+      progressive: false,
+      reporter: Reporter::default(),
     }
   }
   
+  #[allow(clippy::should_implement_trait)]
   pub fn add<T: Transform + 'static>(mut self, transform: T) -> Self {
     self.transforms.push(Box::new(transform));
     self
   }
   
+  pub fn add_boxed(mut self, transform: Box<dyn Transform>) -> Self {
+    self.transforms.push(transform);
+    self
+  }
+  
   pub fn save_intermediates(mut self, enable: bool) -> Self {
     self.save_intermediates = enable;
     self
   }
   
+  /// Decode while the input is still arriving and keep partial output of a truncated input
+  pub fn progressive(mut self, enable: bool) -> Self {
+    self.progressive = enable;
+    self
+  }
+  
+  pub fn reporter(mut self, reporter: Reporter) -> Self {
+    self.reporter = reporter;
+    self
+  }
+  
+  /// Problems with the order of the transforms, see `lint::lint`
+  pub fn lint(&self) -> Vec<Finding> {
+    lint::lint(&self.transforms)
+  }
+  
+  /// Fail if the transforms can't possibly work in this order
+  pub fn validate(&self) -> Result<()> {
+    lint::check(&self.transforms)
+  }
+  
+  /// Number of leading transforms the intermediate at `input` has already been through,
+  /// going by its `{step:03}_{name}` file name
+  fn completed_steps(&self, input: &Path) -> Option<usize> {
+    let (step, name, decoded) = parse_intermediate_name(input)?;
+    let transform = self.transforms.get(step.checked_sub(1)?)?;
+    if slug(transform.name()) != name {
+      return None;
+    }
+    // A decoded intermediate is what was left after reversing `step`
+    Some(if decoded { step - 1 } else { step })
+  }
+  
+  /// Like `completed_steps`, falling back to sniffing which transform's output the data looks like
+  fn sniff_completed_steps(&self, input: &Path, data: &[u8]) -> Option<usize> {
+    self.completed_steps(input).or_else(|| {
+      let last = self.transforms.iter().rposition(|t| t.sniff(data).is_some())?;
+      Some(last + 1)
+    })
+  }
+  
+  /// Encode `input` into `output_dir`, either of which can be `-` for stdin/stdout.
+  /// Intermediates are skipped when writing to stdout.
+  /// An intermediate from an earlier run picks up after the step that wrote it
   pub fn encode(&self, input: &Path, output_dir: &Path) -> Result<PathBuf> {
-    fs::create_dir_all(output_dir)?;
+    self.validate()?;
+    let started = Instant::now();
+    let to_stdout = is_stdio(output_dir);
+    let reporter = self.reporter.stdout_busy(to_stdout);
+    if !to_stdout {
+      runs::create_output_dir(output_dir)?;
+    }
     
-    let mut data = fs::read(input)
-      .map_err(|e| {
-        eprintln!("Failed to read input file: {}", input.display());
-        e
+    let mut data = read_input(input)
+      .inspect_err(|_| {
+        reporter.line(format!("Failed to read input file: {}", input.display()));
       })?;
+    let input_bytes = data.len();
     
-    println!("  Input: {} ({} bytes)\n", input.display(), data.len());
+    reporter.input(input, data.len());
     
     let total = self.transforms.len();
+    let skipped = self.completed_steps(input).unwrap_or(0);
+    if skipped > 0 {
+      reporter.line(format!("  Resuming after step {}/{}\n", skipped, total));
+    }
     
-    for (i, transform) in self.transforms.iter().enumerate() {
+    for (i, transform) in self.transforms.iter().enumerate().skip(skipped) {
       let step = i + 1;
       
-      println!("[{}/{}] Applying: {} ...", step, total, transform.name());
-      std::io::Write::flush(&mut std::io::stdout()).unwrap();
+      reporter.stage_start(Direction::Encode, step, total, transform.name());
       
+      let stage_started = Instant::now();
       let size_before = data.len();
       // Give errors context
       data = transform.encode(data).map_err(|e| {
-        eprintln!("X Failed at step {}/{}: {}", step, total, transform.name());
-        eprintln!("  Error: {}", e);
+        let e = e.in_stage(Direction::Encode, step, total, transform.name());
+        if let Some(stage) = e.stage() {
+          reporter.stage_failed(stage);
+        }
         e
       })?;
+      let duration = stage_started.elapsed();
       
-      let ratio = (data.len() as f64 / size_before as f64) * 100.0;
-      println!("  ({} bytes, {:.1}%", data.len(), ratio);
-      
-      if self.save_intermediates {
+      let mut intermediate = None;
+      if self.save_intermediates && !to_stdout {
         let filename = format!("{:03}_{}.{}", 
           step, 
-          transform.name().replace(" ", "_").to_lowercase(),
+          slug(transform.name()),
           transform.extension(),
         );
         let path = output_dir.join(filename);
         fs::write(&path, &data)
-          .map_err(|e| {
-            eprintln!("Failed to save intermediate file: {}", path.display());
-            e
+          .inspect_err(|_| {
+            reporter.line(format!("Failed to save intermediate file: {}", path.display()));
           })?;
-        println!("  Saved: {}", path.display());
+        intermediate = Some(path.display().to_string());
       }
       
-      println!("  Output size: {} bytes", data.len());
+      reporter.stage(&StageRecord {
+        direction: Direction::Encode,
+        step,
+        total,
+        name: transform.name().to_string(),
+        input_bytes: size_before,
+        output_bytes: data.len(),
+        ratio: report::ratio(data.len(), size_before),
+        duration_ms: report::millis(duration),
+        intermediate,
+        stats: transform.stats(&data).into_iter().collect(),
+      });
     }
     
-    let final_ext = self.transforms.last()
-      .map(|t| t.extension())
-      .unwrap_or("bin");
-    let output_path = output_dir.join(format!("encrypted.{}", final_ext));
-    fs::write(&output_path, &data)?;
+    let output_path = if to_stdout {
+      write_stdout(&data)?;
+      output_dir.to_path_buf()
+    } else {
+      let final_ext = self.transforms.last()
+        .map(|t| t.extension())
+        .unwrap_or("bin");
+      let output_path = output_dir.join(format!("encrypted.{}", final_ext));
+      fs::write(&output_path, &data)?;
+      output_path
+    };
     
-    println!("\n Encryption complete: {}", output_path.display());
+    reporter.summary(&SummaryRecord {
+      direction: Direction::Encode,
+      input: input.display().to_string(),
+      output: if to_stdout { "<stdout>".to_string() } else { output_path.display().to_string() },
+      stages: total - skipped,
+      skipped,
+      input_bytes,
+      output_bytes: data.len(),
+      ratio: report::ratio(data.len(), input_bytes),
+      duration_ms: report::millis(started.elapsed()),
+    });
     Ok(output_path)
   }
   
+  /// Decode `input` into `output_dir`, either of which can be `-` for stdin/stdout.
+  /// Intermediates are skipped when writing to stdout.
+  /// An intermediate from an earlier run is only run through the stages it still needs
   pub fn decode(&self, input: &Path, output_dir: &Path, output_file: Option<&Path>) -> Result<PathBuf> {
-    fs::create_dir_all(output_dir)?;
+    self.validate()?;
+    if self.progressive {
+      return self.decode_progressive(input, output_dir, output_file);
+    }
+    let started = Instant::now();
+    let to_stdout = is_stdio(output_dir);
+    let reporter = self.reporter.stdout_busy(to_stdout);
+    if !to_stdout {
+      runs::create_output_dir(output_dir)?;
+    }
     
-    let mut data = fs::read(input)
-      .map_err(|e| {
-        eprintln!("Failed to read encrypted file: {}", input.display());
-        e
+    let data = read_input(input)
+      .inspect_err(|_| {
+        reporter.line(format!("Failed to read encrypted file: {}", input.display()));
       })?;
+    let input_bytes = data.len();
     
-    println!("  Input: {} ({} bytes)\n", input.display(), data.len());
+    reporter.input(input, data.len());
     
     let total = self.transforms.len();
+    let remaining = self.resume_decode(input, &data, reporter);
+    let data = self.decode_stages(data, remaining, output_dir, reporter, false)?.data;
     
-    for (i, transform) in self.transforms.iter().rev().enumerate() {
-      let step = i + 1;
+    let output_path = if to_stdout {
+      write_stdout(&data)?;
+      output_dir.to_path_buf()
+    } else {
+      let output_path = output_file
+        .map(|p| output_dir.join(p))
+        .unwrap_or_else(|| output_dir.join("decrypted.png"));
+      fs::write(&output_path, &data)?;
+      output_path
+    };
+    
+    reporter.summary(&SummaryRecord {
+      direction: Direction::Decode,
+      input: input.display().to_string(),
+      output: if to_stdout { "<stdout>".to_string() } else { output_path.display().to_string() },
+      stages: remaining,
+      skipped: total - remaining,
+      input_bytes,
+      output_bytes: data.len(),
+      ratio: report::ratio(data.len(), input_bytes),
+      duration_ms: report::millis(started.elapsed()),
+    });
+    Ok(output_path)
+  }
+  
+  /// How many stages `data` still has to go back through, saying so when that's not all of them
+  fn resume_decode(&self, input: &Path, data: &[u8], reporter: Reporter) -> usize {
+    let total = self.transforms.len();
+    let remaining = self.sniff_completed_steps(input, data).unwrap_or(total);
+    if remaining < total {
+      reporter.line(format!("  Resuming at step {}/{}\n", total - remaining + 1, total));
+    }
+    remaining
+  }
+  
+  /// Decode `data` through the first `remaining` stages, last one first, reporting each and saving
+  /// intermediates. With `partial` a stage keeps what it can of cut off data instead of failing,
+  /// the result is only complete if every stage was
+  fn decode_stages(&self, mut data: Vec<u8>, remaining: usize, output_dir: &Path, reporter: Reporter, partial: bool) -> Result<Partial> {
+    let total = self.transforms.len();
+    let mut complete = true;
+    for i in (0..remaining).rev() {
+      let transform = &self.transforms[i];
+      let step = total - i;
       
-      println!("[{}/{}] Reversing: {} ...", step, total, transform.name());
-      std::io::Write::flush(&mut std::io::stdout()).unwrap();
+      reporter.stage_start(Direction::Decode, step, total, transform.name());
       
+      let stats = transform.stats(&data).into_iter().collect();
+      let stage_started = Instant::now();
       let size_before = data.len();
-      data = transform.decode(data).map_err(|e| {
-        eprintln!("X Failed at decode step {}/{}: {}", step, total, transform.name());
-        eprintln!("  Error: {}", e);
-        eprintln!("  This could be a number of things:");
-        eprintln!("    - Corrupted data at this stage");
-        eprintln!("    - Wrong pipeline order");
-        eprintln!("    - Missing transformation step");
+      let decoded = if partial {
+        transform.decode_partial(data)
+      } else {
+        transform.decode(data).map(|data| Partial { data, complete: true })
+      };
+      let decoded = decoded.map_err(|e| {
+        let e = e.in_stage(Direction::Decode, step, total, transform.name());
+        if let Some(stage) = e.stage() {
+          reporter.stage_failed(stage);
+        }
         e
       })?;
+      complete &= decoded.complete;
+      data = decoded.data;
+      let duration = stage_started.elapsed();
       
-      let ratio = (data.len() as f64 / size_before as f64) * 100.0;
-      println!("  ({} bytes, {:.1}%", data.len(), ratio);
-      
-      if self.save_intermediates {
-        let extension = if i + 1 < total {
-          self.transforms[total - i - 2].extension() 
+      let mut intermediate = None;
+      if self.save_intermediates && !is_stdio(output_dir) {
+        let extension = if i > 0 {
+          self.transforms[i - 1].extension() 
         } else {
           "png"
         };
         let filename = format!("{:03}_{}_decoded.{}",
-          total-i,
-          transform.name().replace(" ", "_").to_lowercase(),
+          i + 1,
+          slug(transform.name()),
           extension
         );
         let path = output_dir.join(filename);
         fs::write(&path, &data)
-          .map_err(|e| {
-            eprintln!("Failed to save intermediate file: {}", path.display());
-            e
+          .inspect_err(|_| {
+            reporter.line(format!("Failed to save intermediate file: {}", path.display()));
           })?;
-        println!("  Saved: {}", path.display());
+        intermediate = Some(path.display().to_string());
       }
       
-      println!("  Output size: {} bytes", data.len());
+      reporter.stage(&StageRecord {
+        direction: Direction::Decode,
+        step,
+        total,
+        name: transform.name().to_string(),
+        input_bytes: size_before,
+        output_bytes: data.len(),
+        ratio: report::ratio(data.len(), size_before),
+        duration_ms: report::millis(duration),
+        intermediate,
+        stats,
+      });
     }
+    Ok(Partial { data, complete })
+  }
+  
+  /// Run the first `remaining` stages' `decode_partial` quietly, for a preview
+  fn preview(&self, data: Vec<u8>, remaining: usize) -> Result<Partial> {
+    let mut partial = Partial { data, complete: true };
+    for transform in self.transforms[..remaining].iter().rev() {
+      let next = transform.decode_partial(partial.data)?;
+      partial = Partial { data: next.data, complete: partial.complete && next.complete };
+    }
+    Ok(partial)
+  }
+  
+  /// Decode a stream as it arrives on stdin, rewriting `preview.png` whenever more of the image
+  /// can be shown. A cut off input still gets whatever could be recovered written out
+  fn decode_progressive(&self, input: &Path, output_dir: &Path, output_file: Option<&Path>) -> Result<PathBuf> {
+    let started = Instant::now();
+    let to_stdout = is_stdio(output_dir);
+    let reporter = self.reporter.stdout_busy(to_stdout);
+    if !to_stdout {
+      runs::create_output_dir(output_dir)?;
+    }
+    
+    // Files are already all there, so only stdin gets previews
+    let preview_path = output_dir.join("preview.png");
+    let previews = is_stdio(input) && !to_stdout;
+    let mut reader: Box<dyn Read> = if is_stdio(input) {
+      Box::new(io::stdin().lock())
+    } else {
+      Box::new(fs::File::open(input)?)
+    };
+    
+    let total = self.transforms.len();
+    let mut data = Vec::new();
+    let mut buffer = vec![0; PREVIEW_STEP];
+    let mut next_preview = PREVIEW_STEP;
+    let mut last_preview = Vec::new();
+    loop {
+      let read = match reader.read(&mut buffer) {
+        Ok(0) => break,
+        Ok(read) => read,
+        Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+        Err(e) => return Err(e.into()),
+      };
+      data.extend_from_slice(&buffer[..read]);
+      
+      if previews && data.len() >= next_preview {
+        next_preview = data.len() + (data.len() / 4).max(PREVIEW_STEP);
+        let remaining = self.sniff_completed_steps(input, &data).unwrap_or(total);
+        let Ok(partial) = self.preview(data.clone(), remaining) else {
+          continue;
+        };
+        if partial.data != last_preview && image::guess_format(&partial.data).is_ok() {
+          fs::write(&preview_path, &partial.data)?;
+          reporter.line(format!("  Preview after {} bytes: {}", data.len(), preview_path.display()));
+          last_preview = partial.data;
+        }
+      }
+    }
+    
+    let input_bytes = data.len();
+    reporter.input(input, input_bytes);
+    let remaining = self.resume_decode(input, &data, reporter);
+    let partial = self.decode_stages(data, remaining, output_dir, reporter, true)?;
     
-    let output_path = output_file
-      .map(|p| output_dir.join(p))
-      .unwrap_or_else(|| output_dir.join("decrypted.png"));
+    let output_path = if to_stdout {
+      write_stdout(&partial.data)?;
+      output_dir.to_path_buf()
+    } else {
+      let output_path = output_file
+        .map(|p| output_dir.join(p))
+        .unwrap_or_else(|| output_dir.join("decrypted.png"));
+      fs::write(&output_path, &partial.data)?;
+      if preview_path.exists() {
+        fs::remove_file(&preview_path)?;
+      }
+      output_path
+    };
     
-    fs::write(&output_path, data)?;
+    let output = if to_stdout { "<stdout>".to_string() } else { output_path.display().to_string() };
+    if !partial.complete {
+      return Err(PipelineError::Truncated(format!(
+        "Input was cut off, only part of it could be recovered into {}",
+        output
+      ).into()));
+    }
     
-    println!("\n Decryption complete: {}", output_path.display());
+    reporter.summary(&SummaryRecord {
+      direction: Direction::Decode,
+      input: input.display().to_string(),
+      output,
+      stages: remaining,
+      skipped: total - remaining,
+      input_bytes,
+      output_bytes: partial.data.len(),
+      ratio: report::ratio(partial.data.len(), input_bytes),
+      duration_ms: report::millis(started.elapsed()),
+    });
     Ok(output_path)
   }
   
-  pub fn print_summary(&self) {
-    println!("Pipeline ({} steps):", self.transforms.len());
-    for (i, transform) in self.transforms.iter().enumerate() {
-      println!("  {}. {} -> .{}\n", i + 1, transform.name(), transform.extension());
+  /// List the steps and any lint findings for a run writing to `output_dir`,
+  /// keeping JSON off stdout when that's where the data goes
+  pub fn print_summary(&self, output_dir: &Path) {
+    let reporter = self.reporter.stdout_busy(is_stdio(output_dir));
+    let steps: Vec<(&str, &str)> = self.transforms.iter()
+      .map(|t| (t.name(), t.extension()))
+      .collect();
+    reporter.pipeline(&steps);
+    for finding in self.lint() {
+      reporter.finding(&finding);
     }
   }
 }
\ No newline at end of file
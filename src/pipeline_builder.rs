@@ -1,29 +1,109 @@
-use crate::cli::TransformType;
-use crate::pipeline::Pipeline;
+use crate::cli::TransformOptions;
+use crate::transform::Transform;
 use crate::transforms::*;
 
-pub fn build_default_pipeline() -> Pipeline {
-  Pipeline::new()
-    .add(image::ImageTransform)
-    .add(pdf::PdfTransform)
-    .add(lzma::LzmaTransform)
-    .add(unicode::UnicodeTransform)
-    .add(wav::WavTransform)
+/// Everything the CLI, `list` and the registry need to know about a built-in transform.
+/// Names and extensions come from the transform itself
+pub struct Builtin {
+  /// Name in `--pipeline`
+  pub id: &'static str,
+  /// Other names `--pipeline` accepts
+  pub aliases: &'static [&'static str],
+  pub description: &'static str,
+  /// Long flags of `TransformOptions` the transform reads
+  pub flags: &'static [&'static str],
+  pub examples: &'static [&'static str],
+  pub build: fn(&TransformOptions) -> Box<dyn Transform>,
 }
 
-pub fn build_custom_pipeline(types: &[TransformType]) -> Pipeline {
-  let mut pipeline = Pipeline::new();
-  
-  for t in types {
-    match t {
-      TransformType::Image => pipeline = pipeline.add(image::ImageTransform),
-      TransformType::Pdf => pipeline = pipeline.add(pdf::PdfTransform),
-      TransformType::Lzma => pipeline = pipeline.add(lzma::LzmaTransform),
-      TransformType::Unicode => pipeline = pipeline.add(unicode::UnicodeTransform),
-      TransformType::Wav => pipeline = pipeline.add(wav::WavTransform),
-      TransformType::Gzip => pipeline = pipeline.add(flate::GzipTransform),
-    }
-  }
-  
-  pipeline
-}
\ No newline at end of file
+fn unicode(options: &TransformOptions) -> Box<dyn Transform> {
+  Box::new(unicode::UnicodeTransform::new()
+    .wrap(options.unicode_wrap)
+    .split(options.unicode_split)
+    .headers(options.unicode_headers)
+    .chunk_size(options.chunk_size))
+}
+
+fn pdf(options: &TransformOptions) -> Box<dyn Transform> {
+  Box::new(pdf::PdfTransform::new()
+    .strategy(options.pdf_strategy)
+    .cover(options.pdf_cover.clone())
+    .preview(options.pdf_preview)
+    .caption(options.pdf_caption.clone())
+    .metadata(pdf::PdfMetadata {
+      title: options.pdf_title.clone(),
+      author: options.pdf_author.clone(),
+      created: options.pdf_created.clone(),
+      modified: options.pdf_modified.clone(),
+    })
+    .compress(options.pdf_compress)
+    .encryption(options.pdf_encrypt)
+    .password(options.pdf_password.clone())
+    .object_streams(options.pdf_object_streams)
+    .seed(options.seed))
+}
+
+pub const BUILTINS: &[Builtin] = &[
+  Builtin {
+    id: "image",
+    aliases: &["png"],
+    description: "Image serialization (PNG -> binary)",
+    flags: &[],
+    examples: &["wackypixels encode -p image,lzma,unicode"],
+    build: |_| Box::new(image::ImageTransform),
+  },
+  Builtin {
+    id: "pdf",
+    aliases: &[],
+    description: "PDF, stored in /Info, an attachment, XMP, an image or invisible text",
+    flags: &[
+      "pdf-strategy", "cover", "pdf-preview", "pdf-caption", "pdf-title", "pdf-author", "pdf-created",
+      "pdf-modified", "pdf-compress", "pdf-encrypt", "pdf-password", "pdf-object-streams", "seed",
+    ],
+    examples: &[
+      "wackypixels encode -p image,pdf --pdf-strategy attachment",
+      "wackypixels encode -p image,pdf --pdf-preview full --pdf-caption \"Holiday 2024\"",
+    ],
+    build: pdf,
+  },
+  Builtin {
+    id: "lzma",
+    aliases: &["xz"],
+    description: "LZMA/XZ compression",
+    flags: &["chunk-size"],
+    examples: &["wackypixels encode -p image,lzma,wav"],
+    build: |options| Box::new(lzma::LzmaTransform::new().chunk_size(options.chunk_size)),
+  },
+  Builtin {
+    id: "unicode",
+    aliases: &["text"],
+    description: "Unicode, multimode encoding (CJK, Emojis, Hidden characters, etc)",
+    flags: &["unicode-wrap", "unicode-split", "unicode-headers", "chunk-size"],
+    examples: &["wackypixels encode -p image,lzma,unicode --unicode-wrap 40 --unicode-split 280"],
+    build: unicode,
+  },
+  Builtin {
+    id: "wav",
+    aliases: &["audio"],
+    description: "WAV audio encoding (amplitude modulation)",
+    flags: &["chunk-size"],
+    examples: &["wackypixels encode -p image,lzma,wav"],
+    build: |options| Box::new(wav::WavTransform::new().chunk_size(options.chunk_size)),
+  },
+  Builtin {
+    id: "gzip",
+    aliases: &["gz"],
+    description: "Gzip compression",
+    flags: &[],
+    examples: &["wackypixels encode -p image,lzma,unicode,gzip"],
+    build: |_| Box::new(flate::GzipTransform),
+  },
+];
+
+/// What runs when no `--pipeline` is given
+pub const DEFAULT_PIPELINE: [&str; 5] = ["image", "pdf", "lzma", "unicode", "wav"];
+
+/// The built-in transform called `name` or one of its aliases
+pub fn builtin(name: &str) -> Option<&'static Builtin> {
+  BUILTINS.iter().find(|b| b.id == name || b.aliases.contains(&name))
+}
@@ -0,0 +1,97 @@
+//! Predict what every stage of a pipeline will produce without running it on the whole input
+use crate::error::{PipelineError, Result};
+use crate::report;
+use crate::transform::Transform;
+use serde::Serialize;
+
+/// How much of each stage's input gets encoded for the sampled estimates
+pub const SAMPLE_BYTES: usize = 256 * 1024;
+
+#[derive(Debug, Serialize)]
+pub struct StagePlan {
+  pub transform: String,
+  /// "exact", "analytic" or "sampled"
+  pub model: &'static str,
+  pub input_bytes: u64,
+  pub output_bytes: u64,
+  /// output_bytes / input_bytes
+  pub ratio: f64,
+  /// Largest input the stage's carrier can hold, if it has a limit
+  pub capacity: Option<u64>,
+  pub fits: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Plan {
+  pub input_bytes: u64,
+  pub stages: Vec<StagePlan>,
+  pub output_bytes: u64,
+  /// output_bytes / input_bytes
+  pub ratio: f64,
+}
+
+pub fn plan(transforms: &[Box<dyn Transform>], input: Vec<u8>) -> Result<Plan> {
+  let input_bytes = input.len() as u64;
+  let mut size = input_bytes;
+  // A prefix of the real data at every stage, for the stages that need to see some
+  let mut sample = input;
+  let mut stages = Vec::new();
+
+  for transform in transforms {
+    let estimate = transform.estimate(size, &sample).map_err(|e| {
+      PipelineError::InvalidData(format!("Can't estimate {}: {}", transform.name(), e).into())
+    })?;
+    let capacity = transform.capacity();
+    stages.push(StagePlan {
+      transform: transform.name().to_string(),
+      model: estimate.model,
+      input_bytes: size,
+      output_bytes: estimate.bytes,
+      ratio: report::ratio(estimate.bytes as usize, size as usize),
+      capacity,
+      fits: capacity.is_none_or(|capacity| size <= capacity),
+    });
+
+    size = estimate.bytes;
+    sample = transform.encode(sample).map_err(|e| {
+      PipelineError::InvalidData(format!("Can't sample {}: {}", transform.name(), e).into())
+    })?;
+    sample.truncate(SAMPLE_BYTES);
+  }
+
+  Ok(Plan {
+    input_bytes,
+    stages,
+    output_bytes: size,
+    ratio: report::ratio(size as usize, input_bytes as usize),
+  })
+}
+
+impl Plan {
+  pub fn print(&self) {
+    let width = self.stages.iter()
+      .map(|stage| stage.transform.len())
+      .max()
+      .unwrap_or(0)
+      .max(5);
+    println!(
+      "  #  {:width$}  {:8}  {:>12}  {:>12}  {:>8}  Capacity",
+      "Stage", "Model", "Input", "Output", "Ratio",
+    );
+    for (i, stage) in self.stages.iter().enumerate() {
+      let capacity = match stage.capacity {
+        None => "-".to_string(),
+        Some(capacity) if stage.fits => format!("fits ({} max)", capacity),
+        Some(capacity) => format!("TOO BIG ({} max)", capacity),
+      };
+      println!(
+        "  {}  {:width$}  {:8}  {:>12}  {:>12}  {:>7.1}%  {}",
+        i + 1, stage.transform, stage.model, stage.input_bytes, stage.output_bytes, stage.ratio * 100.0, capacity,
+      );
+    }
+    println!("\n  Final size: ~{} bytes ({:.1}% of the input)", self.output_bytes, self.ratio * 100.0);
+    if self.stages.iter().any(|stage| !stage.fits) {
+      println!("  X The payload won't fit in every stage");
+    }
+  }
+}
@@ -0,0 +1,150 @@
+//! Transforms from shared libraries (`.so`, `.dylib`, `.dll`) over a small C ABI.
+//!
+//! A plugin exports `const PluginVTable *wackypixels_plugin(void)`. The table has to outlive
+//! the library and carries the ABI version, the plugin's `TransformInfo` as a NUL terminated
+//! JSON string, and `encode`/`decode` functions taking the input, the plugin's parameters as
+//! a JSON object, and a `PluginBuffer` to fill. They return 0 on success, anything else with
+//! a UTF-8 error message in the buffer. Every filled buffer is handed back to `free`
+use crate::error::{PipelineError, Result};
+use crate::registry::{Registry, Source, TransformInfo, plugin_params};
+use crate::transform::{Kind, Transform};
+use libloading::{Library, Symbol};
+use std::ffi::{CStr, CString, c_char};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Bumped whenever `PluginVTable` or the meaning of its fields changes
+pub const ABI_VERSION: u32 = 1;
+
+/// Symbol every plugin exports
+pub const ENTRY_POINT: &[u8] = b"wackypixels_plugin";
+
+/// Bytes owned by the plugin, only it knows how to free them
+#[repr(C)]
+pub struct PluginBuffer {
+  pub ptr: *mut u8,
+  pub len: usize,
+  pub cap: usize,
+}
+
+pub type TransformFn = unsafe extern "C" fn(
+  input: *const u8,
+  len: usize,
+  params: *const c_char,
+  out: *mut PluginBuffer,
+) -> i32;
+
+#[repr(C)]
+pub struct PluginVTable {
+  pub abi_version: u32,
+  pub info: *const c_char,
+  pub encode: TransformFn,
+  pub decode: TransformFn,
+  pub free: unsafe extern "C" fn(buffer: PluginBuffer),
+}
+
+struct NativeTransform {
+  info: TransformInfo,
+  path: PathBuf,
+  params: CString,
+  encode: TransformFn,
+  decode: TransformFn,
+  free: unsafe extern "C" fn(buffer: PluginBuffer),
+  // Keeps the functions above loaded
+  _library: Arc<Library>,
+}
+
+impl fmt::Debug for NativeTransform {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("NativeTransform")
+      .field("id", &self.info.id)
+      .field("path", &self.path)
+      .finish()
+  }
+}
+
+impl NativeTransform {
+  fn call(&self, function: TransformFn, input: &[u8]) -> Result<Vec<u8>> {
+    let mut out = PluginBuffer { ptr: std::ptr::null_mut(), len: 0, cap: 0 };
+    let status = unsafe { function(input.as_ptr(), input.len(), self.params.as_ptr(), &mut out) };
+    let data = if out.ptr.is_null() {
+      Vec::new()
+    } else {
+      unsafe { std::slice::from_raw_parts(out.ptr, out.len) }.to_vec()
+    };
+    unsafe { (self.free)(out) };
+    if status != 0 {
+      return Err(PipelineError::Plugin(format!("{}: {}", self.info.id, String::from_utf8_lossy(&data)).into()));
+    }
+    Ok(data)
+  }
+}
+
+impl Transform for NativeTransform {
+  fn encode(&self, input: Vec<u8>) -> Result<Vec<u8>> {
+    self.call(self.encode, &input)
+  }
+
+  fn decode(&self, input: Vec<u8>) -> Result<Vec<u8>> {
+    self.call(self.decode, &input)
+  }
+
+  fn name(&self) -> &str {
+    &self.info.name
+  }
+
+  fn extension(&self) -> &str {
+    &self.info.extension
+  }
+
+  fn input_kind(&self) -> Kind {
+    self.info.input
+  }
+
+  fn output_kind(&self) -> Kind {
+    self.info.output
+  }
+}
+
+fn error(path: &Path, message: impl fmt::Display) -> PipelineError {
+  PipelineError::Plugin(format!("{}: {}", path.display(), message).into())
+}
+
+/// Load the shared library at `path` and register its transform
+pub fn load(registry: &mut Registry, path: &Path) -> Result<()> {
+  // Loading runs the library's initializers, plugins are trusted code
+  let library = unsafe { Library::new(path) }.map_err(|e| error(path, e))?;
+  let vtable = unsafe {
+    let entry: Symbol<unsafe extern "C" fn() -> *const PluginVTable> =
+      library.get(ENTRY_POINT).map_err(|e| error(path, e))?;
+    entry().as_ref().ok_or_else(|| error(path, "wackypixels_plugin returned null"))?
+  };
+  if vtable.abi_version != ABI_VERSION {
+    return Err(error(path, format!(
+      "built for plugin ABI {}, this wackypixels speaks {}",
+      vtable.abi_version, ABI_VERSION
+    )));
+  }
+  if vtable.info.is_null() {
+    return Err(error(path, "no transform info"));
+  }
+  let info = unsafe { CStr::from_ptr(vtable.info) }.to_str().map_err(|e| error(path, e))?;
+  let info: TransformInfo = serde_json::from_str(info).map_err(|e| error(path, e))?;
+  let (encode, decode, free) = (vtable.encode, vtable.decode, vtable.free);
+  let library = Arc::new(library);
+  let path = path.to_path_buf();
+
+  registry.register(info.clone(), Source::Native(path.clone()), Arc::new(move |options| {
+    let params = CString::new(plugin_params(&info, options)?).map_err(|e| error(&path, e))?;
+    Ok(Box::new(NativeTransform {
+      info: info.clone(),
+      path: path.clone(),
+      params,
+      encode,
+      decode,
+      free,
+      _library: library.clone(),
+    }))
+  }))
+}
@@ -0,0 +1,180 @@
+//! Transforms from WebAssembly modules, run sandboxed in an embedded interpreter.
+//!
+//! A module gets no imports at all, so it can't touch files, the network or the clock, and every
+//! call runs in a fresh instance with limited memory and fuel. It exports its `memory` and:
+//!
+//! - `wp_alloc(len: i32) -> i32`, room for `len` bytes the host is about to write
+//! - `wp_info() -> i64`, its `TransformInfo` as JSON
+//! - `wp_encode(ptr: i32, len: i32, params_ptr: i32, params_len: i32) -> i64` and `wp_decode`,
+//!   taking the input and the plugin's parameters as a JSON object
+//!
+//! Results pack `(ptr << 32) | len`. Negative results point to a UTF-8 error message instead
+use crate::error::{PipelineError, Result};
+use crate::registry::{Registry, Source, TransformInfo, plugin_params};
+use crate::transform::{Kind, Transform};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use wasmi::{Config, Engine, Instance, Linker, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+/// Most linear memory a module may grow to
+const MEMORY_LIMIT: usize = 1 << 30;
+
+/// Instructions a call may run, plus `FUEL_PER_BYTE` for every input byte
+const FUEL_BASE: u64 = 1_000_000_000;
+const FUEL_PER_BYTE: u64 = 10_000;
+
+struct WasmTransform {
+  info: TransformInfo,
+  path: PathBuf,
+  params: String,
+  engine: Engine,
+  module: Arc<Module>,
+}
+
+impl fmt::Debug for WasmTransform {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("WasmTransform")
+      .field("id", &self.info.id)
+      .field("path", &self.path)
+      .finish()
+  }
+}
+
+fn error(path: &Path, message: impl fmt::Display) -> PipelineError {
+  PipelineError::Plugin(format!("{}: {}", path.display(), message).into())
+}
+
+/// A fresh sandbox running `module`
+struct Sandbox {
+  store: Store<StoreLimits>,
+  instance: Instance,
+}
+
+impl Sandbox {
+  fn new(engine: &Engine, module: &Module, fuel: u64) -> std::result::Result<Self, String> {
+    let limits = StoreLimitsBuilder::new().memory_size(MEMORY_LIMIT).build();
+    let mut store = Store::new(engine, limits);
+    store.limiter(|limits| limits);
+    store.set_fuel(fuel).map_err(|e| e.to_string())?;
+    let instance = Linker::new(engine)
+      .instantiate(&mut store, module)
+      .and_then(|pre| pre.start(&mut store))
+      .map_err(|e| e.to_string())?;
+    Ok(Self { store, instance })
+  }
+
+  /// Copy `data` into the module's memory, returning where it went
+  fn write(&mut self, data: &[u8]) -> std::result::Result<(i32, i32), String> {
+    let len = i32::try_from(data.len()).map_err(|_| "input is over 2 GiB".to_string())?;
+    let alloc = self.instance
+      .get_typed_func::<i32, i32>(&self.store, "wp_alloc")
+      .map_err(|e| format!("wp_alloc: {}", e))?;
+    let ptr = alloc.call(&mut self.store, len).map_err(|e| format!("wp_alloc: {}", e))?;
+    self.memory()?
+      .write(&mut self.store, ptr as u32 as usize, data)
+      .map_err(|e| e.to_string())?;
+    Ok((ptr, len))
+  }
+
+  /// Copy out the `(ptr << 32) | len` region a module returned, once it's known to be in its memory
+  fn read(&self, packed: i64) -> std::result::Result<Vec<u8>, String> {
+    let packed = packed.unsigned_abs();
+    let (ptr, len) = ((packed >> 32) as usize, (packed & 0xffff_ffff) as usize);
+    let memory = self.memory()?;
+    let size = memory.data(&self.store).len();
+    if ptr.checked_add(len).is_none_or(|end| end > size) {
+      return Err(format!("returned {} bytes at {}, past the end of its {} byte memory", len, ptr, size));
+    }
+    let mut data = vec![0; len];
+    memory.read(&self.store, ptr, &mut data).map_err(|e| e.to_string())?;
+    Ok(data)
+  }
+
+  fn memory(&self) -> std::result::Result<wasmi::Memory, String> {
+    self.instance
+      .get_memory(&self.store, "memory")
+      .ok_or_else(|| "doesn't export its memory".to_string())
+  }
+
+  fn info(&mut self) -> std::result::Result<Vec<u8>, String> {
+    let info = self.instance
+      .get_typed_func::<(), i64>(&self.store, "wp_info")
+      .map_err(|e| format!("wp_info: {}", e))?;
+    let packed = info.call(&mut self.store, ()).map_err(|e| format!("wp_info: {}", e))?;
+    self.read(packed)
+  }
+
+  /// Run `function` on `input`, the outer error is the sandbox's, the inner one the plugin's
+  fn run(&mut self, function: &str, input: &[u8], params: &str) -> std::result::Result<std::result::Result<Vec<u8>, String>, String> {
+    let (ptr, len) = self.write(input)?;
+    let (params_ptr, params_len) = self.write(params.as_bytes())?;
+    let function = self.instance
+      .get_typed_func::<(i32, i32, i32, i32), i64>(&self.store, function)
+      .map_err(|e| format!("{}: {}", function, e))?;
+    let packed = function
+      .call(&mut self.store, (ptr, len, params_ptr, params_len))
+      .map_err(|e| e.to_string())?;
+    let data = self.read(packed)?;
+    Ok(if packed < 0 { Err(String::from_utf8_lossy(&data).into_owned()) } else { Ok(data) })
+  }
+}
+
+impl WasmTransform {
+  fn call(&self, function: &str, input: &[u8]) -> Result<Vec<u8>> {
+    let fuel = FUEL_BASE.saturating_add(FUEL_PER_BYTE.saturating_mul(input.len() as u64));
+    let mut sandbox = Sandbox::new(&self.engine, &self.module, fuel).map_err(|e| error(&self.path, e))?;
+    sandbox.run(function, input, &self.params)
+      .map_err(|e| error(&self.path, e))?
+      .map_err(|e| PipelineError::Plugin(format!("{}: {}", self.info.id, e).into()))
+  }
+}
+
+impl Transform for WasmTransform {
+  fn encode(&self, input: Vec<u8>) -> Result<Vec<u8>> {
+    self.call("wp_encode", &input)
+  }
+
+  fn decode(&self, input: Vec<u8>) -> Result<Vec<u8>> {
+    self.call("wp_decode", &input)
+  }
+
+  fn name(&self) -> &str {
+    &self.info.name
+  }
+
+  fn extension(&self) -> &str {
+    &self.info.extension
+  }
+
+  fn input_kind(&self) -> Kind {
+    self.info.input
+  }
+
+  fn output_kind(&self) -> Kind {
+    self.info.output
+  }
+}
+
+/// Compile the module at `path` and register its transform
+pub fn load(registry: &mut Registry, path: &Path) -> Result<()> {
+  let mut config = Config::default();
+  config.consume_fuel(true);
+  let engine = Engine::new(&config);
+  let module = Arc::new(Module::new(&engine, &std::fs::read(path)?).map_err(|e| error(path, e))?);
+  let info = Sandbox::new(&engine, &module, FUEL_BASE)
+    .and_then(|mut sandbox| sandbox.info())
+    .map_err(|e| error(path, e))?;
+  let info: TransformInfo = serde_json::from_slice(&info).map_err(|e| error(path, e))?;
+  let path = path.to_path_buf();
+
+  registry.register(info.clone(), Source::Wasm(path.clone()), Arc::new(move |options| {
+    Ok(Box::new(WasmTransform {
+      info: info.clone(),
+      path: path.clone(),
+      params: plugin_params(&info, options)?,
+      engine: engine.clone(),
+      module: module.clone(),
+    }))
+  }))
+}
@@ -0,0 +1,248 @@
+//! Every transform a pipeline can be built from, the built-in ones and any loaded from plugins,
+//! each describing itself
+use crate::cli::TransformOptions;
+use crate::error::{PipelineError, Result};
+use crate::pipeline::Pipeline;
+use crate::pipeline_builder::{BUILTINS, Builtin, DEFAULT_PIPELINE};
+use crate::transform::{Kind, Transform};
+use clap::Args;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// A setting of a transform. Built-in transforms take theirs as `--<name>` flags,
+/// plugins through `--plugin-param <id>.<name>=<value>`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Parameter {
+  pub name: String,
+  /// Placeholder for the value in help text, e.g. `BYTES`
+  #[serde(default)]
+  pub value_name: Option<String>,
+  #[serde(default)]
+  pub description: String,
+  /// Used when the parameter isn't given. Without one the parameter is required
+  #[serde(default)]
+  pub default: Option<String>,
+}
+
+/// How a transform describes itself, plugins hand this over as JSON
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransformInfo {
+  /// Name in `--pipeline`
+  pub id: String,
+  /// Other names `--pipeline` accepts
+  #[serde(default)]
+  pub aliases: Vec<String>,
+  /// Name in progress output and intermediate file names
+  pub name: String,
+  #[serde(default = "default_extension")]
+  pub extension: String,
+  #[serde(default)]
+  pub description: String,
+  /// What the transform encodes
+  #[serde(default)]
+  pub input: Kind,
+  /// What it encodes that into
+  #[serde(default)]
+  pub output: Kind,
+  #[serde(default)]
+  pub parameters: Vec<Parameter>,
+  /// Command lines showing the transform off
+  #[serde(default)]
+  pub examples: Vec<String>,
+}
+
+fn default_extension() -> String {
+  "bin".to_string()
+}
+
+/// Where a transform comes from
+#[derive(Debug, Clone)]
+pub enum Source {
+  Builtin,
+  Native(PathBuf),
+  Wasm(PathBuf),
+}
+
+pub type Factory = Arc<dyn Fn(&TransformOptions) -> Result<Box<dyn Transform>> + Send + Sync>;
+
+pub struct Entry {
+  pub info: TransformInfo,
+  pub source: Source,
+  factory: Factory,
+}
+
+pub struct Registry {
+  entries: Vec<Entry>,
+}
+
+impl Default for Registry {
+  fn default() -> Self {
+    Self::builtin()
+  }
+}
+
+impl Registry {
+  /// Just the transforms that ship with wackypixels
+  pub fn builtin() -> Self {
+    let entries = BUILTINS.iter().map(|builtin| {
+      let build = builtin.build;
+      Entry {
+        info: builtin_info(builtin),
+        source: Source::Builtin,
+        factory: Arc::new(move |options| Ok(build(options))),
+      }
+    }).collect();
+    Self { entries }
+  }
+
+  /// The built-in transforms plus one plugin from every path
+  pub fn with_plugins(paths: &[PathBuf]) -> Result<Self> {
+    let mut registry = Self::builtin();
+    for path in paths {
+      registry.load(path)?;
+    }
+    Ok(registry)
+  }
+
+  pub fn register(&mut self, info: TransformInfo, source: Source, factory: Factory) -> Result<()> {
+    for name in std::iter::once(&info.id).chain(&info.aliases) {
+      if let Some(existing) = self.get(name) {
+        return Err(PipelineError::Plugin(format!(
+          "`{}` from {:?} is already taken by {:?}",
+          name, source, existing.source
+        ).into()));
+      }
+    }
+    self.entries.push(Entry { info, source, factory });
+    Ok(())
+  }
+
+  /// Load a plugin, a `.wasm` module or otherwise a shared library
+  pub fn load(&mut self, path: &Path) -> Result<()> {
+    if path.extension().is_some_and(|ext| ext == "wasm") {
+      #[cfg(feature = "wasm-plugins")]
+      return crate::plugins::wasm::load(self, path);
+      #[cfg(not(feature = "wasm-plugins"))]
+      return Err(PipelineError::Plugin(format!(
+        "Can't load {}, wackypixels was built without the `wasm-plugins` feature",
+        path.display()
+      ).into()));
+    }
+    #[cfg(feature = "native-plugins")]
+    return crate::plugins::native::load(self, path);
+    #[cfg(not(feature = "native-plugins"))]
+    Err(PipelineError::Plugin(format!(
+      "Can't load {}, wackypixels was built without the `native-plugins` feature",
+      path.display()
+    ).into()))
+  }
+
+  pub fn entries(&self) -> &[Entry] {
+    &self.entries
+  }
+
+  /// The transform called `name` or one of its aliases
+  pub fn get(&self, name: &str) -> Option<&Entry> {
+    self.entries.iter().find(|entry| entry.info.id == name || entry.info.aliases.iter().any(|alias| alias == name))
+  }
+
+  pub fn build(&self, id: &str, options: &TransformOptions) -> Result<Box<dyn Transform>> {
+    let entry = self.get(id).ok_or_else(|| {
+      let known: Vec<&str> = self.entries.iter().map(|entry| entry.info.id.as_str()).collect();
+      PipelineError::Usage(format!("Unknown transform `{}`, expected one of: {}", id, known.join(", ")).into())
+    })?;
+    (entry.factory)(options)
+  }
+
+  /// Transforms for `ids` in order, the default pipeline when there are none
+  pub fn transforms(&self, ids: Option<&[String]>, options: &TransformOptions) -> Result<Vec<Box<dyn Transform>>> {
+    match ids {
+      Some(ids) => ids.iter().map(|id| self.build(id, options)).collect(),
+      None => DEFAULT_PIPELINE.iter().map(|id| self.build(id, options)).collect(),
+    }
+  }
+
+  pub fn pipeline(&self, ids: Option<&[String]>, options: &TransformOptions) -> Result<Pipeline> {
+    let transforms = self.transforms(ids, options)?;
+    Ok(transforms.into_iter().fold(Pipeline::new(), Pipeline::add_boxed))
+  }
+}
+
+/// The `--pipeline` names of the default pipeline
+pub fn default_ids() -> Vec<String> {
+  DEFAULT_PIPELINE.iter().map(|id| id.to_string()).collect()
+}
+
+/// Describe a built-in transform, with the help text of its flags as the parameters
+fn builtin_info(builtin: &Builtin) -> TransformInfo {
+  let transform = (builtin.build)(&TransformOptions::default());
+  let command = TransformOptions::augment_args(clap::Command::new("options"));
+  let parameters = builtin.flags.iter().map(|flag| {
+    // tests/registry.rs checks every listed flag exists
+    let arg = command.get_arguments()
+      .find(|arg| arg.get_long() == Some(flag))
+      .unwrap_or_else(|| panic!("`{}` lists --{}, which TransformOptions doesn't have", builtin.id, flag));
+    Parameter {
+      name: flag.to_string(),
+      value_name: Some(arg)
+        .filter(|arg| arg.get_action().takes_values())
+        .and_then(|arg| {
+          let choices: Vec<_> = arg.get_possible_values().iter().map(|value| value.get_name().to_string()).collect();
+          if choices.is_empty() {
+            arg.get_value_names()?.first().map(|name| name.to_string())
+          } else {
+            Some(choices.join("|"))
+          }
+        }),
+      description: arg.get_help().map(|help| help.to_string()).unwrap_or_default(),
+      default: arg.get_default_values().first().map(|value| value.to_string_lossy().into_owned()),
+    }
+  }).collect();
+  TransformInfo {
+    id: builtin.id.to_string(),
+    aliases: builtin.aliases.iter().map(|alias| alias.to_string()).collect(),
+    name: transform.name().to_string(),
+    extension: transform.extension().to_string(),
+    description: builtin.description.to_string(),
+    input: transform.input_kind(),
+    output: transform.output_kind(),
+    parameters,
+    examples: builtin.examples.iter().map(|example| example.to_string()).collect(),
+  }
+}
+
+/// A plugin's `--plugin-param`s as the JSON object it gets handed, with defaults filled in
+pub fn plugin_params(info: &TransformInfo, options: &TransformOptions) -> Result<String> {
+  let mut params = serde_json::Map::new();
+  let prefix = format!("{}.", info.id);
+  for param in &options.plugin_params {
+    let Some((key, value)) = param.split_once('=') else {
+      return Err(PipelineError::Plugin(format!("`{}` should look like <id>.<name>=<value>", param).into()));
+    };
+    let Some(name) = key.strip_prefix(&prefix) else {
+      continue;
+    };
+    if !info.parameters.iter().any(|p| p.name == name) {
+      return Err(PipelineError::Plugin(format!("`{}` has no parameter `{}`", info.id, name).into()));
+    }
+    params.insert(name.to_string(), value.into());
+  }
+  for parameter in &info.parameters {
+    if params.contains_key(&parameter.name) {
+      continue;
+    }
+    match &parameter.default {
+      Some(default) => {
+        params.insert(parameter.name.clone(), default.as_str().into());
+      }
+      None => {
+        return Err(PipelineError::Plugin(format!(
+          "`{}` needs --plugin-param {}.{}=<value>",
+          info.id, info.id, parameter.name
+        ).into()));
+      }
+    }
+  }
+  Ok(serde_json::Value::Object(params).to_string())
+}
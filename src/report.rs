@@ -0,0 +1,414 @@
+//! Everything the pipeline has to say goes through a `Reporter`, so scripts can ask
+//! for JSON (or silence) instead of scraping the human-oriented progress output
+use clap::ValueEnum;
+use crate::lint::Finding;
+use crate::error::{Offset, PipelineError, StageError};
+use crate::runs::{self, Cleanup};
+use crate::verify::{Comparison, Verification};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ReportFormat {
+  /// Human readable progress on stderr
+  #[default]
+  Text,
+  /// One JSON record per line, on stdout (stderr when stdout carries data)
+  Json,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+  Quiet,
+  #[default]
+  Normal,
+  Verbose,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+  Encode,
+  Decode,
+}
+
+/// One finished pipeline stage
+#[derive(Debug, Clone, Serialize)]
+pub struct StageRecord {
+  pub direction: Direction,
+  pub step: usize,
+  pub total: usize,
+  pub name: String,
+  pub input_bytes: usize,
+  pub output_bytes: usize,
+  /// output_bytes / input_bytes
+  pub ratio: f64,
+  pub duration_ms: f64,
+  pub intermediate: Option<String>,
+  /// Transform specific numbers, e.g. the WAV sample count
+  #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+  pub stats: BTreeMap<&'static str, u64>,
+}
+
+/// A whole encode or decode run
+#[derive(Debug, Clone, Serialize)]
+pub struct SummaryRecord {
+  pub direction: Direction,
+  pub input: String,
+  pub output: String,
+  /// Stages actually run
+  pub stages: usize,
+  /// Stages an intermediate input had already been through
+  pub skipped: usize,
+  pub input_bytes: usize,
+  pub output_bytes: usize,
+  /// output_bytes / input_bytes
+  pub ratio: f64,
+  pub duration_ms: f64,
+}
+
+/// One file of a batch run
+#[derive(Debug, Clone, Serialize)]
+pub struct FileRecord {
+  pub input: String,
+  pub output: Option<String>,
+  pub input_bytes: u64,
+  pub output_bytes: u64,
+  /// output_bytes / input_bytes
+  pub ratio: f64,
+  pub duration_ms: f64,
+  pub error: Option<String>,
+  /// Exit code of the error, see `PipelineError::exit_code`
+  pub code: Option<i32>,
+}
+
+impl FileRecord {
+  pub fn new(input: &Path, result: crate::error::Result<PathBuf>, duration: Duration) -> Self {
+    let size = |path: &Path| fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+    let input_bytes = size(input);
+    let (output, output_bytes, error, code) = match result {
+      Ok(path) => (Some(path.display().to_string()), size(&path), None, None),
+      Err(e) => (None, 0, Some(e.to_string()), Some(e.exit_code())),
+    };
+    Self {
+      input: input.display().to_string(),
+      output,
+      input_bytes,
+      output_bytes,
+      ratio: ratio(output_bytes as usize, input_bytes as usize),
+      duration_ms: millis(duration),
+      error,
+      code,
+    }
+  }
+}
+
+/// What `clean` removed, or with `dry_run` would have
+#[derive(Debug, Clone, Serialize)]
+pub struct CleanRecord {
+  pub dry_run: bool,
+  pub removed: Vec<String>,
+  /// Newest marked directories spared by `--keep-last`
+  pub kept: Vec<String>,
+  /// Existing directories without a marker, which were left alone
+  pub unmarked: Vec<String>,
+  pub missing: Vec<String>,
+}
+
+impl CleanRecord {
+  pub fn new(cleanup: &Cleanup, removed: &[PathBuf], dry_run: bool) -> Self {
+    let paths = |dirs: &[PathBuf]| dirs.iter().map(|dir| dir.display().to_string()).collect();
+    Self {
+      dry_run,
+      removed: paths(removed),
+      kept: paths(&cleanup.keep),
+      unmarked: paths(&cleanup.unmarked),
+      missing: paths(&cleanup.missing),
+    }
+  }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+enum Record<'a> {
+  Stage(&'a StageRecord),
+  Summary(&'a SummaryRecord),
+  File(&'a FileRecord),
+  Lint(&'a Finding),
+  Verify(&'a Verification),
+  Clean(&'a CleanRecord),
+  Batch {
+    files: usize,
+    failed: usize,
+    duration_ms: f64,
+  },
+  Error {
+    direction: Direction,
+    step: usize,
+    name: &'a str,
+    /// Exit code of the error, see `PipelineError::exit_code`
+    code: i32,
+    offset: Option<Offset>,
+    message: String,
+  },
+}
+
+/// Routes progress output according to `--quiet`, `--verbose` and `--format`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Reporter {
+  format: ReportFormat,
+  verbosity: Verbosity,
+  stdout_busy: bool,
+}
+
+pub fn ratio(output: usize, input: usize) -> f64 {
+  if input == 0 { 0.0 } else { output as f64 / input as f64 }
+}
+
+pub fn millis(duration: Duration) -> f64 {
+  duration.as_secs_f64() * 1000.0
+}
+
+impl Reporter {
+  pub fn new(format: ReportFormat, verbosity: Verbosity) -> Self {
+    Self { format, verbosity, stdout_busy: false }
+  }
+
+  /// Reporter that stays silent apart from errors, e.g. for the pipelines inside a batch
+  pub fn quiet() -> Self {
+    Self::new(ReportFormat::Text, Verbosity::Quiet)
+  }
+
+  /// Set when the pipeline writes its data to stdout, so JSON moves to stderr
+  pub fn stdout_busy(mut self, busy: bool) -> Self {
+    self.stdout_busy = busy;
+    self
+  }
+
+  fn text(&self) -> bool {
+    self.format == ReportFormat::Text && self.verbosity > Verbosity::Quiet
+  }
+
+  fn verbose(&self) -> bool {
+    self.text() && self.verbosity == Verbosity::Verbose
+  }
+
+  fn json(&self, record: &Record) {
+    if self.format != ReportFormat::Json {
+      return;
+    }
+    let line = serde_json::to_string(record).expect("report records always serialize");
+    if self.stdout_busy {
+      eprintln!("{}", line);
+    } else {
+      let mut stdout = io::stdout().lock();
+      let _ = writeln!(stdout, "{}", line);
+      let _ = stdout.flush();
+    }
+  }
+
+  /// Free-form progress line, only shown in text mode
+  pub fn line(&self, message: impl AsRef<str>) {
+    if self.text() {
+      eprintln!("{}", message.as_ref());
+    }
+  }
+
+  /// Like `line`, but only with `--verbose`
+  pub fn detail(&self, message: impl AsRef<str>) {
+    if self.verbose() {
+      eprintln!("{}", message.as_ref());
+    }
+  }
+
+  pub fn pipeline(&self, steps: &[(&str, &str)]) {
+    self.line(format!("Pipeline ({} steps):", steps.len()));
+    for (i, (name, extension)) in steps.iter().enumerate() {
+      self.line(format!("  {}. {} -> .{}\n", i + 1, name, extension));
+    }
+  }
+
+  /// A problem `lint` found with the pipeline
+  pub fn finding(&self, finding: &Finding) {
+    self.line(format!("  ! {}\n", finding));
+    self.json(&Record::Lint(finding));
+  }
+  
+  pub fn input(&self, path: &Path, bytes: usize) {
+    self.line(format!("  Input: {} ({} bytes)\n", path.display(), bytes));
+  }
+
+  pub fn stage_start(&self, direction: Direction, step: usize, total: usize, name: &str) {
+    if !self.text() {
+      return;
+    }
+    let verb = match direction {
+      Direction::Encode => "Applying",
+      Direction::Decode => "Reversing",
+    };
+    eprintln!("[{}/{}] {}: {} ...", step, total, verb, name);
+    let _ = io::stderr().flush();
+  }
+
+  pub fn stage(&self, record: &StageRecord) {
+    self.line(format!("  ({} bytes, {:.1}%", record.output_bytes, record.ratio * 100.0));
+    if let Some(path) = &record.intermediate {
+      self.line(format!("  Saved: {}", path));
+    }
+    for (key, value) in &record.stats {
+      self.detail(format!("  {}: {}", key, value));
+    }
+    self.detail(format!("  Took: {:.1} ms", record.duration_ms));
+    self.line(format!("  Output size: {} bytes", record.output_bytes));
+    self.json(&Record::Stage(record));
+  }
+
+  /// The error that ends the run, unless `stage_failed` already showed it
+  pub fn fatal(&self, error: &PipelineError) {
+    if error.stage().is_some() && self.text() {
+      return;
+    }
+    eprintln!("  Error: {}", error);
+  }
+
+  pub fn stage_failed(&self, stage: &StageError) {
+    let StageError { direction, step, total, .. } = *stage;
+    let name = stage.transform.as_str();
+    match direction {
+      Direction::Encode => self.line(format!("X Failed at step {}/{}: {}", step, total, name)),
+      Direction::Decode => self.line(format!("X Failed at decode step {}/{}: {}", step, total, name)),
+    }
+    let offset = stage.error.offset();
+    match offset {
+      Some(offset) => self.line(format!("  Error at {}: {}", offset, stage.error)),
+      None => self.line(format!("  Error: {}", stage.error)),
+    }
+    if direction == Direction::Decode {
+      self.line("  This could be a number of things:");
+      self.line("    - Corrupted data at this stage");
+      self.line("    - Wrong pipeline order");
+      self.line("    - Missing transformation step");
+    }
+    self.json(&Record::Error {
+      direction,
+      step,
+      name,
+      code: stage.error.exit_code(),
+      offset,
+      message: stage.error.to_string(),
+    });
+  }
+
+  pub fn summary(&self, record: &SummaryRecord) {
+    let verb = match record.direction {
+      Direction::Encode => "Encryption",
+      Direction::Decode => "Decryption",
+    };
+    self.line(format!("\n {} complete: {}", verb, record.output));
+    self.detail(format!(
+      "  {} -> {} bytes ({:.1}%) in {:.1} ms",
+      record.input_bytes, record.output_bytes, record.ratio * 100.0, record.duration_ms,
+    ));
+    self.json(&Record::Summary(record));
+  }
+
+  pub fn file(&self, record: &FileRecord) {
+    match &record.error {
+      None => self.line(format!("  ok {} -> {}", record.input, record.output.as_deref().unwrap_or_default())),
+      Some(error) => self.line(format!("  X  {}: {}", record.input, error)),
+    }
+    self.json(&Record::File(record));
+  }
+
+  /// Whether a round trip gave back the input
+  pub fn verification(&self, verification: &Verification) {
+    let status = if verification.matches { "ok" } else { "X " };
+    self.line(format!("\n{} {} vs {}", status, verification.original, verification.decoded));
+    match &verification.comparison {
+      Comparison::Pixels { width, height, differing_pixels, max_difference, psnr_db } => {
+        self.line(format!("  {}x{} pixels, {} differ (by up to {})", width, height, differing_pixels, max_difference));
+        match psnr_db {
+          Some(psnr) => self.line(format!("  PSNR: {:.2} dB", psnr)),
+          None => self.line("  PSNR: infinite, the pixels are identical"),
+        }
+      }
+      Comparison::Dimensions { original, decoded } => self.line(format!(
+        "  Size differs: {}x{} vs {}x{}",
+        original[0], original[1], decoded[0], decoded[1],
+      )),
+      Comparison::Bytes { original_bytes, decoded_bytes, original_sha256, decoded_sha256 } => {
+        self.line(format!("  Original: {} bytes, SHA-256 {}", original_bytes, original_sha256));
+        self.line(format!("  Decoded:  {} bytes, SHA-256 {}", decoded_bytes, decoded_sha256));
+      }
+    }
+    self.json(&Record::Verify(verification));
+  }
+
+  /// What `clean` found and is about to delete
+  pub fn cleanup(&self, cleanup: &Cleanup, dry_run: bool) {
+    for dir in &cleanup.missing {
+      self.line(format!("X {} (doesn't exist)", dir.display()));
+    }
+    for dir in &cleanup.unmarked {
+      self.line(format!("X {} (no {} marker, leaving it alone)", dir.display(), runs::MARKER));
+    }
+    for dir in &cleanup.keep {
+      self.line(format!("  Keeping {}/", dir.display()));
+    }
+    if cleanup.remove.is_empty() {
+      self.line("Nothing to clean.");
+      return;
+    }
+    self.line(if dry_run { "Would delete:" } else { "About to delete:" });
+    for dir in &cleanup.remove {
+      self.line(format!("  - {}/", dir.display()));
+    }
+  }
+
+  pub fn removed(&self, dir: &Path) {
+    self.line(format!("  Removed {}/", dir.display()));
+  }
+
+  /// The end of a `clean`
+  pub fn cleaned(&self, record: &CleanRecord) {
+    if !record.dry_run && !record.removed.is_empty() {
+      self.line("Cleaned!");
+    }
+    self.json(&Record::Clean(record));
+  }
+
+  /// Final table of a batch run
+  pub fn batch(&self, records: &[FileRecord], duration_ms: f64) {
+    let failed = records.iter().filter(|record| record.error.is_some()).count();
+    let width = records.iter()
+      .map(|record| record.input.chars().count())
+      .max()
+      .unwrap_or(0)
+      .max(4);
+    self.line(format!(
+      "\n  {:width$}  {:6}  {:>10}  {:>10}  {:>8}  {:>9}",
+      "File", "Status", "Input", "Output", "Ratio", "Time",
+    ));
+    for record in records {
+      match &record.error {
+        None => self.line(format!(
+          "  {:width$}  {:6}  {:>10}  {:>10}  {:>7.1}%  {:>6.0} ms",
+          record.input, "ok", record.input_bytes, record.output_bytes, record.ratio * 100.0, record.duration_ms,
+        )),
+        Some(error) => self.line(format!(
+          "  {:width$}  {:6}  {}",
+          record.input, "FAILED", error,
+        )),
+      }
+    }
+    self.line(format!(
+      "\n  {} ok, {} failed in {:.0} ms",
+      records.len() - failed, failed, duration_ms,
+    ));
+    self.json(&Record::Batch { files: records.len(), failed, duration_ms });
+  }
+}
@@ -0,0 +1,124 @@
+//! Where outputs go and what `clean` may delete. Directories wackypixels creates get a
+//! `.wackypixels` marker file, and only marked directories are ever removed. `run` gives
+//! every round trip a folder of its own, named after the time and the input's hash
+use crate::error::Result;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Marker file in every directory wackypixels created
+pub const MARKER: &str = ".wackypixels";
+
+/// Where `run` puts its run folders unless told otherwise
+pub const RUNS_DIR: &str = "runs";
+
+pub fn is_marked(dir: &Path) -> bool {
+  dir.join(MARKER).is_file()
+}
+
+/// Create an output directory, marking it as ours if this call is what created it.
+/// Directories that were already there, even empty ones, are used but never marked
+pub fn create_output_dir(dir: &Path) -> Result<()> {
+  if let Some(parent) = dir.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+    fs::create_dir_all(parent)?;
+  }
+  match fs::create_dir(dir) {
+    Ok(()) => fs::write(dir.join(MARKER), "Created by wackypixels, `wackypixels clean` may delete this directory\n")?,
+    Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists && dir.is_dir() => {}
+    Err(e) => return Err(e.into()),
+  }
+  Ok(())
+}
+
+/// `YYYYMMDDTHHMMSSZ` for a Unix time, in UTC
+fn timestamp(secs: u64) -> String {
+  // Days to a civil date, see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+  let days = (secs / 86400) as i64 + 719468;
+  let era = days.div_euclid(146097);
+  let day_of_era = days.rem_euclid(146097);
+  let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+  let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+  let month_index = (5 * day_of_year + 2) / 153;
+  let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+  let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+  let year = year_of_era + era * 400 + i64::from(month <= 2);
+  let time = secs % 86400;
+  format!(
+    "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+    year, month, day, time / 3600, time / 60 % 60, time % 60,
+  )
+}
+
+/// Create a fresh, marked run folder under `root` for a run on `input`,
+/// e.g. `runs/20240612T093000Z-3f2a9c1d`
+pub fn create_run_dir(root: &Path, input: &[u8]) -> Result<PathBuf> {
+  let secs = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs());
+  let hash = format!("{:x}", Sha256::digest(input));
+  let name = format!("{}-{}", timestamp(secs), &hash[..8]);
+  fs::create_dir_all(root)?;
+  let mut dir = root.join(&name);
+  // Same input within the same second
+  let mut attempt = 1;
+  while dir.exists() {
+    attempt += 1;
+    dir = root.join(format!("{}-{}", name, attempt));
+  }
+  create_output_dir(&dir)?;
+  Ok(dir)
+}
+
+/// What `clean` would do with some directories
+#[derive(Debug, Default)]
+pub struct Cleanup {
+  pub remove: Vec<PathBuf>,
+  /// Newest marked directories spared by `--keep-last`
+  pub keep: Vec<PathBuf>,
+  /// Existing directories without a marker, which are left alone
+  pub unmarked: Vec<PathBuf>,
+  pub missing: Vec<PathBuf>,
+}
+
+/// Plan cleaning `dirs`. A marked directory goes as a whole, an unmarked one only loses its
+/// marked subdirectories (like the run folders in `runs/`). Of everything that would go,
+/// the `keep_last` newest by marker time stay
+pub fn plan_cleanup(dirs: &[PathBuf], keep_last: usize) -> Result<Cleanup> {
+  let mut cleanup = Cleanup::default();
+  let mut candidates = Vec::new();
+  for dir in dirs {
+    if !dir.is_dir() {
+      cleanup.missing.push(dir.clone());
+    } else if is_marked(dir) {
+      candidates.push(dir.clone());
+    } else {
+      let mut marked = Vec::new();
+      for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() && is_marked(&path) {
+          marked.push(path);
+        }
+      }
+      if marked.is_empty() {
+        cleanup.unmarked.push(dir.clone());
+      }
+      candidates.extend(marked);
+    }
+  }
+
+  // Newest first, by when the marker was written
+  let created = |dir: &PathBuf| fs::metadata(dir.join(MARKER)).and_then(|m| m.modified()).ok();
+  candidates.sort_by_key(|dir| std::cmp::Reverse((created(dir), dir.clone())));
+  let keep = keep_last.min(candidates.len());
+  cleanup.remove = candidates.split_off(keep);
+  cleanup.keep = candidates;
+  Ok(cleanup)
+}
+
+/// Remove a directory `plan_cleanup` picked, checking the marker once more first
+pub fn remove(dir: &Path) -> Result<()> {
+  if !is_marked(dir) {
+    return Err(std::io::Error::other(format!("{} has no {} marker, not removing it", dir.display(), MARKER)).into());
+  }
+  fs::remove_dir_all(dir)
+    .map_err(|e| std::io::Error::new(e.kind(), format!("Failed to remove {}: {}", dir.display(), e)).into())
+}
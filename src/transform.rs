@@ -1,18 +1,143 @@
-use std::fmt::Debug;
-use crate::error::{Result};
+use std::fmt::{self, Debug};
+use crate::error::{Detail, Result};
+use rand::SeedableRng;
+use rand::rngs::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
+
+/// What kind of data a transform takes or makes, so impossible pipelines can be caught before they run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+  /// An encoded image file, e.g. a PNG
+  Image,
+  /// Anything at all
+  #[default]
+  Bytes,
+  /// Output of a compressor, which won't shrink any further
+  Compressed,
+  /// UTF-8 text
+  Text,
+  Wav,
+  Pdf,
+}
+
+impl Kind {
+  /// Whether a transform taking `self` can make sense of `given`
+  pub fn accepts(self, given: Kind) -> bool {
+    self == Kind::Bytes || self == given
+  }
+}
+
+impl fmt::Display for Kind {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str(match self {
+      Kind::Image => "an image",
+      Kind::Bytes => "bytes",
+      Kind::Compressed => "compressed bytes",
+      Kind::Text => "UTF-8 text",
+      Kind::Wav => "a WAV file",
+      Kind::Pdf => "a PDF",
+    })
+  }
+}
+
+/// Fail on output of a newer format than this build writes. Every version up to `current`
+/// stays readable, version 0 being output from before formats carried a version
+pub fn check_format(found: u32, current: u32) -> std::result::Result<(), Detail> {
+  if found > current {
+    return Err(format!(
+      "Format version {} is from a newer wackypixels, this one reads up to {}",
+      found, current
+    ).into());
+  }
+  Ok(())
+}
+
+/// Source of anything random in a transform's output. The same `seed` gives the same numbers
+/// on every platform and build, without one they come from the system
+pub fn rng(seed: Option<u64>) -> ChaCha8Rng {
+  match seed {
+    Some(seed) => ChaCha8Rng::seed_from_u64(seed),
+    None => rand::make_rng(),
+  }
+}
+
+/// Output of decoding data that may have been cut off
+#[derive(Debug)]
+pub struct Partial {
+  pub data: Vec<u8>,
+  /// False when the input ended before the transform expected it to
+  pub complete: bool,
+}
+
+/// Predicted output size of a stage
+#[derive(Debug, Clone, Copy)]
+pub struct Estimate {
+  pub bytes: u64,
+  /// "exact", "analytic" or "sampled"
+  pub model: &'static str,
+}
+
+/// Encode the sample and an empty input to fit `overhead + ratio * size`
+pub fn sampled_estimate<T: Transform + ?Sized>(transform: &T, input_bytes: u64, sample: &[u8]) -> Result<Estimate> {
+  let overhead = transform.encode(vec![]).map(|out| out.len() as f64).unwrap_or(0.0);
+  let sampled = transform.encode(sample.to_vec())?.len() as f64;
+  let ratio = if sample.is_empty() { 0.0 } else { (sampled - overhead).max(0.0) / sample.len() as f64 };
+  Ok(Estimate {
+    bytes: (overhead + ratio * input_bytes as f64).round() as u64,
+    model: "sampled",
+  })
+}
 
 /// A reversible transformation step in the wacky encoding pipeline
-pub trait Transform: Debug {
+pub trait Transform: Debug + Send + Sync {
   /// Apply the transformation (encode direction)
   fn encode(&self, data: Vec<u8>) -> Result<Vec<u8>>;
   
   /// Reverse the transformation (decode direction)
   fn decode(&self, data: Vec<u8>) -> Result<Vec<u8>>;
   
+  /// Decode as much of a possibly truncated input as possible.
+  /// Transforms that can't make sense of half their input just decode it in full
+  fn decode_partial(&self, data: Vec<u8>) -> Result<Partial> {
+    Ok(Partial { data: self.decode(data)?, complete: true })
+  }
+  
   fn name(&self) -> &str;
   
   /// File extension for intermediate outputs
   fn extension(&self) -> &str {
     "bin"
   }
+  
+  /// What `encode` expects
+  fn input_kind(&self) -> Kind {
+    Kind::Bytes
+  }
+  
+  /// What `encode` produces
+  fn output_kind(&self) -> Kind {
+    Kind::Bytes
+  }
+  
+  /// Describe `data` if it looks like this transform's encoded output
+  fn sniff(&self, _data: &[u8]) -> Option<String> {
+    None
+  }
+  
+  /// Predict the encoded size of `input_bytes` bytes, of which `sample` is a representative part.
+  /// By default that's `sampled_estimate`
+  fn estimate(&self, input_bytes: u64, sample: &[u8]) -> Result<Estimate> {
+    sampled_estimate(self, input_bytes, sample)
+  }
+  
+  /// Largest input the carrier can hold, for stages that have a limit
+  fn capacity(&self) -> Option<u64> {
+    None
+  }
+  
+  /// Extra numbers about encoded data for verbose and JSON reports
+  fn stats(&self, _encoded: &[u8]) -> Vec<(&'static str, u64)> {
+    vec![]
+  }
 }
\ No newline at end of file
@@ -1,36 +1,80 @@
-use flate2::write::{GzEncoder, GzDecoder};
-use flate2::Compression;
+use flate2::read;
+use flate2::{Compression, GzBuilder};
 use image::EncodableLayout;
-use std::io::Write;
-use crate::transform::Transform;
-use crate::error::{PipelineError, Result};
+use std::io::{Read, Write};
+use crate::transform::{Kind, Partial, Transform, check_format};
+use crate::error::{Detail, PipelineError, Result};
+
+/// Version of the format `encode` writes, kept in a `WP` subfield of the gzip extra field
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Subfield id of the format version in the gzip header's extra field
+const VERSION_SUBFIELD: [u8; 2] = *b"WP";
+
+/// Format version from a gzip header's extra field, 0 for output from before there was one
+fn format_version(extra: Option<&[u8]>) -> u32 {
+  let mut extra = extra.unwrap_or_default();
+  // Subfields are a 2 byte id, a 2 byte little endian length and the data
+  while let [a, b, lo, hi, rest @ ..] = extra {
+    let len = u16::from_le_bytes([*lo, *hi]) as usize;
+    let Some(data) = rest.get(..len) else {
+      break;
+    };
+    if [*a, *b] == VERSION_SUBFIELD && let [version] = data {
+      return *version as u32;
+    }
+    extra = &rest[len..];
+  }
+  0
+}
 
 #[derive(Debug)]
 pub struct GzipTransform;
 
 impl Transform for GzipTransform {
   fn encode(&self, data: Vec<u8>) -> Result<Vec<u8>> {
-    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    let mut extra = VERSION_SUBFIELD.to_vec();
+    extra.extend_from_slice(&1u16.to_le_bytes());
+    extra.push(FORMAT_VERSION as u8);
+    let mut encoder = GzBuilder::new().extra(extra).write(Vec::new(), Compression::default());
     encoder
       .write_all(&data)
-      .map_err(|e| PipelineError::Flate(e.to_string()))?;
+      .map_err(|e| PipelineError::Flate(Detail::wrap(e)))?;
     Ok(
       encoder
         .finish()
-        .map_err(|e| PipelineError::Flate(e.to_string()))?
+        .map_err(|e| PipelineError::Flate(Detail::wrap(e)))?
         .as_bytes()
         .to_vec()
     )
   }
   
   fn decode(&self, data: Vec<u8>) -> Result<Vec<u8>> { 
-    let mut decoder = GzDecoder::new(Vec::new());
-    decoder
-        .write_all(&data)
-        .map_err(|e| PipelineError::Flate(e.to_string()))?;
+    let mut decoder = read::GzDecoder::new(&data[..]);
+    let mut out = Vec::new();
     decoder
-        .finish()
-        .map_err(|e| PipelineError::Flate(e.to_string()))
+        .read_to_end(&mut out)
+        .map_err(|e| PipelineError::Flate(Detail::wrap(e)))?;
+    let version = format_version(decoder.header().and_then(|header| header.extra()));
+    check_format(version, FORMAT_VERSION).map_err(PipelineError::Flate)?;
+    Ok(out)
+  }
+  
+  fn decode_partial(&self, data: Vec<u8>) -> Result<Partial> {
+    // read_to_end keeps whatever was inflated before the input ran out
+    let mut decoder = read::GzDecoder::new(&data[..]);
+    let mut out = Vec::new();
+    let complete = decoder.read_to_end(&mut out).is_ok();
+    let version = format_version(decoder.header().and_then(|header| header.extra()));
+    check_format(version, FORMAT_VERSION).map_err(PipelineError::Flate)?;
+    if out.is_empty() && !complete {
+      return Err(PipelineError::Flate("Not enough data to decompress anything".into()));
+    }
+    Ok(Partial { data: out, complete })
+  }
+  
+  fn sniff(&self, data: &[u8]) -> Option<String> {
+    data.starts_with(&[0x1F, 0x8B]).then(|| "gzip stream".to_string())
   }
   
   fn name(&self) -> &str {
@@ -40,4 +84,8 @@ impl Transform for GzipTransform {
   fn extension(&self) -> &str {
     "gz"
   }
+
+  fn output_kind(&self) -> Kind {
+    Kind::Compressed
+  }
 }
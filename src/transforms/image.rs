@@ -1,9 +1,101 @@
 //! ImageTransform serializes/deserializes png data
-use crate::error::{PipelineError, Result};
-use crate::transform::Transform;
+use crate::error::{Detail, PipelineError, Result};
+use crate::transform::{Estimate, Kind, Partial, Transform, check_format};
 use image::{GenericImageView, ImageBuffer, ImageFormat, ImageReader, Rgba};
 use std::io::Cursor;
 
+/// Start of serialized image data, followed by the format version
+const MAGIC: &[u8; 4] = b"WPIM";
+
+/// Version of the format `encode` writes: magic, version, then width, height and channel
+/// format ahead of the pixels. Version 0 was the same without magic and version
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Channel format byte of RGBA pixels, the only one there is
+const RGBA: u8 = 4;
+
+struct Header {
+  width: u32,
+  height: u32,
+  format: u8,
+  /// Where the pixels start
+  len: usize,
+}
+
+fn header(data: &[u8]) -> Result<Header> {
+  let (version, rest) = match data.strip_prefix(MAGIC) {
+    Some([version, rest @ ..]) => (*version as u32, rest),
+    _ => (0, data),
+  };
+  check_format(version, FORMAT_VERSION).map_err(PipelineError::Image)?;
+  let [w0, w1, w2, w3, h0, h1, h2, h3, format, ..] = *rest else {
+    return Err(PipelineError::Image("Data too short".into()));
+  };
+  Ok(Header {
+    width: u32::from_le_bytes([w0, w1, w2, w3]),
+    height: u32::from_le_bytes([h0, h1, h2, h3]),
+    format,
+    len: data.len() - rest.len() + 9,
+  })
+}
+
+/// Width and height of serialized image data `total_len` bytes long, from a `data` sample
+/// that can be cut off after the header
+pub fn dimensions(data: &[u8], total_len: u64) -> Result<(u32, u32)> {
+  let Header { width, height, format, len } = header(data)?;
+  if format != RGBA {
+    return Err(PipelineError::Image("Unsupported format, only RGBA supported".into()));
+  }
+  let expected_len = (width as u64 * height as u64)
+    .checked_mul(4)
+    .and_then(|bytes| bytes.checked_add(len as u64));
+  if expected_len != Some(total_len) {
+    return Err(PipelineError::Image("Pixel data length mismatch".into()));
+  }
+  Ok((width, height))
+}
+
+/// Split serialized image data into (width, height, rgba_pixels)
+pub fn parse_raw(data: &[u8]) -> Result<(u32, u32, &[u8])> {
+  let Header { width, height, format, len } = header(data)?;
+
+  if format != RGBA {
+    return Err(PipelineError::Image("Unsupported format, only RGBA supported".into()));
+  }
+
+  let expected_len = (width as usize)
+    .checked_mul(height as usize)
+    .and_then(|pixels| pixels.checked_mul(4))
+    .and_then(|bytes| bytes.checked_add(len));
+  if expected_len != Some(data.len()) {
+    return Err(PipelineError::Image("Pixel data length mismatch".into()));
+  }
+
+  Ok((width, height, &data[len..]))
+}
+
+/// A truncated image gets padded out to at most this many times the pixel bytes that arrived,
+/// its size comes from a header that can't be trusted
+const PARTIAL_CANVAS_FACTOR: usize = 16;
+
+fn to_png(width: u32, height: u32, pixels: Vec<u8>) -> Result<Vec<u8>> {
+  let img: ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::from_raw(width, height, pixels)
+    .ok_or_else(|| {
+      PipelineError::Image(format!(
+        "Failed to construct {}x{} image buffer",
+        width, height
+      ).into())
+    })?;
+
+  let mut png_bytes: Vec<u8> = Vec::new();
+
+  img
+    .write_to(&mut Cursor::new(&mut png_bytes), ImageFormat::Png)
+    .map_err(|e| PipelineError::Image(Detail::wrap(e)))?;
+
+  Ok(png_bytes)
+}
+
 #[derive(Debug)]
 pub struct ImageTransform;
 
@@ -11,58 +103,70 @@ impl Transform for ImageTransform {
   fn encode(&self, data: Vec<u8>) -> Result<Vec<u8>> {
     let img = ImageReader::new(Cursor::new(&data))
         .with_guessed_format()
-        .map_err(|e| PipelineError::Image(e.to_string()))?
+        .map_err(|e| PipelineError::Image(Detail::wrap(e)))?
         .decode()
-        .map_err(|e| PipelineError::Image(e.to_string()))?;
+        .map_err(|e| PipelineError::Image(Detail::wrap(e)))?;
     let (width, height) = img.dimensions();
     let pixels = img.to_rgba8().into_raw();
     let mut out = Vec::new();
+    out.extend_from_slice(MAGIC);
+    out.push(FORMAT_VERSION as u8);
     out.extend_from_slice(&width.to_le_bytes());
     out.extend_from_slice(&height.to_le_bytes());
-    out.push(4); // RGBA
+    out.push(RGBA);
     out.extend_from_slice(&pixels);
     Ok(out)
   }
 
   fn decode(&self, data: Vec<u8>) -> Result<Vec<u8>> {
-    if data.len() < 9 {
-      return Err(PipelineError::Image("Data too short".to_string()));
-    }
-
-    let width = u32::from_le_bytes(data[0..4].try_into().unwrap());
-    let height = u32::from_le_bytes(data[4..8].try_into().unwrap());
-    let format = data[8];
+    let (width, height, pixels) = parse_raw(&data)?;
+    to_png(width, height, pixels.to_vec())
+  }
 
-    if format != 4 {
-      return Err(PipelineError::Image(
-        "Unsupported format, only RGBA supported".to_string(),
-      ));
+  fn decode_partial(&self, data: Vec<u8>) -> Result<Partial> {
+    if parse_raw(&data).is_ok() {
+      return Ok(Partial { data: self.decode(data)?, complete: true });
     }
-
-    let expected_len = 9 + (width * height * 4) as usize;
-    if data.len() != expected_len {
-      return Err(PipelineError::Image(
-        "Pixel data length mismatch".to_string(),
-      ));
+    let Header { width, height, format, len } = header(&data)?;
+    if format != RGBA {
+      return Err(PipelineError::Image("Unsupported format, only RGBA supported".into()));
+    }
+    let size = (width as usize)
+      .checked_mul(height as usize)
+      .and_then(|pixels| pixels.checked_mul(4))
+      .filter(|&size| size > data.len() - len)
+      .ok_or_else(|| PipelineError::Image("Pixel data length mismatch".into()))?;
+    let received = data.len() - len;
+    let budget = received.max(1).saturating_mul(PARTIAL_CANVAS_FACTOR);
+    let row = size / height as usize;
+    if row > budget {
+      return Err(PipelineError::Image(format!(
+        "{}x{} is too big to preview from {} bytes", width, height, received
+      ).into()));
     }
 
-    let pixels = data[9..].to_vec();
-
-    let img: ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::from_raw(width, height, pixels)
-      .ok_or_else(|| {
-        PipelineError::Image(format!(
-          "Failed to construct {}x{} image buffer",
-          width, height
-        ))
-      })?;
-
-    let mut png_bytes: Vec<u8> = Vec::new();
+    // Whole rows that arrived, everything below stays transparent
+    let rows = received / row;
+    let canvas_rows = (height as usize).min(budget / row);
+    let mut pixels = vec![0; canvas_rows * row];
+    pixels[..rows * row].copy_from_slice(&data[len..len + rows * row]);
+    Ok(Partial { data: to_png(width, canvas_rows as u32, pixels)?, complete: false })
+  }
 
-    img
-      .write_to(&mut Cursor::new(&mut png_bytes), ImageFormat::Png)
-      .map_err(|e| e.to_string())?;
+  fn estimate(&self, _input_bytes: u64, sample: &[u8]) -> Result<Estimate> {
+    // The sample of an image file is the whole file, its header has all we need
+    let (width, height) = ImageReader::new(Cursor::new(sample))
+      .with_guessed_format()
+      .map_err(|e| PipelineError::Image(Detail::wrap(e)))?
+      .into_dimensions()
+      .map_err(|e| PipelineError::Image(Detail::wrap(e)))?;
+    let header = MAGIC.len() as u64 + 10;
+    Ok(Estimate { bytes: header + width as u64 * height as u64 * 4, model: "exact" })
+  }
 
-    Ok(png_bytes)
+  fn sniff(&self, data: &[u8]) -> Option<String> {
+    let (width, height, _) = parse_raw(data).ok()?;
+    Some(format!("raw RGBA image, {}x{}", width, height))
   }
 
   fn name(&self) -> &str {
@@ -72,4 +176,8 @@ impl Transform for ImageTransform {
   fn extension(&self) -> &str {
     "bin"
   }
+
+  fn input_kind(&self) -> Kind {
+    Kind::Image
+  }
 }
@@ -1,22 +1,91 @@
+use xz2::stream::{Check, MtStreamBuilder};
+use xz2::read;
 use xz2::write::{XzEncoder, XzDecoder};
-use std::io::{Write};
-use crate::transform::Transform;
-use crate::error::{PipelineError, Result};
+use std::io::{Read, Write};
+use crate::transform::{Kind, Partial, Transform, check_format};
+use crate::error::{Detail, PipelineError, Result};
 
-#[derive(Debug)]
-pub struct LzmaTransform;
+const XZ_MAGIC: [u8; 6] = [0xFD, b'7', b'z', b'X', b'Z', 0x00];
+
+/// Magic in front of the xz stream, followed by the format version
+const MAGIC: &[u8; 4] = b"WPXZ";
+
+/// Version of the format `encode` writes: magic and version, then a standard .xz stream
+/// (`tail -c +6 file.xz | xz -d` reads it). Version 0 was the bare .xz stream
+pub const FORMAT_VERSION: u32 = 1;
+
+/// The xz stream behind our header, checking its version
+fn xz_stream(data: &[u8]) -> Result<&[u8]> {
+  let (version, stream) = match data.strip_prefix(MAGIC) {
+    Some([version, stream @ ..]) => (*version as u32, stream),
+    Some([]) => return Err(PipelineError::Lzma("Data too short".into())),
+    None => (0, data),
+  };
+  check_format(version, FORMAT_VERSION).map_err(PipelineError::Lzma)?;
+  Ok(stream)
+}
+
+#[derive(Debug, Default)]
+pub struct LzmaTransform {
+  /// Compress independent xz blocks of this many bytes on all cores
+  chunk_size: Option<usize>,
+}
+
+impl LzmaTransform {
+  pub fn new() -> Self {
+    Self::default()
+  }
+  
+  pub fn chunk_size(mut self, bytes: Option<usize>) -> Self {
+    self.chunk_size = bytes;
+    self
+  }
+  
+  fn encoder(&self) -> Result<XzEncoder<Vec<u8>>> {
+    let mut header = MAGIC.to_vec();
+    header.push(FORMAT_VERSION as u8);
+    let Some(chunk_size) = self.chunk_size else {
+      return Ok(XzEncoder::new(header, 9));
+    };
+    // Still a single standard .xz stream, just split into blocks
+    let threads = std::thread::available_parallelism().map(|n| n.get() as u32).unwrap_or(1);
+    let stream = MtStreamBuilder::new()
+      .threads(threads)
+      .block_size(chunk_size.max(1) as u64)
+      .preset(9)
+      .check(Check::Crc64)
+      .encoder()
+      .map_err(|e| PipelineError::Lzma(Detail::wrap(e)))?;
+    Ok(XzEncoder::new_stream(header, stream))
+  }
+}
 
 impl Transform for LzmaTransform {
     fn encode(&self, data: Vec<u8>) -> Result<Vec<u8>> {
-      let mut encoder = XzEncoder::new(Vec::new(), 9);
-      encoder.write_all(&data).map_err(|e| e.to_string())?;
-      encoder.finish().map_err(|e| PipelineError::Lzma(e.to_string()))
+      let mut encoder = self.encoder()?;
+      encoder.write_all(&data).map_err(|e| PipelineError::Lzma(Detail::wrap(e)))?;
+      encoder.finish().map_err(|e| PipelineError::Lzma(Detail::wrap(e)))
     }
     
     fn decode(&self, data: Vec<u8>) -> Result<Vec<u8>> {
       let mut decoder = XzDecoder::new(Vec::new());
-      decoder.write_all(&data).map_err(|e| e.to_string())?;
-      decoder.finish().map_err(|e| PipelineError::Lzma(e.to_string()))
+      decoder.write_all(xz_stream(&data)?).map_err(|e| PipelineError::Lzma(Detail::wrap(e)))?;
+      decoder.finish().map_err(|e| PipelineError::Lzma(Detail::wrap(e)))
+    }
+    
+    fn decode_partial(&self, data: Vec<u8>) -> Result<Partial> {
+      // read_to_end keeps whatever was decompressed before the input ran out
+      let mut out = Vec::new();
+      let complete = read::XzDecoder::new(xz_stream(&data)?).read_to_end(&mut out).is_ok();
+      if out.is_empty() && !complete {
+        return Err(PipelineError::Lzma("Not enough data to decompress anything".into()));
+      }
+      Ok(Partial { data: out, complete })
+    }
+    
+    fn sniff(&self, data: &[u8]) -> Option<String> {
+        let stream = xz_stream(data).ok()?;
+        stream.starts_with(&XZ_MAGIC).then(|| "xz stream".to_string())
     }
     
     fn name(&self) -> &str {
@@ -26,4 +95,8 @@ impl Transform for LzmaTransform {
     fn extension(&self) -> &str {
         "xz"
     }
+
+    fn output_kind(&self) -> Kind {
+      Kind::Compressed
+    }
 }
\ No newline at end of file
@@ -1,116 +1,1368 @@
 //! PDFTransform serializes/deserializes png data
-use crate::transform::Transform;
-use crate::error::{PipelineError, Result};
-use lopdf::{Document, Object, Stream, dictionary};use std::io::Cursor;
+use crate::transform::{Estimate, Kind, Partial, Transform, check_format, rng, sampled_estimate};
+use crate::transforms::image;
+use crate::error::{Detail, PipelineError, Result};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use clap::ValueEnum;
+use ::image::{RgbaImage, imageops};
+use lopdf::content::{Content, Operation};
+use lopdf::encryption::crypt_filters::{Aes128CryptFilter, Aes256CryptFilter, CryptFilter};
+use lopdf::{
+  Dictionary, Document, EncryptionState, EncryptionVersion, IncrementalDocument, Object, ObjectId,
+  Permissions, SaveOptions, Stream, StringFormat, dictionary,
+};
+use rand::Rng;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{Cursor, Read};
+use std::path::PathBuf;
+use std::sync::Arc;
 
-#[derive(Debug)]
-pub struct PdfTransform;
+/// Name of the attachment used by [`PdfStrategy::Attachment`]
+const ATTACHMENT_NAME: &str = "wackypixels.bin";
+/// Namespace of the `wp:Payload` element used by [`PdfStrategy::Xmp`]
+const XMP_NAMESPACE: &str = "https://github.com/PixelSaver/wackypixels/ns/1.0/";
+/// Prefix in front of the payload for strategies that have no length of their own
+const FRAME_MAGIC: &[u8; 4] = b"WPIX";
+/// Width of the disguised grayscale image
+const IMAGE_WIDTH: usize = 256;
+/// Payload bytes of invisible text per page
+const TEXT_BYTES_PER_PAGE: usize = 16 * 1024;
+/// Payload bytes per invisible `Tj` line
+const TEXT_BYTES_PER_LINE: usize = 64;
+/// Font resource name used by the invisible text
+const TEXT_FONT: &str = "WpF0";
+/// Deepest name tree level searched for the attachment, real trees are a few levels at most
+const NAME_TREE_DEPTH: usize = 32;
+/// Longest side of a thumbnail preview, in pixels
+const THUMBNAIL_SIZE: u32 = 256;
+/// Longest side of a thumbnail preview on the page, in points
+const THUMBNAIL_BOX: f64 = 400.0;
+/// Longest side of a full page preview, in points
+const FULL_PAGE_SIZE: f64 = 800.0;
 
-impl Transform for PdfTransform {
-  fn encode(&self, data: Vec<u8>) -> Result<Vec<u8>> {
-    let mut doc = Document::with_version("1.7");
+/// Where in the PDF the payload gets hidden
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum PdfStrategy {
+  /// Stream referenced from a custom `/WackyPixels` key in the `/Info` dictionary
+  #[default]
+  Info,
+  /// Standard `/EmbeddedFiles` attachment
+  Attachment,
+  /// Base64 element inside the document's XMP metadata packet
+  Xmp,
+  /// Grayscale image XObject drawn in a corner of the page
+  Image,
+  /// Invisible (render mode 3) text spread over as many pages as needed
+  Text,
+}
+
+impl PdfStrategy {
+  /// Decoding tries each strategy in this order
+  const ALL: [PdfStrategy; 5] = [
+    PdfStrategy::Info,
+    PdfStrategy::Attachment,
+    PdfStrategy::Xmp,
+    PdfStrategy::Image,
+    PdfStrategy::Text,
+  ];
+}
+
+/// How the generated page shows the image it carries
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum PdfPreview {
+  /// Just the caption
+  #[default]
+  None,
+  /// A small rendering of the image under the caption
+  Thumbnail,
+  /// The image at full resolution, filling the page
+  Full,
+}
+
+/// Standard security handler algorithm used to encrypt the document
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum PdfEncryption {
+  /// 128-bit RC4 (PDF 1.4)
+  Rc4,
+  /// 128-bit AES (PDF 1.6)
+  Aes128,
+  /// 256-bit AES (PDF 2.0)
+  Aes256,
+}
+
+/// Document information written to the `/Info` dictionary
+#[derive(Debug, Clone, Default)]
+pub struct PdfMetadata {
+  pub title: Option<String>,
+  pub author: Option<String>,
+  /// PDF date string, see [`parse_pdf_date`]
+  pub created: Option<String>,
+  /// PDF date string, see [`parse_pdf_date`]
+  pub modified: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct PdfTransform {
+  strategy: PdfStrategy,
+  /// Existing PDF to hide the payload in instead of a fresh "Hello, World!" page
+  cover: Option<PathBuf>,
+  preview: PdfPreview,
+  /// Visible text on the generated page, "Hello, World!" when unset
+  caption: Option<String>,
+  metadata: PdfMetadata,
+  /// Flate compress the hidden payload and page content streams
+  compress: bool,
+  encryption: Option<PdfEncryption>,
+  /// User password, needed to encrypt and to decode encrypted documents
+  password: Option<String>,
+  /// Pack objects into object streams (with a cross-reference stream)
+  object_streams: bool,
+  /// Derive the file identifier from this instead of the system's randomness
+  seed: Option<u64>,
+}
+
+impl PdfTransform {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn strategy(mut self, strategy: PdfStrategy) -> Self {
+    self.strategy = strategy;
+    self
+  }
+
+  pub fn cover(mut self, path: Option<PathBuf>) -> Self {
+    self.cover = path;
+    self
+  }
+
+  /// Only applies to the generated page, a cover document is never drawn on
+  pub fn preview(mut self, preview: PdfPreview) -> Self {
+    self.preview = preview;
+    self
+  }
+
+  /// Only applies to the generated page, a cover document is never drawn on
+  pub fn caption(mut self, caption: Option<String>) -> Self {
+    self.caption = caption;
+    self
+  }
+
+  pub fn metadata(mut self, metadata: PdfMetadata) -> Self {
+    self.metadata = metadata;
+    self
+  }
+
+  pub fn compress(mut self, enable: bool) -> Self {
+    self.compress = enable;
+    self
+  }
+
+  pub fn encryption(mut self, encryption: Option<PdfEncryption>) -> Self {
+    self.encryption = encryption;
+    self
+  }
+
+  pub fn password(mut self, password: Option<String>) -> Self {
+    self.password = password;
+    self
+  }
+
+  pub fn object_streams(mut self, enable: bool) -> Self {
+    self.object_streams = enable;
+    self
+  }
+
+  pub fn seed(mut self, seed: Option<u64>) -> Self {
+    self.seed = seed;
+    self
+  }
+
+  /// Catch option combinations that can't work before doing any work
+  fn check_options(&self) -> Result<()> {
+    if self.cover.is_some() && self.encryption.is_some() {
+      return Err(PipelineError::Pdf("Encryption can't be added to a cover PDF through an incremental update".into()));
+    }
+    if self.cover.is_some() && self.object_streams {
+      return Err(PipelineError::Pdf("Object streams can't be added to a cover PDF through an incremental update".into()));
+    }
+    if self.encryption.is_some() && self.object_streams {
+      return Err(PipelineError::Pdf("Object streams can't be combined with encryption".into()));
+    }
+    if self.encryption.is_some() && self.password.is_none() {
+      return Err(PipelineError::Password("Encrypting needs a password".into()));
+    }
+    if let Some(caption) = &self.caption {
+      win_ansi(caption)?;
+    }
+    if self.encryption.is_some() && self.seed.is_some() {
+      // lopdf pads the password hashes and draws AES IVs with randomness of its own
+      return Err(PipelineError::Usage("Encrypted PDFs can't be reproduced, drop --seed or --pdf-encrypt".into()));
+    }
+    Ok(())
+  }
+
+  /// A stream holding `len` bytes as it ends up in the file, with its `/Length`.
+  /// AES prepends an IV and pads to whole blocks, RC4 keeps the length
+  fn stream_len(&self, len: u64) -> u64 {
+    let len = match self.encryption {
+      Some(PdfEncryption::Aes128 | PdfEncryption::Aes256) => 16 + (len / 16 + 1) * 16,
+      _ => len,
+    };
+    len + digits(len)
+  }
+
+  /// Everything in the streams carrying an `n` byte payload (and in the dictionaries that say how
+  /// big it is) that grows with `n`. Text pages past the first are left to `estimate`
+  fn carrier_len(&self, n: u64) -> u64 {
+    match self.strategy {
+      PdfStrategy::Info => self.stream_len(n),
+      PdfStrategy::Attachment => self.stream_len(n) + digits(n),
+      PdfStrategy::Xmp => self.stream_len(n.div_ceil(3) * 4),
+      PdfStrategy::Image => {
+        let rows = (n + 8).div_ceil(IMAGE_WIDTH as u64);
+        self.stream_len(rows * IMAGE_WIDTH as u64) + digits(rows)
+      }
+      PdfStrategy::Text => {
+        let framed = n + 8;
+        let page = TEXT_BYTES_PER_PAGE as u64;
+        let (full, rest) = (framed / page, framed % page);
+        full * self.stream_len(text_content_len(page)) + if rest > 0 { self.stream_len(text_content_len(rest)) } else { 0 }
+      }
+    }
+  }
+
+  /// Bytes the preview adds to the generated page for an `input_bytes` long payload. Its pixels
+  /// are compressed, so that's only known when `sample` is the whole image, otherwise they're
+  /// counted uncompressed
+  fn preview_len(&self, input_bytes: u64, sample: &[u8]) -> Result<u64> {
+    if self.cover.is_some() || self.preview == PdfPreview::None {
+      return Ok(0);
+    }
+    if sample.len() as u64 != input_bytes || image::parse_raw(sample).is_err() {
+      let Ok((width, height)) = image::dimensions(sample, input_bytes) else {
+        return Ok(0);
+      };
+      let (width, height) = preview_size(self.preview, width, height);
+      return Ok(width as u64 * height as u64 * 3);
+    }
+    let caption = self.caption.as_deref().unwrap_or("Hello, World!");
+    let saved_len = |preview: PdfPreview| -> Result<u64> {
+      let (mut doc, _) = base_document(preview, caption, sample)?;
+      let mut bytes = Vec::new();
+      doc.save_to(&mut bytes).map_err(pdf_err)?;
+      Ok(bytes.len() as u64)
+    };
+    Ok(saved_len(self.preview)?.saturating_sub(saved_len(PdfPreview::None)?))
+  }
+
+  /// Load the document, decrypting it with the password if there is one
+  fn load(&self, data: &[u8]) -> Result<Document> {
+    let doc = match &self.password {
+      Some(password) => Document::load_mem_with_password(data, password),
+      None => Document::load_mem(data),
+    }.map_err(load_err)?;
+    if doc.is_encrypted() {
+      return Err(PipelineError::Password("PDF is encrypted, pass its password with --pdf-password".into()));
+    }
+    Ok(doc)
+  }
+
+  /// Load the document and try every strategy until one yields a payload
+  fn find_payload(&self, data: &[u8]) -> Result<(PdfStrategy, Vec<u8>)> {
+    let doc = self.load(data)?;
+    check_format(format_version(&doc), FORMAT_VERSION).map_err(PipelineError::Pdf)?;
+
+    extract_any(&doc)
+  }
+
+}
+
+/// Try every strategy until one yields a payload, whichever was used on encode
+fn extract_any(doc: &Document) -> Result<(PdfStrategy, Vec<u8>)> {
+  let mut failures = Vec::new();
+  for strategy in PdfStrategy::ALL {
+    let extracted = match strategy {
+      PdfStrategy::Info => extract_info(doc),
+      PdfStrategy::Attachment => extract_attachment(doc),
+      PdfStrategy::Xmp => extract_xmp(doc),
+      PdfStrategy::Image => extract_image(doc),
+      PdfStrategy::Text => extract_text(doc),
+    };
+    match extracted {
+      Ok(payload) => return Ok((strategy, payload)),
+      Err(e) => failures.push(format!("{:?}: {}", strategy, e)),
+    }
+  }
+
+  Err(PipelineError::Pdf(format!(
+    "No hidden payload found ({})",
+    failures.join("; ")
+  ).into()))
+}
+
+/// Parse `YYYY-MM-DD`, `YYYY-MM-DDTHH:MM:SS` (optionally ending in `Z`) or an
+/// existing `D:...` string into a PDF date string
+pub fn parse_pdf_date(input: &str) -> std::result::Result<String, String> {
+  if input.starts_with("D:") {
+    return Ok(input.to_string());
+  }
+
+  let invalid = || format!("Invalid date '{}', expected YYYY-MM-DD or YYYY-MM-DDTHH:MM:SS", input);
+  let (date, time) = input.trim_end_matches('Z').split_once('T').unwrap_or((input, "00:00:00"));
+  let date: Vec<&str> = date.split('-').collect();
+  let time: Vec<&str> = time.split(':').collect();
+  let widths = [4, 2, 2, 2, 2, 2];
+  let fields: Vec<&str> = date.into_iter().chain(time).collect();
+  if fields.len() != widths.len()
+    || fields.iter().zip(widths).any(|(f, w)| f.len() != w || !f.bytes().all(|b| b.is_ascii_digit()))
+  {
+    return Err(invalid());
+  }
+
+  Ok(format!("D:{}Z", fields.concat()))
+}
+
+/// PDF text string, UTF-16BE with a byte order mark when it isn't plain ASCII
+fn text_string(text: &str) -> Object {
+  if text.is_ascii() {
+    return Object::string_literal(text);
+  }
+  let mut bytes = vec![0xFE, 0xFF];
+  bytes.extend(text.encode_utf16().flat_map(u16::to_be_bytes));
+  Object::String(bytes, StringFormat::Hexadecimal)
+}
+
+fn pdf_err<E: std::error::Error + Send + Sync + 'static>(e: E) -> PipelineError {
+  PipelineError::Pdf(Detail::wrap(e))
+}
+
+/// Like `pdf_err`, but tells a wrong password apart from a broken PDF
+fn load_err(e: lopdf::Error) -> PipelineError {
+  match e {
+    lopdf::Error::InvalidPassword
+    | lopdf::Error::Decryption(lopdf::encryption::DecryptionError::IncorrectPassword) => {
+      PipelineError::Password(Detail::wrap(e))
+    }
+    e => pdf_err(e),
+  }
+}
+
+/// Characters of `n` written out in decimal, as in a `/Length`
+fn digits(n: u64) -> u64 {
+  n.checked_ilog10().map_or(1, |log| log as u64 + 1)
+}
+
+/// Prefix the payload with a magic and its length so it can be cut back out of padded carriers
+fn frame(data: &[u8]) -> Vec<u8> {
+  let mut framed = Vec::with_capacity(data.len() + 8);
+  framed.extend_from_slice(FRAME_MAGIC);
+  framed.extend_from_slice(&(data.len() as u32).to_le_bytes());
+  framed.extend_from_slice(data);
+  framed
+}
+
+fn unframe(framed: &[u8]) -> Result<Vec<u8>> {
+  if framed.len() < 8 || &framed[..4] != FRAME_MAGIC {
+    return Err(PipelineError::Pdf("Missing WackyPixels frame".into()));
+  }
+  let len = u32::from_le_bytes([framed[4], framed[5], framed[6], framed[7]]) as usize;
+  framed.get(8..8 + len)
+    .map(|payload| payload.to_vec())
+    .ok_or_else(|| PipelineError::Pdf("Framed payload is truncated".into()))
+}
+
+/// Stream content with any filters undone
+fn stream_bytes(stream: &Stream) -> Result<Vec<u8>> {
+  stream.get_plain_content().map_err(pdf_err)
+}
+
+/// Follow a chain of references to the object they point to
+fn resolve<'a>(doc: &'a Document, object: &'a Object) -> Result<&'a Object> {
+  doc.dereference(object).map(|(_, object)| object).map_err(pdf_err)
+}
+
+/// Pixel size of the preview of a `width` x `height` image
+fn preview_size(preview: PdfPreview, width: u32, height: u32) -> (u32, u32) {
+  if preview != PdfPreview::Thumbnail || width.max(height) <= THUMBNAIL_SIZE {
+    return (width, height);
+  }
+  let scale = THUMBNAIL_SIZE as f64 / width.max(height) as f64;
+  (((width as f64 * scale).round() as u32).max(1), ((height as f64 * scale).round() as u32).max(1))
+}
+
+/// Image XObject (plus alpha soft mask) for the preview, returns its id and pixel size
+fn preview_image(doc: &mut Document, preview: PdfPreview, data: &[u8]) -> Result<Option<(ObjectId, u32, u32)>> {
+  if preview == PdfPreview::None {
+    return Ok(None);
+  }
+  // Only serialized images can be drawn, anything else just gets the caption
+  let Ok((width, height, pixels)) = image::parse_raw(data) else {
+    return Ok(None);
+  };
+  let Some(mut img) = RgbaImage::from_raw(width, height, pixels.to_vec()) else {
+    return Ok(None);
+  };
+  let (thumb_w, thumb_h) = preview_size(preview, width, height);
+  if (thumb_w, thumb_h) != (width, height) {
+    img = imageops::thumbnail(&img, thumb_w, thumb_h);
+  }
+
+  let (width, height) = img.dimensions();
+  let rgb: Vec<u8> = img.pixels().flat_map(|p| [p[0], p[1], p[2]]).collect();
+  let alpha: Vec<u8> = img.pixels().map(|p| p[3]).collect();
+
+  let mut dict = dictionary! {
+    "Type" => "XObject",
+    "Subtype" => "Image",
+    "Width" => width as i64,
+    "Height" => height as i64,
+    "ColorSpace" => "DeviceRGB",
+    "BitsPerComponent" => 8,
+  };
+  if alpha.iter().any(|&a| a != 255) {
+    let mut mask = Stream::new(dictionary! {
+      "Type" => "XObject",
+      "Subtype" => "Image",
+      "Width" => width as i64,
+      "Height" => height as i64,
+      "ColorSpace" => "DeviceGray",
+      "BitsPerComponent" => 8,
+    }, alpha);
+    mask.compress().map_err(pdf_err)?;
+    dict.set("SMask", doc.add_object(mask));
+  }
+
+  let mut stream = Stream::new(dict, rgb);
+  stream.compress().map_err(pdf_err)?;
+  Ok(Some((doc.add_object(stream), width, height)))
+}
+
+/// A one page document showing the caption and optionally a preview of the
+/// image, returns the document and its page
+fn base_document(preview: PdfPreview, caption: &str, data: &[u8]) -> Result<(Document, ObjectId)> {
+  let mut doc = Document::with_version("1.7");
+
+  let image = preview_image(&mut doc, preview, data)?;
+  // (media box, image drawing operators, caption font size and position)
+  let (media_box, draw_image, (size, x, y)) = match (preview, image) {
+    (PdfPreview::Full, Some((_, width, height))) => {
+      // Image fills the page, with a strip for the caption underneath
+      let scale = FULL_PAGE_SIZE / width.max(height) as f64;
+      let (w, h) = (width as f64 * scale, height as f64 * scale);
+      let strip = if caption.is_empty() { 0.0 } else { 48.0 };
+      (
+        [0.0, 0.0, w, h + strip],
+        format!("q {:.2} 0 0 {:.2} 0 {} cm /Im1 Do Q ", w, h, strip),
+        (18, 18.0, 18.0),
+      )
+    }
+    (_, Some((_, width, height))) => {
+      // Thumbnail sits under the caption
+      let scale = THUMBNAIL_BOX / width.max(height) as f64;
+      let (w, h) = (width as f64 * scale, height as f64 * scale);
+      (
+        [0.0, 0.0, 600.0, 800.0],
+        format!("q {:.2} 0 0 {:.2} 100 {:.2} cm /Im1 Do Q ", w, h, 670.0 - h),
+        (24, 100.0, 700.0),
+      )
+    }
+    _ => ([0.0, 0.0, 600.0, 800.0], String::new(), (24, 100.0, 700.0)),
+  };
+
+  // Adding the pdf's visible text
+  let text_stream_content = format!(
+    "{}BT /F1 {} Tf {} {} Td ({}) Tj ET",
+    draw_image, size, x, y, escape_literal(&win_ansi(caption)?)
+  );
+  let text_stream = Stream::new(dictionary! {}, text_stream_content.as_bytes().to_vec());
+  let text_stream_id = doc.add_object(text_stream);
+
+  let mut resources = dictionary! {
+    "Font" => dictionary! {
+      "F1" => dictionary! {
+        "Type" => "Font",
+        "Subtype" => "Type1",
+        "BaseFont" => "Helvetica",
+        "Encoding" => "WinAnsiEncoding",
+      }
+    }
+  };
+  if let Some((image_id, _, _)) = image {
+    resources.set("XObject", dictionary! { "Im1" => image_id });
+  }
+
+  // PDF Page DIctionary
+  let obj_id = doc.new_object_id();
+  let page_id = doc.add_object(dictionary! {
+    "Type" => "Page",
+    "Parent" => obj_id,
+    "Resources" => resources,
+    "Contents" => text_stream_id,
+    "MediaBox" => media_box.iter().map(|&v| Object::Real(v as f32)).collect::<Vec<_>>(),
+  });
+
+  // Creating /Pages dict
+  let pages_id = doc.add_object(dictionary! {
+    "Type" => "Pages",
+    "Kids" => vec![page_id.into()],
+    "Count" => 1,
+  });
+
+  // update page's parent
+  doc.get_dictionary_mut(page_id).map_err(pdf_err)?.set("Parent", pages_id);
+
+  // Setup /Root catalog
+  let catalog_id = doc.add_object(dictionary! {
+    "Type" => "Catalog",
+    "Pages" => pages_id,
+  });
+  doc.trailer.set(b"Root", catalog_id);
+
+  Ok((doc, page_id))
+}
+
+/// Escape WinAnsi bytes for use inside a `( )` literal in a content stream, anything
+/// outside printable ASCII as an octal escape
+fn escape_literal(bytes: &[u8]) -> String {
+  bytes.iter().map(|&b| match b {
+    b'\\' | b'(' | b')' => format!("\\{}", b as char),
+    b' '..=b'~' => (b as char).to_string(),
+    _ => format!("\\{:03o}", b),
+  }).collect()
+}
+
+/// Characters of Windows-1252 that differ from Latin-1, and their bytes
+const WIN_ANSI_EXTRAS: [(char, u8); 27] = [
+  ('€', 0x80), ('‚', 0x82), ('ƒ', 0x83), ('„', 0x84), ('…', 0x85), ('†', 0x86), ('‡', 0x87),
+  ('ˆ', 0x88), ('‰', 0x89), ('Š', 0x8A), ('‹', 0x8B), ('Œ', 0x8C), ('Ž', 0x8E), ('‘', 0x91),
+  ('’', 0x92), ('“', 0x93), ('”', 0x94), ('•', 0x95), ('–', 0x96), ('—', 0x97), ('˜', 0x98),
+  ('™', 0x99), ('š', 0x9A), ('›', 0x9B), ('œ', 0x9C), ('ž', 0x9E), ('Ÿ', 0x9F),
+];
+
+/// The caption in WinAnsiEncoding, the widest encoding the standard Helvetica font has.
+/// Anything else would need an embedded font, so it's refused
+fn win_ansi(caption: &str) -> Result<Vec<u8>> {
+  caption.chars().map(|c| match c as u32 {
+    0x20..=0x7E | 0xA0..=0xFF => Ok(c as u8),
+    _ => WIN_ANSI_EXTRAS.iter()
+      .find(|&&(extra, _)| extra == c)
+      .map(|&(_, byte)| byte)
+      .ok_or_else(|| PipelineError::Usage(format!(
+        "The caption can't show {:?}, the PDF's standard font only covers Western European text",
+        c
+      ).into())),
+  }).collect()
+}
+
+/// Append a content stream to a page, keeping whatever it already draws. The
+/// existing content gets wrapped in `q`/`Q` so its graphics state can't leak into ours
+fn append_page_content(doc: &mut Document, page_id: ObjectId, content: Vec<u8>) -> Result<()> {
+  let page = doc.get_dictionary(page_id).map_err(pdf_err)?;
+  let existing = match page.get(b"Contents") {
+    Ok(Object::Array(existing)) => existing.clone(),
+    Ok(existing) => vec![existing.clone()],
+    Err(_) => vec![],
+  };
+
+  let mut contents = Vec::with_capacity(existing.len() + 2);
+  if !existing.is_empty() {
+    contents.push(doc.add_object(Stream::new(dictionary! {}, b"q".to_vec())).into());
+    contents.extend(existing);
+    let mut restored = b"Q\n".to_vec();
+    restored.extend(content);
+    contents.push(doc.add_object(Stream::new(dictionary! {}, restored)).into());
+  } else {
+    contents.push(doc.add_object(Stream::new(dictionary! {}, content)).into());
+  }
+
+  doc.get_dictionary_mut(page_id).map_err(pdf_err)?.set("Contents", contents);
+  Ok(())
+}
+
+/// Make `owner[key]` an indirect dictionary and return its id, so it can be
+/// edited in place. Inline dictionaries get moved out, missing ones created
+fn indirect_dict(doc: &mut Document, owner_id: ObjectId, key: &[u8]) -> Result<ObjectId> {
+  let owner = doc.get_dictionary(owner_id).map_err(pdf_err)?;
+  let dict = match owner.get(key) {
+    Ok(Object::Reference(id)) => return Ok(*id),
+    Ok(Object::Dictionary(dict)) => dict.clone(),
+    Ok(_) => return Err(PipelineError::Pdf(format!(
+      "/{} is not a dictionary", String::from_utf8_lossy(key)
+    ).into())),
+    Err(_) => Dictionary::new(),
+  };
+
+  let id = doc.add_object(dict);
+  doc.get_dictionary_mut(owner_id).map_err(pdf_err)?.set(key, id);
+  Ok(id)
+}
+
+/// Mutable access to one of the page's resource sub-dictionaries (`/Font`, `/XObject`, ...).
+/// Resources inherited from a parent `/Pages` node get copied onto the page first
+fn page_resource<'a>(doc: &'a mut Document, page_id: ObjectId, kind: &[u8]) -> Result<&'a mut Dictionary> {
+  if !doc.get_dictionary(page_id).map_err(pdf_err)?.has(b"Resources") {
+    let mut inherited = None;
+    let mut node = doc.get_dictionary(page_id).map_err(pdf_err)?;
+    while let Ok(parent) = node.get(b"Parent").and_then(Object::as_reference) {
+      node = doc.get_dictionary(parent).map_err(pdf_err)?;
+      if let Ok(resources) = node.get(b"Resources") {
+        inherited = Some(resources.clone());
+        break;
+      }
+    }
+    let resources = inherited.unwrap_or_else(|| Dictionary::new().into());
+    doc.get_dictionary_mut(page_id).map_err(pdf_err)?.set("Resources", resources);
+  }
+
+  let resources_id = indirect_dict(doc, page_id, b"Resources")?;
+  let kind_id = indirect_dict(doc, resources_id, kind)?;
+  doc.get_dictionary_mut(kind_id).map_err(pdf_err)
+}
+
+/// Version of the format `encode` writes, kept in the catalog under `VERSION_KEY`.
+/// Version 0 had no such entry
+pub const FORMAT_VERSION: u32 = 1;
+const VERSION_KEY: &str = "WackyPixelsVersion";
+
+fn mark_version(doc: &mut Document) -> Result<()> {
+  let catalog_id = catalog_id(doc)?;
+  doc.get_dictionary_mut(catalog_id).map_err(pdf_err)?.set(VERSION_KEY, FORMAT_VERSION as i64);
+  Ok(())
+}
+
+fn format_version(doc: &Document) -> u32 {
+  doc.catalog().ok()
+    .and_then(|catalog| catalog.get(VERSION_KEY.as_bytes()).ok())
+    .and_then(|version| version.as_i64().ok())
+    .map_or(0, |version| version.clamp(0, u32::MAX as i64) as u32)
+}
+
+/// `format_version` for a PDF that won't load, read straight from the catalog's bytes
+/// wherever they are, loose or in an object stream
+fn scanned_version(data: &[u8]) -> u32 {
+  let key = format!("/{}", VERSION_KEY);
+  let version = |bytes: &[u8]| {
+    let rest = bytes[find(bytes, key.as_bytes())? + key.len()..].trim_ascii_start();
+    let digits = rest.iter().take_while(|b| b.is_ascii_digit()).count();
+    let version: u64 = std::str::from_utf8(&rest[..digits]).ok()?.parse().ok()?;
+    Some(version.min(u32::MAX as u64) as u32)
+  };
+  version(data)
+    .or_else(|| raw_streams(data).iter().find_map(|(_, content)| version(content)))
+    .unwrap_or(0)
+}
+
+fn catalog_id(doc: &Document) -> Result<ObjectId> {
+  doc.trailer.get(b"Root")
+    .and_then(Object::as_reference)
+    .map_err(pdf_err)
+}
+
+/// Load the cover document, returning it with the page that gets the visible changes
+fn load_cover(bytes: &[u8]) -> Result<(Document, ObjectId)> {
+  let doc = Document::load_mem(bytes).map_err(pdf_err)?;
+  if doc.is_encrypted() || doc.was_encrypted() {
+    return Err(PipelineError::Pdf("Encrypted cover PDFs aren't supported".into()));
+  }
+  let page_id = doc.page_iter().next()
+    .ok_or_else(|| PipelineError::Pdf("Cover PDF has no pages".into()))?;
+  Ok((doc, page_id))
+}
+
+/// Append every object `modified` added or changed compared to `original` as an
+/// incremental update, so the original bytes (and any signatures over them) stay intact
+fn save_incremental(original_bytes: Vec<u8>, original: Document, modified: Document) -> Result<Vec<u8>> {
+  let mut update = IncrementalDocument::create_from(original_bytes, original);
+
+  for (id, object) in modified.objects {
+    if update.get_prev_documents().get_object(id).ok() != Some(&object) {
+      update.new_document.objects.insert(id, object);
+    }
+  }
+  update.new_document.max_id = modified.max_id;
+  if let Ok(info) = modified.trailer.get(b"Info") {
+    update.new_document.trailer.set("Info", info.clone());
+  }
+  update.new_document.trailer.remove(b"XRefStm");
+
+  let mut pdf_bytes = Vec::new();
+  update.save_to(&mut pdf_bytes).map_err(pdf_err)?;
+  Ok(pdf_bytes)
+}
+
+/// The document's `/Info` dictionary, created if it doesn't have one yet
+fn info_id(doc: &mut Document) -> ObjectId {
+  match doc.trailer.get(b"Info").and_then(Object::as_reference) {
+    Ok(info_id) => info_id,
+    Err(_) => {
+      let info_id = doc.add_object(dictionary! {});
+      doc.trailer.set("Info", info_id);
+      info_id
+    }
+  }
+}
+
+fn apply_metadata(doc: &mut Document, metadata: &PdfMetadata) -> Result<()> {
+  let fields = [
+    ("Title", metadata.title.as_ref().map(|t| text_string(t))),
+    ("Author", metadata.author.as_ref().map(|a| text_string(a))),
+    ("CreationDate", metadata.created.as_ref().map(|d| Object::string_literal(d.as_str()))),
+    ("ModDate", metadata.modified.as_ref().map(|d| Object::string_literal(d.as_str()))),
+  ];
+  if fields.iter().all(|(_, value)| value.is_none()) {
+    return Ok(());
+  }
+
+  let info_id = info_id(doc);
+  let info = doc.get_dictionary_mut(info_id).map_err(pdf_err)?;
+  for (key, value) in fields {
+    if let Some(value) = value {
+      info.set(key, value);
+    }
+  }
+  Ok(())
+}
+
+/// Flate compress every stream that isn't part of the original cover document
+fn compress_streams(doc: &mut Document, original: Option<&Document>) -> Result<()> {
+  for (id, object) in doc.objects.iter_mut() {
+    if original.is_some_and(|original| original.get_object(*id).ok() == Some(object)) {
+      continue;
+    }
+    if let Object::Stream(stream) = object {
+      stream.compress().map_err(pdf_err)?;
+    }
+  }
+  Ok(())
+}
+
+/// Give a fresh document its file identifier, which also feeds into the RC4 and
+/// AES-128 key derivation
+fn set_id(doc: &mut Document, rng: &mut impl Rng) {
+  let mut id = [0u8; 16];
+  rng.fill_bytes(&mut id);
+  let id = Object::String(id.to_vec(), StringFormat::Hexadecimal);
+  doc.trailer.set("ID", vec![id.clone(), id]);
+}
+
+/// Encrypt the document with the standard security handler, using the user
+/// password as the owner password too
+fn encrypt(doc: &mut Document, encryption: PdfEncryption, password: &str, rng: &mut impl Rng) -> Result<()> {
+  let permissions = Permissions::all();
+  let mut file_key = [0u8; 32];
+  let version = match encryption {
+    PdfEncryption::Rc4 => EncryptionVersion::V2 {
+      document: doc,
+      owner_password: password,
+      user_password: password,
+      key_length: 128,
+      permissions,
+    },
+    PdfEncryption::Aes128 => {
+      let filter: Arc<dyn CryptFilter> = Arc::new(Aes128CryptFilter);
+      EncryptionVersion::V4 {
+        document: doc,
+        encrypt_metadata: true,
+        crypt_filters: BTreeMap::from([(b"StdCF".to_vec(), filter)]),
+        stream_filter: b"StdCF".to_vec(),
+        string_filter: b"StdCF".to_vec(),
+        owner_password: password,
+        user_password: password,
+        permissions,
+      }
+    }
+    PdfEncryption::Aes256 => {
+      rng.fill_bytes(&mut file_key);
+      let filter: Arc<dyn CryptFilter> = Arc::new(Aes256CryptFilter);
+      EncryptionVersion::V5 {
+        encrypt_metadata: true,
+        crypt_filters: BTreeMap::from([(b"StdCF".to_vec(), filter)]),
+        file_encryption_key: &file_key,
+        stream_filter: b"StdCF".to_vec(),
+        string_filter: b"StdCF".to_vec(),
+        owner_password: password,
+        user_password: password,
+        permissions,
+      }
+    }
+  };
+
+  let state = EncryptionState::try_from(version).map_err(pdf_err)?;
+  doc.encrypt(&state).map_err(pdf_err)
+}
+
+fn embed_info(doc: &mut Document, data: Vec<u8>) -> Result<()> {
+  // Adding the hidden stream
+  let hidden_stream = Stream::new(
+    dictionary! {
+      "Type" => "XObject",
+      "Subtype" => "Metadata",
+    },
+    data,
+  );
+  let hidden_stream_id = doc.add_object(hidden_stream);
+
+  // Store reference in /Info dir, keeping any existing title, author, ...
+  let info_id = info_id(doc);
+  let info_dict = doc.get_dictionary_mut(info_id).map_err(pdf_err)?;
+  info_dict.set("WackyPixels", hidden_stream_id);
+  Ok(())
+}
+
+fn extract_info(doc: &Document) -> Result<Vec<u8>> {
+  // Find hidden stream using /Info dict
+  let info_dict = resolve(doc, doc.trailer.get(b"Info").map_err(pdf_err)?)?
+    .as_dict()
+    .map_err(pdf_err)?;
+  let hidden_object = resolve(doc, info_dict.get(b"WackyPixels").map_err(pdf_err)?)?;
+
+  // extract the hidden stream
+  let hidden_stream = match hidden_object {
+    Object::Stream(stream) => stream,
+    _ => return Err(PipelineError::Pdf("WackyPixels is not a stream".into())),
+  };
+  stream_bytes(hidden_stream)
+}
+
+fn embed_attachment(doc: &mut Document, data: Vec<u8>) -> Result<()> {
+  let size = data.len() as i64;
+  let file_id = doc.add_object(Stream::new(
+    dictionary! {
+      "Type" => "EmbeddedFile",
+      "Subtype" => "application/octet-stream",
+      "Params" => dictionary! { "Size" => size },
+    },
+    data,
+  ));
+  let filespec_id = doc.add_object(dictionary! {
+    "Type" => "Filespec",
+    "F" => Object::string_literal(ATTACHMENT_NAME),
+    "UF" => Object::string_literal(ATTACHMENT_NAME),
+    "EF" => dictionary! { "F" => file_id },
+  });
+
+  // Add to the existing name tree, which has to stay sorted by key
+  let names_id = indirect_dict(doc, catalog_id(doc)?, b"Names")?;
+  let files_id = indirect_dict(doc, names_id, b"EmbeddedFiles")?;
+  let files = doc.get_dictionary_mut(files_id).map_err(pdf_err)?;
+  if files.has(b"Kids") && !files.has(b"Names") {
+    return Err(PipelineError::Pdf("Adding to a multi-level /EmbeddedFiles name tree isn't supported".into()));
+  }
+
+  let mut names = files.get(b"Names").and_then(Object::as_array).cloned().unwrap_or_default();
+  let position = names.chunks(2)
+    .position(|pair| pair[0].as_str().is_ok_and(|key| key >= ATTACHMENT_NAME.as_bytes()))
+    .unwrap_or(names.len() / 2) * 2;
+  names.splice(position..position, [Object::string_literal(ATTACHMENT_NAME), filespec_id.into()]);
+  files.set("Names", names);
+  Ok(())
+}
 
-    // Adding the pdf's visible text
-    let visible_text = "Hello, World!";
-    let text_stream_content = format!("BT /F1 24 Tf 100 700 Td ({}) Tj ET", visible_text);
-    let text_stream = Stream::new(dictionary! {}, text_stream_content.as_bytes().to_vec());
-    let text_stream_id = doc.add_object(text_stream);
+/// Search a name tree node (and its kids, down to `depth` levels) for `name`
+fn find_in_name_tree<'a>(doc: &'a Document, node: &'a Dictionary, name: &[u8], depth: usize) -> Option<&'a Object> {
+  if let Ok(names) = node.get(b"Names").and_then(Object::as_array) {
+    for pair in names.chunks(2) {
+      if let [key, value] = pair
+        && key.as_str().is_ok_and(|key| key == name)
+      {
+        return Some(value);
+      }
+    }
+  }
+
+  // A kid pointing back up the tree would recurse forever
+  let depth = depth.checked_sub(1)?;
+  let kids = node.get(b"Kids").and_then(Object::as_array).ok()?;
+  kids.iter()
+    .filter_map(|kid| resolve(doc, kid).ok()?.as_dict().ok())
+    .find_map(|kid| find_in_name_tree(doc, kid, name, depth))
+}
+
+fn extract_attachment(doc: &Document) -> Result<Vec<u8>> {
+  let catalog = doc.catalog().map_err(pdf_err)?;
+  let names = resolve(doc, catalog.get(b"Names").map_err(pdf_err)?)?
+    .as_dict()
+    .map_err(pdf_err)?;
+  let embedded_files = resolve(doc, names.get(b"EmbeddedFiles").map_err(pdf_err)?)?
+    .as_dict()
+    .map_err(pdf_err)?;
+
+  let filespec = find_in_name_tree(doc, embedded_files, ATTACHMENT_NAME.as_bytes(), NAME_TREE_DEPTH)
+    .ok_or_else(|| PipelineError::Pdf(format!("No {} attachment", ATTACHMENT_NAME).into()))?;
+  let filespec = resolve(doc, filespec)?.as_dict().map_err(pdf_err)?;
+  let ef = resolve(doc, filespec.get(b"EF").map_err(pdf_err)?)?
+    .as_dict()
+    .map_err(pdf_err)?;
+  let file = resolve(doc, ef.get(b"F").map_err(pdf_err)?)?
+    .as_stream()
+    .map_err(pdf_err)?;
+  stream_bytes(file)
+}
+
+fn embed_xmp(doc: &mut Document, data: Vec<u8>) -> Result<()> {
+  let description = format!(
+    concat!(
+      "  <rdf:Description rdf:about=\"\" xmlns:wp=\"{}\">\n",
+      "   <wp:Payload>{}</wp:Payload>\n",
+      "  </rdf:Description>\n",
+    ),
+    XMP_NAMESPACE,
+    BASE64.encode(&data),
+  );
+
+  // Slot our description into the document's own packet when it has one
+  let catalog_id = catalog_id(doc)?;
+  let existing = doc.get_dictionary(catalog_id).map_err(pdf_err)?
+    .get(b"Metadata")
+    .and_then(Object::as_reference)
+    .ok()
+    .and_then(|id| Some((id, doc.get_object(id).ok()?.as_stream().ok()?)))
+    .and_then(|(id, stream)| Some((id, String::from_utf8(stream_bytes(stream).ok()?).ok()?)))
+    .filter(|(_, packet)| packet.contains("</rdf:RDF>"));
+
+  let (metadata_id, packet) = match existing {
+    Some((id, packet)) => (id, packet.replacen("</rdf:RDF>", &format!("{} </rdf:RDF>", description), 1)),
+    None => (doc.new_object_id(), format!(
+      concat!(
+        "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>\n",
+        "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\n",
+        " <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\n",
+        "{}",
+        " </rdf:RDF>\n",
+        "</x:xmpmeta>\n",
+        "<?xpacket end=\"w\"?>",
+      ),
+      description,
+    )),
+  };
+
+  doc.objects.insert(metadata_id, Object::Stream(Stream::new(
+    dictionary! {
+      "Type" => "Metadata",
+      "Subtype" => "XML",
+    },
+    packet.into_bytes(),
+  )));
+  doc.get_dictionary_mut(catalog_id).map_err(pdf_err)?.set("Metadata", metadata_id);
+  Ok(())
+}
+
+fn extract_xmp(doc: &Document) -> Result<Vec<u8>> {
+  let catalog = doc.catalog().map_err(pdf_err)?;
+  let metadata = resolve(doc, catalog.get(b"Metadata").map_err(pdf_err)?)?
+    .as_stream()
+    .map_err(pdf_err)?;
+  let packet = String::from_utf8(stream_bytes(metadata)?)?;
+
+  let start = packet.find("<wp:Payload>")
+    .ok_or_else(|| PipelineError::Pdf("XMP packet has no wp:Payload".into()))?
+    + "<wp:Payload>".len();
+  let end = packet[start..].find("</wp:Payload>")
+    .ok_or_else(|| PipelineError::Pdf("Unterminated wp:Payload".into()))?
+    + start;
+  let encoded: String = packet[start..end].chars().filter(|c| !c.is_whitespace()).collect();
+  BASE64.decode(encoded).map_err(pdf_err)
+}
+
+fn embed_image(doc: &mut Document, page_id: ObjectId, data: Vec<u8>) -> Result<()> {
+  let mut pixels = frame(&data);
+  let height = pixels.len().div_ceil(IMAGE_WIDTH);
+  pixels.resize(IMAGE_WIDTH * height, 0);
+
+  let image_id = doc.add_object(Stream::new(
+    dictionary! {
+      "Type" => "XObject",
+      "Subtype" => "Image",
+      "Width" => IMAGE_WIDTH as i64,
+      "Height" => height as i64,
+      "ColorSpace" => "DeviceGray",
+      "BitsPerComponent" => 8,
+    },
+    pixels,
+  ));
+  page_resource(doc, page_id, b"XObject")?.set("WpIm0", image_id);
+
+  // Draw it as a small texture in the bottom corner of the page
+  append_page_content(doc, page_id, b"q 48 0 0 48 528 24 cm /WpIm0 Do Q".to_vec())
+}
 
-    // PDF Page DIctionary
-    let obj_id = doc.new_object_id();
-    let page_id = doc.add_object(dictionary! {
+fn extract_image(doc: &Document) -> Result<Vec<u8>> {
+  for page_id in doc.page_iter() {
+    let Ok((inline, indirect)) = doc.get_page_resources(page_id) else {
+      continue;
+    };
+    let xobjects = inline.into_iter()
+      .chain(indirect.into_iter().filter_map(|id| doc.get_dictionary(id).ok()))
+      .filter_map(|resources| resolve(doc, resources.get(b"XObject").ok()?).ok()?.as_dict().ok());
+
+    for (_, xobject) in xobjects.flat_map(Dictionary::iter) {
+      let Ok(Object::Stream(stream)) = resolve(doc, xobject) else {
+        continue;
+      };
+      if !stream.dict.get(b"Subtype").is_ok_and(|s| s.as_name().is_ok_and(|s| s == b"Image")) {
+        continue;
+      }
+      if let Ok(payload) = stream_bytes(stream).and_then(|pixels| unframe(&pixels)) {
+        return Ok(payload);
+      }
+    }
+  }
+  Err(PipelineError::Pdf("No image XObject carries a payload".into()))
+}
+
+/// Font resource for invisible text, which is also how decoding tells it apart
+/// from other invisible text such as an OCR layer
+fn text_font() -> Dictionary {
+  dictionary! {
+    "Type" => "Font",
+    "Subtype" => "Type1",
+    "BaseFont" => "Helvetica",
+  }
+}
+
+/// Length of the `invisible_text` content stream for `len` bytes: the text object around
+/// them, then two hex digits per byte and `<..> Tj` plus `0 -1 Td` per line
+fn text_content_len(len: u64) -> u64 {
+  let lines = len.div_ceil(TEXT_BYTES_PER_LINE as u64);
+  "BT\n/WpF0 1 Tf\n3 Tr\n10 790 Td\nET\n".len() as u64 + len * 2 + lines * "<> Tj\n0 -1 Td\n".len() as u64
+}
+
+/// Content stream drawing `chunk` as hex strings of invisible text
+fn invisible_text(chunk: &[u8]) -> Result<Vec<u8>> {
+  let mut operations = vec![
+    Operation::new("BT", vec![]),
+    Operation::new("Tf", vec![TEXT_FONT.into(), 1.into()]),
+    Operation::new("Tr", vec![3.into()]),
+    Operation::new("Td", vec![10.into(), 790.into()]),
+  ];
+  for line in chunk.chunks(TEXT_BYTES_PER_LINE) {
+    operations.push(Operation::new("Tj", vec![Object::String(line.to_vec(), StringFormat::Hexadecimal)]));
+    operations.push(Operation::new("Td", vec![0.into(), (-1).into()]));
+  }
+  operations.push(Operation::new("ET", vec![]));
+  Content { operations }.encode().map_err(pdf_err)
+}
+
+fn embed_text(doc: &mut Document, page_id: ObjectId, data: Vec<u8>) -> Result<()> {
+  let framed = frame(&data);
+  let mut chunks = framed.chunks(TEXT_BYTES_PER_PAGE);
+
+  // The first chunk shares the visible page, the rest get blank pages of their own
+  page_resource(doc, page_id, b"Font")?.set(TEXT_FONT, text_font());
+  if let Some(first) = chunks.next() {
+    append_page_content(doc, page_id, invisible_text(first)?)?;
+  }
+
+  let pages_id = doc.catalog()
+    .and_then(|catalog| catalog.get(b"Pages"))
+    .and_then(Object::as_reference)
+    .map_err(pdf_err)?;
+  let media_box = doc.get_dictionary(page_id)
+    .and_then(|page| page.get(b"MediaBox"))
+    .cloned()
+    .unwrap_or_else(|_| vec![0.into(), 0.into(), 612.into(), 792.into()].into());
+
+  let mut new_pages = Vec::new();
+  for chunk in chunks {
+    let content_id = doc.add_object(Stream::new(dictionary! {}, invisible_text(chunk)?));
+    new_pages.push(doc.add_object(dictionary! {
       "Type" => "Page",
-      "Parent" => obj_id,
+      "Parent" => pages_id,
       "Resources" => dictionary! {
-        "Font" => dictionary! {
-          "F1" => dictionary! {
-            "Type" => "Font",
-            "Subtype" => "Type1",
-            "BaseFont" => "Helvetica"
-          }
-        }
+        "Font" => dictionary! { TEXT_FONT => text_font() },
       },
-      "Contents" => text_stream_id,
-      "MediaBox" => vec![0.into(), 0.into(), 600.into(), 800.into()],
-    });
-
-    // Creating /Pages dict
-    let pages_id = doc.add_object(dictionary! {
-      "Type" => "Pages",
-      "Kids" => vec![page_id.into()],
-      "Count" => 1,
-    });
-
-    // update page's parent
-    if let Object::Dictionary(dict) = doc.get_object_mut(page_id).map_err(|e| PipelineError::Pdf(e.to_string()))? {
-      dict.set("Parent", pages_id);
-    }
-
-    // Setup /Root catalog
-    let catalog_id = doc.add_object(dictionary! {
-      "Type" => "Catalog",
-      "Pages" => pages_id,
-    });
-    doc.trailer.set(b"Root", catalog_id);
-
-    // Adding the hidden stream
-    let hidden_stream = Stream::new(
-      dictionary! {
-        "Type" => "XObject",
-        "Subtype" => "Metadata",
-        "Length" => data.len() as i64,
-      },
-      data,
-    );
-    let hidden_stream_id = doc.add_object(hidden_stream);
-
-    // Store reference in /Info dir
-    let info_id = doc.add_object(dictionary! {});
-    doc.trailer.set("Info", info_id);
-    let info_dict = doc.get_object_mut(info_id)
-      .map_err(|e| PipelineError::Pdf(e.to_string()))?
-      .as_dict_mut()
-      .map_err(|e| PipelineError::Pdf(e.to_string()))?;
-    info_dict.set("WackyPixels", hidden_stream_id);
+      "Contents" => content_id,
+      "MediaBox" => media_box.clone(),
+    }));
+  }
+
+  let pages = doc.get_dictionary_mut(pages_id).map_err(pdf_err)?;
+  let count = pages.get(b"Count").and_then(Object::as_i64).map_err(pdf_err)?;
+  let mut kids = pages.get(b"Kids").and_then(Object::as_array).map_err(pdf_err)?.clone();
+  pages.set("Count", count + new_pages.len() as i64);
+  kids.extend(new_pages.into_iter().map(Object::from));
+  pages.set("Kids", kids);
+  Ok(())
+}
+
+fn extract_text(doc: &Document) -> Result<Vec<u8>> {
+  let mut framed = Vec::new();
+
+  for page_id in doc.page_iter() {
+    let Ok(content) = doc.get_and_decode_page_content(page_id) else {
+      continue;
+    };
+    let mut render_mode = 0;
+    let mut ours = false;
+    for operation in content.operations {
+      match (operation.operator.as_str(), operation.operands.as_slice()) {
+        ("BT", _) => render_mode = 0,
+        ("Tr", [mode]) => render_mode = mode.as_i64().unwrap_or(0),
+        ("Tf", [font, _]) => ours = font.as_name().is_ok_and(|font| font == TEXT_FONT.as_bytes()),
+        ("Tj", [Object::String(bytes, StringFormat::Hexadecimal)]) if ours && render_mode == 3 => {
+          framed.extend_from_slice(bytes);
+        }
+        _ => {}
+      }
+    }
+  }
+
+  unframe(&framed)
+}
+
+/// Streams of a possibly cut off PDF, found by scanning for `stream` keywords rather than
+/// through the cross-reference table. Returns each stream's dictionary with the whitespace
+/// taken out (`/Type/XObject`) and its content, inflated as far as it goes
+fn raw_streams(data: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+  let mut streams = Vec::new();
+  let mut position = 0;
+  while let Some(found) = find(&data[position..], b"stream") {
+    let keyword = position + found;
+    position = keyword + b"stream".len();
+    // `endstream`, or `stream` inside some other token
+    if data[..keyword].ends_with(b"end") {
+      continue;
+    }
+    let dict_end = data[..keyword].iter().rposition(|b| !b.is_ascii_whitespace()).unwrap_or(0);
+    let Some(obj) = rfind(&data[..keyword], b"obj") else {
+      continue;
+    };
+    if !data[..=dict_end].ends_with(b">>") {
+      continue;
+    }
+    let dict: Vec<u8> = data[obj + 3..=dict_end].iter().copied().filter(|b| !b.is_ascii_whitespace()).collect();
+
+    let start = match data.get(position..) {
+      Some([b'\r', b'\n', ..]) => position + 2,
+      Some([b'\n', ..]) => position + 1,
+      _ => continue,
+    };
+    let end = match dict_number(&dict, b"/Length") {
+      Some(length) => start.saturating_add(length).min(data.len()),
+      None => find(&data[start..], b"endstream").map_or(data.len(), |end| start + end),
+    };
+    let mut content = data[start..end].to_vec();
+    if find(&dict, b"/FlateDecode").is_some() {
+      // read_to_end keeps whatever was inflated before the stream ran out
+      let mut inflated = Vec::new();
+      let _ = flate2::read::ZlibDecoder::new(&content[..]).read_to_end(&mut inflated);
+      content = inflated;
+    }
+    streams.push((dict, content));
+    position = end;
+  }
+  streams
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+  haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+fn rfind(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+  haystack.windows(needle.len()).rposition(|window| window == needle)
+}
+
+/// Integer right after `key` in a whitespace free dictionary. A reference like
+/// `/Length 12 0 R` (now `/Length120R`) doesn't count
+fn dict_number(dict: &[u8], key: &[u8]) -> Option<usize> {
+  let rest = &dict[find(dict, key)? + key.len()..];
+  let digits = rest.iter().take_while(|b| b.is_ascii_digit()).count();
+  if rest.get(digits) == Some(&b'R') {
+    return None;
+  }
+  std::str::from_utf8(&rest[..digits]).ok()?.parse().ok()
+}
+
+/// The framed payload, or as much of it as `framed` holds
+fn unframe_partial(framed: &[u8]) -> Option<Vec<u8>> {
+  if framed.len() < 8 || &framed[..4] != FRAME_MAGIC {
+    return None;
+  }
+  let len = u32::from_le_bytes([framed[4], framed[5], framed[6], framed[7]]) as usize;
+  Some(framed[8..].iter().take(len).copied().collect())
+}
+
+/// Hex strings of the invisible text in a content stream
+fn hex_strings(content: &[u8]) -> Vec<u8> {
+  let mut bytes = Vec::new();
+  let mut digits = Vec::new();
+  let mut inside = false;
+  for &b in content {
+    match b {
+      b'<' => inside = true,
+      b'>' => {
+        inside = false;
+        digits.clear();
+      }
+      _ if inside && b.is_ascii_hexdigit() => {
+        digits.push(b);
+        if let [high, low] = digits[..] {
+          let hex = [high, low];
+          bytes.extend(std::str::from_utf8(&hex).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok()));
+          digits.clear();
+        }
+      }
+      _ => {}
+    }
+  }
+  bytes
+}
+
+/// As much of the payload as a cut off PDF still holds, for every strategy but encryption
+fn scan_payload(data: &[u8]) -> Option<(PdfStrategy, Vec<u8>)> {
+  let has = |dict: &[u8], name: &[u8]| find(dict, name).is_some();
+  let mut text = Vec::new();
+  for (dict, content) in raw_streams(data) {
+    if has(&dict, b"/Type/XObject") && has(&dict, b"/Subtype/Metadata") {
+      return Some((PdfStrategy::Info, content));
+    }
+    if has(&dict, b"/Type/EmbeddedFile") {
+      return Some((PdfStrategy::Attachment, content));
+    }
+    if has(&dict, b"/Subtype/Image")
+      && let Some(payload) = unframe_partial(&content)
+    {
+      return Some((PdfStrategy::Image, payload));
+    }
+    if let Some(start) = find(&content, b"<wp:Payload>") {
+      let encoded: Vec<u8> = content[start + b"<wp:Payload>".len()..].iter()
+        .copied()
+        .take_while(|&b| b != b'<')
+        .filter(|b| !b.is_ascii_whitespace())
+        .collect();
+      let whole = encoded.len() / 4 * 4;
+      return Some((PdfStrategy::Xmp, BASE64.decode(&encoded[..whole]).ok()?));
+    }
+    if find(&content, format!("/{}", TEXT_FONT).as_bytes()).is_some() {
+      text.extend(hex_strings(&content));
+    }
+  }
+  unframe_partial(&text).map(|payload| (PdfStrategy::Text, payload))
+}
+
+impl Transform for PdfTransform {
+  fn encode(&self, data: Vec<u8>) -> Result<Vec<u8>> {
+    self.check_options()?;
+    let cover = self.cover.as_ref().map(fs::read).transpose()?;
+    let (mut doc, page_id) = match &cover {
+      Some(bytes) => load_cover(bytes)?,
+      None => base_document(self.preview, self.caption.as_deref().unwrap_or("Hello, World!"), &data)?,
+    };
+    // Decoding would find the old payload before ours
+    if cover.is_some() && let Ok((strategy, _)) = extract_any(&doc) {
+      return Err(PipelineError::Pdf(format!(
+        "The cover already hides a payload (with the {:?} strategy), use a PDF without one",
+        strategy
+      ).into()));
+    }
+    let original = cover.is_some().then(|| doc.clone());
+    apply_metadata(&mut doc, &self.metadata)?;
+    mark_version(&mut doc)?;
+
+    match self.strategy {
+      PdfStrategy::Info => embed_info(&mut doc, data)?,
+      PdfStrategy::Attachment => embed_attachment(&mut doc, data)?,
+      PdfStrategy::Xmp => embed_xmp(&mut doc, data)?,
+      PdfStrategy::Image => embed_image(&mut doc, page_id, data)?,
+      PdfStrategy::Text => embed_text(&mut doc, page_id, data)?,
+    }
+
+    if self.compress {
+      compress_streams(&mut doc, original.as_ref())?;
+    }
+
+    if let (Some(bytes), Some(original)) = (cover, original) {
+      return save_incremental(bytes, original, doc);
+    }
+
+    let mut rng = rng(self.seed);
+    set_id(&mut doc, &mut rng);
+    if let (Some(encryption), Some(password)) = (self.encryption, &self.password) {
+      encrypt(&mut doc, encryption, password, &mut rng)?;
+    }
 
     let mut pdf_bytes = Vec::new();
-    
-    doc.save_to(&mut Cursor::new(&mut pdf_bytes))
-      .map_err(|e| e.to_string())?;
+
+    if self.object_streams {
+      let options = SaveOptions::builder()
+        .use_object_streams(true)
+        .use_xref_streams(true)
+        .build();
+      doc.save_with_options(&mut pdf_bytes, options)
+        .map_err(pdf_err)?;
+    } else {
+      doc.save_to(&mut Cursor::new(&mut pdf_bytes))
+        .map_err(pdf_err)?;
+    }
     Ok(pdf_bytes)
   }
 
+  fn estimate(&self, input_bytes: u64, sample: &[u8]) -> Result<Estimate> {
+    // What Flate makes of the payload depends on the data, only a sample can tell
+    if self.compress {
+      return sampled_estimate(self, input_bytes, sample);
+    }
+    // Page, objects, cross-reference table and any cover, encryption or metadata don't grow
+    // with the payload, so an empty one has them all
+    let empty = self.encode(Vec::new())?.len() as u64;
+    let mut bytes = empty + self.carrier_len(input_bytes) - self.carrier_len(0) + self.preview_len(input_bytes, sample)?;
+
+    // Each further text page brings a page object and a content stream of its own,
+    // measured once on a payload just long enough for a second page
+    let pages = (input_bytes + 8).div_ceil(TEXT_BYTES_PER_PAGE as u64);
+    if self.strategy == PdfStrategy::Text && pages > 1 {
+      let second_page = TEXT_BYTES_PER_PAGE as u64 - 8 + 1;
+      let two_pages = self.encode(vec![0; second_page as usize])?.len() as u64;
+      let page = two_pages.saturating_sub(empty + self.carrier_len(second_page) - self.carrier_len(0));
+      bytes += (pages - 1) * page;
+    }
+    // `startxref` at the end points to the table from near the end of the file
+    bytes += digits(bytes) - digits(empty);
+    Ok(Estimate { bytes, model: "analytic" })
+  }
+
   fn decode(&self, data: Vec<u8>) -> Result<Vec<u8>> {
-    let doc = Document::load_from(Cursor::new(&data))
-        .map_err(|e| PipelineError::Pdf(e.to_string()))?;
-
-    // Find hidden stream using /Info dict
-    let info_id = doc.trailer.get(b"Info")
-      .map_err(|e| PipelineError::Pdf(e.to_string()))?
-      .as_reference()
-      .map_err(|e| PipelineError::Pdf(e.to_string()))?;
-    let info_dict = doc.get_object(info_id)
-      .map_err(|e| PipelineError::Pdf(e.to_string()))?
-      .as_dict()
-      .map_err(|e| PipelineError::Pdf(e.to_string()))?;
-    let hidden_id = info_dict.get(b"WackyPixels")
-      .map_err(|e| PipelineError::Pdf(e.to_string()))?
-      .as_reference()
-      .map_err(|e| PipelineError::Pdf(e.to_string()))?;
-
-    // extract the hidden stream
-    let hidden_object = doc.get_object(hidden_id)
-      .map_err(|e| PipelineError::Pdf(e.to_string()))?;
-    let hidden_stream = match hidden_object {
-      Object::Stream(stream) => stream,
-      _ => return Err(PipelineError::Pdf("WackyFiles is not a stream".to_string())),
+    let (_, payload) = self.find_payload(&data)?;
+    Ok(payload)
+  }
+
+  fn decode_partial(&self, data: Vec<u8>) -> Result<Partial> {
+    let error = match self.load(&data) {
+      Ok(doc) => {
+        check_format(format_version(&doc), FORMAT_VERSION).map_err(PipelineError::Pdf)?;
+        match extract_any(&doc) {
+          Ok((_, payload)) => return Ok(Partial { data: payload, complete: true }),
+          Err(e) => e,
+        }
+      }
+      // Encrypted streams are no use without the document around them
+      Err(e @ PipelineError::Password(_)) => return Err(e),
+      Err(e) if find(&data, b"/Encrypt").is_some() => return Err(e),
+      Err(e) => e,
     };
-    
-    let data = hidden_stream.content.clone();
-  
-    Ok(data)
+    // Without its cross-reference table a cut off PDF won't load, but the streams are all still there
+    check_format(scanned_version(&data), FORMAT_VERSION).map_err(PipelineError::Pdf)?;
+    match scan_payload(&data) {
+      Some((_, payload)) => Ok(Partial { data: payload, complete: false }),
+      None => Err(error),
+    }
   }
 
+  fn sniff(&self, data: &[u8]) -> Option<String> {
+    if !data.starts_with(b"%PDF-") {
+      return None;
+    }
+    let version = data.get(5..8).map(String::from_utf8_lossy).unwrap_or_default();
+    match self.find_payload(data) {
+      Ok((strategy, payload)) => Some(format!(
+        "PDF {}, {} byte payload hidden with the {:?} strategy",
+        version, payload.len(), strategy,
+      )),
+      Err(_) => Some(format!("PDF {}", version)),
+    }
+  }
 
   fn name(&self) -> &str {
     "PDF"
@@ -119,5 +1371,8 @@ impl Transform for PdfTransform {
   fn extension(&self) -> &str {
     "pdf"
   }
-}
 
+  fn output_kind(&self) -> Kind {
+    Kind::Pdf
+  }
+}
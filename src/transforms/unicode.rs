@@ -1,5 +1,34 @@
-use crate::transform::Transform;
-use crate::error::{PipelineError, Result};
+use crate::transform::{Estimate, Kind, Partial, Transform, check_format};
+use crate::error::{Detail, Offset, PipelineError, Result};
+use rayon::prelude::*;
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Opening marker of a part header, e.g. `[wackypixels 2/5]`
+const PART_HEADER: &str = "[wackypixels ";
+
+/// U+2063 INVISIBLE SEPARATOR between independently encoded blocks
+const BLOCK_SEPARATOR: &str = "\u{2063}";
+
+/// U+2062 INVISIBLE TIMES at the start of the output, followed by the format version
+/// as a variation selector (U+FE00 + version). Like everything outside the encoding
+/// ranges, both are skipped when decoding the blocks
+const VERSION_MARK: char = '\u{2062}';
+
+/// Version of the format `encode` writes. Version 0 had no version mark
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Format version of the (reassembled) encoded text
+fn format_version(encoded: &str) -> Result<u32> {
+  let Some(start) = encoded.find(VERSION_MARK) else {
+    return Ok(0);
+  };
+  let version = encoded[start + VERSION_MARK.len_utf8()..].chars().next()
+    .and_then(|selector| (selector as u32).checked_sub(0xFE00))
+    .filter(|&version| version < 16)
+    .ok_or_else(|| PipelineError::Unicode(Detail::from("Malformed format version").at(Offset::Byte(start as u64))))?;
+  check_format(version, FORMAT_VERSION).map_err(|e| PipelineError::Unicode(e.at(Offset::Byte(start as u64))))?;
+  Ok(version)
+}
 
 #[derive(Clone, Copy)]
 enum EncodeMode {
@@ -34,122 +63,454 @@ impl EncodeMode {
   }
 }
 
-#[derive(Debug)]
-pub struct UnicodeTransform;
+#[derive(Debug, Default)]
+pub struct UnicodeTransform {
+  /// Break lines after this many graphemes
+  wrap: Option<usize>,
+  /// Split the output into parts of at most this many code points
+  split: Option<usize>,
+  /// Prefix every part with a `[wackypixels i/N]` header
+  headers: bool,
+  /// Encode independent blocks of this many bytes in parallel
+  chunk_size: Option<usize>,
+}
 
-impl Transform for UnicodeTransform {
-    fn encode(&self, data: Vec<u8>) -> Result<Vec<u8>> {
-      let mut out = String::new();
-      let mut bit_buffer: u64 = 0;
-      let mut bit_count: u32 = 0;
-      let mut idx = 0;
-      
-      // Header: 3 × 14-bit Visible chars store total length
-      let total_len = data.len() as u32;
-      for i in 0..3 {
-        let chunk = (total_len >> (i * 14)) & 0x3FFF;
-        out.push(char::from_u32(0x4E00 + chunk).unwrap());
+impl UnicodeTransform {
+  pub fn new() -> Self {
+    Self::default()
+  }
+  
+  pub fn wrap(mut self, graphemes: Option<usize>) -> Self {
+    self.wrap = graphemes.filter(|&n| n > 0);
+    self
+  }
+  
+  /// Splitting always adds part headers, otherwise the parts couldn't be reordered
+  pub fn split(mut self, code_points: Option<usize>) -> Self {
+    self.split = code_points;
+    self
+  }
+  
+  pub fn headers(mut self, enable: bool) -> Self {
+    self.headers = enable;
+    self
+  }
+  
+  pub fn chunk_size(mut self, bytes: Option<usize>) -> Self {
+    self.chunk_size = bytes;
+    self
+  }
+  
+  /// Lay the encoded text out into lines and numbered parts
+  fn layout(&self, encoded: &str) -> Result<String> {
+    if self.wrap.is_none() && self.split.is_none() && !self.headers {
+      return Ok(encoded.to_string());
+    }
+    
+    let glyphs = glyphs(encoded);
+    
+    let Some(limit) = self.split else {
+      let body = pack(&glyphs, self.wrap, usize::MAX)?.concat();
+      return Ok(if self.headers {
+        format!("{}1/1]\n{}", PART_HEADER, body)
+      } else {
+        body
+      });
+    };
+    
+    // The header width depends on the number of parts, so retry with wider
+    // headers until the part count fits in the digits we budgeted for
+    let mut digits = 1;
+    loop {
+      let header_len = header_len(digits);
+      if limit <= header_len {
+        return Err(PipelineError::Unicode(format!(
+          "Split limit of {} code points leaves no room for data after the part header",
+          limit
+        ).into()));
       }
       
-      while idx < data.len() || bit_count > 0 {
-        // Fill buffer
-        while bit_count < 18 && idx < data.len() {
-          bit_buffer = (bit_buffer << 8) | data[idx] as u64;
-          bit_count += 8;
-          idx += 1;
-        }
-        
-        // Take 2 bits for mode (or pad if not enough bits)
-        let mode_bits = if bit_count >= 2 {
-          ((bit_buffer >> (bit_count - 2)) & 0b11) as u8
-        } else {
-          ((bit_buffer << (2 - bit_count)) & 0b11) as u8
-        };
-        bit_count = bit_count.saturating_sub(2);
-        
-        let mode = EncodeMode::from_bits(mode_bits);
-        let (base, depth) = mode.config();
-        
-        // Take depth bits for payload
-        let val = if bit_count >= depth {
-          let v = (bit_buffer >> (bit_count - depth)) & ((1 << depth) - 1);
-          bit_count -= depth;
-          v
-        } else {
-          // pad remaining bits
-          let v = (bit_buffer << (depth - bit_count)) & ((1 << depth) - 1);
-          bit_count = 0;
-          v
-        };
-        
-        bit_buffer &= (1 << bit_count) - 1;
-        out.push(char::from_u32(base + val as u32).unwrap());
+      let parts = pack(&glyphs, self.wrap, limit - header_len)?;
+      let total = parts.len();
+      if total.to_string().len() > digits {
+        digits += 1;
+        continue;
       }
       
-      Ok(out.as_bytes().to_vec())
+      let out: Vec<String> = parts.iter()
+        .enumerate()
+        .map(|(i, body)| format!("{}{}/{}]\n{}", PART_HEADER, i + 1, total, body))
+        .collect();
+      return Ok(out.join("\n\n"));
+    }
+  }
+}
+
+/// Returns (base_codepoint, payload_bits, mode_bits) for glyphs in one of the encoding ranges
+fn glyph_config(cp: u32) -> Option<(u32, u32, u8)> {
+  if (0x4E00..=0x9FFF).contains(&cp) {
+    Some((0x4E00, 14, 0b01))
+  } else if (0x1F600..=0x1F63F).contains(&cp) {
+    Some((0x1F600, 6, 0b10))
+  } else if (0xE0100..=0xE01EF).contains(&cp) {
+    Some((0xE0100, 7, 0b00))
+  } else if (0x1D400..=0x1D7FF).contains(&cp) {
+    Some((0x1D400, 10, 0b11))
+  } else {
+    None
+  }
+}
+
+/// Worst case code points taken by a header and its newline with `digits`-wide numbers
+fn header_len(digits: usize) -> usize {
+  PART_HEADER.chars().count() + digits * 2 + 3
+}
+
+/// The encoded text cut into glyphs to lay out, with how many graphemes start in each: one glyph
+/// per code point, except that the version selector stays with its mark. Parts can't go by
+/// grapheme, the invisible glyphs are variation selectors and a run of them is a single grapheme
+fn glyphs(encoded: &str) -> Vec<(&str, usize)> {
+  let mut graphemes = encoded.grapheme_indices(true).map(|(i, _)| i).peekable();
+  let mut graphemes_before = |end: usize| std::iter::from_fn(|| graphemes.next_if(|&i| i < end)).count();
+  let mut glyphs = Vec::new();
+  let mut start = 0;
+  for (i, _) in encoded.char_indices().skip(1) {
+    let after_mark = i == start + VERSION_MARK.len_utf8() && encoded[start..].starts_with(VERSION_MARK);
+    if !after_mark {
+      glyphs.push((&encoded[start..i], graphemes_before(i)));
+      start = i;
     }
+  }
+  if start < encoded.len() {
+    glyphs.push((&encoded[start..], graphemes_before(encoded.len())));
+  }
+  glyphs
+}
+
+/// Greedily pack glyphs into part bodies of at most `budget` code points,
+/// breaking lines every `wrap` graphemes
+fn pack(glyphs: &[(&str, usize)], wrap: Option<usize>, budget: usize) -> Result<Vec<String>> {
+  let mut parts = Vec::new();
+  let mut body = String::new();
+  let mut body_len = 0;
+  let mut line_len = 0;
+  
+  for &(g, graphemes) in glyphs {
+    let g_len = g.chars().count();
+    let needs_break = graphemes > 0 && wrap.is_some_and(|w| line_len >= w);
+    let cost = g_len + needs_break as usize;
     
-    fn decode(&self, data: Vec<u8>) -> Result<Vec<u8>> {
-      let encoded = String::from_utf8(data)?;
-      let mut chars = encoded.chars();
+    if body_len > 0 && body_len + cost > budget {
+      parts.push(std::mem::take(&mut body));
+      body_len = 0;
+      line_len = 0;
+    } else if needs_break {
+      body.push('\n');
+      body_len += 1;
+      line_len = 0;
+    }
+    
+    if g_len > budget {
+      return Err(PipelineError::Unicode(format!(
+        "Split limit too small to fit a single {}-code-point character",
+        g_len
+      ).into()));
+    }
+    
+    // A grapheme cut in two by a part boundary starts over in the next part
+    line_len += graphemes.max((body_len == 0) as usize);
+    body.push_str(g);
+    body_len += g_len;
+  }
+  
+  if !body.is_empty() || parts.is_empty() {
+    parts.push(body);
+  }
+  Ok(parts)
+}
+
+/// Put numbered parts back in order. Text without part headers is returned as is
+fn reassemble(text: &str) -> Result<String> {
+  let mut parts: Vec<(usize, usize, &str)> = Vec::new();
+  let headers: Vec<usize> = text.match_indices(PART_HEADER).map(|(i, _)| i).collect();
+  
+  if headers.is_empty() {
+    return Ok(text.to_string());
+  }
+  
+  for (n, &start) in headers.iter().enumerate() {
+    let rest = &text[start + PART_HEADER.len()..];
+    let close = rest.find(']')
+      .ok_or_else(|| PipelineError::Unicode(Detail::from("Unterminated part header").at(Offset::Byte(start as u64))))?;
+    let (index, total) = rest[..close].split_once('/')
+      .and_then(|(i, t)| Some((i.trim().parse::<usize>().ok()?, t.trim().parse::<usize>().ok()?)))
+      .ok_or_else(|| PipelineError::Unicode(
+        Detail::from(format!("Malformed part header: {}{}]", PART_HEADER, &rest[..close])).at(Offset::Byte(start as u64))
+      ))?;
+    
+    let body_start = start + PART_HEADER.len() + close + 1;
+    let body_end = headers.get(n + 1).copied().unwrap_or(text.len());
+    parts.push((index, total, &text[body_start..body_end]));
+  }
+  
+  let total = parts[0].1;
+  if parts.iter().any(|&(_, t, _)| t != total) {
+    return Err(PipelineError::Unicode("Parts disagree on the total part count".into()));
+  }
+  
+  if let Some(&(index, _, _)) = parts.iter().find(|&&(i, _, _)| i == 0 || i > total) {
+    return Err(PipelineError::Unicode(format!("Part {} doesn't fit in {} parts", index, total).into()));
+  }
+  
+  // The same part pasted twice is fine, two different ones with the same number aren't
+  let glyphs = |body: &str| body.chars().filter(|&c| glyph_config(c as u32).is_some()).collect::<String>();
+  parts.sort_by_key(|&(i, _, _)| i);
+  for pair in parts.windows(2) {
+    if let [(index, _, first), (next, _, second)] = pair
+      && index == next
+      && glyphs(first) != glyphs(second)
+    {
+      return Err(PipelineError::Unicode(format!(
+        "Part {} of {} shows up twice with different contents", index, total
+      ).into()));
+    }
+  }
+  parts.dedup_by_key(|&mut (i, _, _)| i);
+  for (expected, &(index, _, _)) in (1..=total).zip(parts.iter()) {
+    if index != expected {
+      return Err(PipelineError::Unicode(format!("Missing part {} of {}", expected, total).into()));
+    }
+  }
+  if parts.len() < total {
+    return Err(PipelineError::Unicode(format!(
+      "Missing part {} of {}", parts.len() + 1, total
+    ).into()));
+  }
+  
+  Ok(parts.iter().map(|&(_, _, body)| body).collect())
+}
+
+/// The glyph for `code_point`, which the encoding ranges keep clear of surrogates
+fn glyph(code_point: u32) -> Result<char> {
+  char::from_u32(code_point)
+    .ok_or_else(|| PipelineError::Unicode(format!("U+{:X} is not a valid character", code_point).into()))
+}
+
+/// Encode one independent block: a 3 glyph length header, then the data
+fn encode_block(data: &[u8]) -> Result<String> {
+  let mut out = String::new();
+  let mut bit_buffer: u64 = 0;
+  let mut bit_count: u32 = 0;
+  let mut idx = 0;
+  
+  // Header: 3 × 14-bit Visible chars store total length
+  let total_len = data.len() as u32;
+  for i in 0..3 {
+    let chunk = (total_len >> (i * 14)) & 0x3FFF;
+    out.push(glyph(0x4E00 + chunk)?);
+  }
+  
+  while idx < data.len() || bit_count > 0 {
+    // Fill buffer
+    while bit_count < 18 && idx < data.len() {
+      bit_buffer = (bit_buffer << 8) | data[idx] as u64;
+      bit_count += 8;
+      idx += 1;
+    }
+    
+    // Take 2 bits for mode (or pad if not enough bits)
+    let mode_bits = if bit_count >= 2 {
+      ((bit_buffer >> (bit_count - 2)) & 0b11) as u8
+    } else {
+      ((bit_buffer << (2 - bit_count)) & 0b11) as u8
+    };
+    bit_count = bit_count.saturating_sub(2);
+    
+    let mode = EncodeMode::from_bits(mode_bits);
+    let (base, depth) = mode.config();
+    
+    // Take depth bits for payload
+    let val = if bit_count >= depth {
+      let v = (bit_buffer >> (bit_count - depth)) & ((1 << depth) - 1);
+      bit_count -= depth;
+      v
+    } else {
+      // pad remaining bits
+      let v = (bit_buffer << (depth - bit_count)) & ((1 << depth) - 1);
+      bit_count = 0;
+      v
+    };
+    
+    bit_buffer &= (1 << bit_count) - 1;
+    out.push(glyph(base + val as u32)?);
+  }
+  
+  Ok(out)
+}
+
+/// Decode one block of glyphs, anything outside the encoding ranges is skipped
+fn decode_block(encoded: &str) -> Result<Vec<u8>> {
+  let (out, total_len) = decode_glyphs(encoded)?;
+  if out.len() != total_len as usize {
+    return Err(PipelineError::Unicode("Decoded length mismatch".into()));
+  }
+  Ok(out)
+}
+
+/// Returns the bytes the glyphs of one block hold and the length its header promises
+fn decode_glyphs(encoded: &str) -> Result<(Vec<u8>, u32)> {
+  let mut chars = encoded.chars().filter(|&c| glyph_config(c as u32).is_some());
+  
+  // Decode length header
+  let mut total_len: u32 = 0;
+  for i in 0..3 {
+    let c = chars.next().ok_or_else(|| PipelineError::Unicode("Missing header".into()))?;
+    let v = (c as u32)
+      .checked_sub(0x4E00)
+      .ok_or_else(|| PipelineError::Unicode("Invalid header char".into()))?;
+    total_len |= v << (i * 14);
+  }
+  
+  let mut out = Vec::with_capacity((total_len as usize).min(encoded.len()));
+  let mut bit_buffer: u64 = 0;
+  let mut bit_count: u32 = 0;
+  
+  for c in chars {
+    let cp = c as u32;
+    // Determine mode and payload bits
+    let Some((base, depth, mode_bits)) = glyph_config(cp) else {
+      continue;
+    };
+    
+    let val = cp - base;
+    
+    // Prepend mode bits (the 2 bits) for this glyph
+    bit_buffer = (bit_buffer << (depth + 2)) | ((mode_bits as u64) << depth) | val as u64;
+    bit_count += depth + 2;
+    
+    // Extract bytes
+    while bit_count >= 8 && out.len() < total_len as usize {
+      let byte = (bit_buffer >> (bit_count - 8)) as u8;
+      out.push(byte);
+      bit_count -= 8;
+      bit_buffer &= (1 << bit_count) - 1;
+    }
+  }
+  
+  Ok((out, total_len))
+}
+
+impl Transform for UnicodeTransform {
+    fn encode(&self, data: Vec<u8>) -> Result<Vec<u8>> {
+      let out = match self.chunk_size {
+        Some(chunk_size) if !data.is_empty() => data
+          .par_chunks(chunk_size.max(1))
+          .map(encode_block)
+          .collect::<Result<Vec<_>>>()?
+          .join(BLOCK_SEPARATOR),
+        _ => encode_block(&data)?,
+      };
+      let out = format!("{}{}{}", VERSION_MARK, glyph(0xFE00 + FORMAT_VERSION)?, out);
       
-      // Decode length header
-      let mut total_len: u32 = 0;
-      for i in 0..3 {
-        let c = chars.next().ok_or("Missing header")
-          .map_err(|e| PipelineError::Unicode(e.to_string()))?;
-        let v = (c as u32)
-          .checked_sub(0x4E00)
-          .ok_or("Invalid header char")
-          .map_err(|e| PipelineError::Unicode(e.to_string()))?;
-        total_len |= v << (i * 14);
+      Ok(self.layout(&out)?.into_bytes())
+    }
+    
+    fn decode(&self, data: Vec<u8>) -> Result<Vec<u8>> {
+      // Whitespace, quote markers and anything else outside the
+      // encoding ranges gets skipped in decode_block
+      let encoded = reassemble(&String::from_utf8(data)?)?;
+      format_version(&encoded)?;
+      let blocks = encoded
+        .split(BLOCK_SEPARATOR)
+        .collect::<Vec<_>>()
+        .par_iter()
+        .map(|block| decode_block(block))
+        .collect::<Result<Vec<_>>>()?;
+      Ok(blocks.concat())
+    }
+    
+    fn estimate(&self, input_bytes: u64, _sample: &[u8]) -> Result<Estimate> {
+      // With evenly spread bits every mode is equally likely, so a glyph carries
+      // 2 + (7 + 14 + 6 + 10) / 4 bits in (4 + 3 + 4 + 4) / 4 bytes of UTF-8
+      let glyphs = (input_bytes as f64 * 8.0 / 11.25).ceil();
+      let blocks = match self.chunk_size {
+        Some(chunk_size) if input_bytes > 0 => input_bytes.div_ceil(chunk_size.max(1) as u64),
+        _ => 1,
+      };
+      // 3 CJK length glyphs per block, 3 UTF-8 bytes per separator and 6 for the version mark
+      let mut bytes = 6.0 + glyphs * 3.75 + blocks as f64 * 9.0 + (blocks - 1) as f64 * 3.0;
+      if let Some(wrap) = self.wrap {
+        // Invisible glyphs join the grapheme before them, so about 3 in 4 glyphs start one
+        bytes += glyphs * 0.75 / wrap as f64;
       }
+      if let Some(split) = self.split {
+        // Roughly `[wackypixels i/N]` plus the blank line between parts
+        let parts = (glyphs / split.saturating_sub(header_len(3)).max(1) as f64).ceil();
+        bytes += parts * (header_len(parts.to_string().len()) + 2) as f64;
+      } else if self.headers {
+        bytes += header_len(1) as f64;
+      }
+      Ok(Estimate { bytes: bytes.round() as u64, model: "analytic" })
+    }
+    
+    fn capacity(&self) -> Option<u64> {
+      // Block lengths are stored as a u32
+      match self.chunk_size {
+        Some(_) => None,
+        None => Some(u32::MAX as u64),
+      }
+    }
+    
+    fn decode_partial(&self, data: Vec<u8>) -> Result<Partial> {
+      // A cut off code point at the very end is just dropped
+      let text = match String::from_utf8(data) {
+        Ok(text) => text,
+        Err(e) => {
+          let valid = e.utf8_error().valid_up_to();
+          let mut bytes = e.into_bytes();
+          bytes.truncate(valid);
+          String::from_utf8(bytes)?
+        }
+      };
+      let encoded = reassemble(&text)?;
+      format_version(&encoded)?;
       
-      let mut out = Vec::with_capacity(total_len as usize);
-      let mut bit_buffer: u64 = 0;
-      let mut bit_count: u32 = 0;
-      
-      for c in chars {
-        let cp = c as u32;
-        // Determine mode and payload bits
-        let (base, depth) = if (0x4E00..=0x9FFF).contains(&cp) {
-          (0x4E00, 14)
-        } else if (0x1F600..=0x1F63F).contains(&cp) {
-          (0x1F600, 6)
-        } else if (0xE0100..=0xE01EF).contains(&cp) {
-          (0xE0100, 7)
-        } else if (0x1D400..=0x1D7FF).contains(&cp) {
-          (0x1D400, 10)
-        } else {
-          continue;
-        };
-        
-        let val = cp - base;
-        
-        // Prepend mode bits (the 2 bits) for this glyph
-        let mode_bits = match base {
-          0x4E00 => 0b01,
-          0xE0100 => 0b00,
-          0x1F600 => 0b10,
-          0x1D400 => 0b11,
-          _ => unreachable!(),
+      let mut out = Vec::new();
+      for block in encoded.split(BLOCK_SEPARATOR) {
+        let Ok((data, total_len)) = decode_glyphs(block) else {
+          return Ok(Partial { data: out, complete: false });
         };
-        bit_buffer = (bit_buffer << (depth + 2)) | ((mode_bits as u64) << depth) | val as u64;
-        bit_count += depth + 2;
-        
-        // Extract bytes
-        while bit_count >= 8 && out.len() < total_len as usize {
-          let byte = (bit_buffer >> (bit_count - 8)) as u8;
-          out.push(byte);
-          bit_count -= 8;
-          bit_buffer &= (1 << bit_count) - 1;
+        out.extend_from_slice(&data);
+        if data.len() < total_len as usize {
+          return Ok(Partial { data: out, complete: false });
         }
       }
-      
-      if out.len() != total_len as usize {
-        return Err(PipelineError::Unicode("Decoded length mismatch".into()));
+      Ok(Partial { data: out, complete: true })
+    }
+    
+    fn sniff(&self, data: &[u8]) -> Option<String> {
+      let text = std::str::from_utf8(data).ok()?;
+      let parts = text.matches(PART_HEADER).count();
+      let encoded = reassemble(text).ok()?;
+      let mut total_len: u64 = 0;
+      let mut blocks = 0;
+      for block in encoded.split(BLOCK_SEPARATOR) {
+        // The 3 glyph length header is always CJK
+        let mut glyphs = block.chars().filter(|&c| glyph_config(c as u32).is_some());
+        for i in 0..3 {
+          let v = (glyphs.next()? as u32).checked_sub(0x4E00).filter(|&v| v <= 0x3FFF)?;
+          total_len += (v as u64) << (i * 14);
+        }
+        blocks += 1;
+      }
+      let mut description = format!("unicode text, {} byte payload", total_len);
+      if blocks > 1 {
+        description.push_str(&format!(" in {} blocks", blocks));
       }
-      Ok(out)
+      if parts > 0 {
+        description.push_str(&format!(" in {} parts", parts));
+      }
+      Some(description)
     }
     
     fn name(&self) -> &str {
@@ -159,4 +520,8 @@ impl Transform for UnicodeTransform {
     fn extension(&self) -> &str {
         "txt"
     }
+
+    fn output_kind(&self) -> Kind {
+      Kind::Text
+    }
 }
\ No newline at end of file
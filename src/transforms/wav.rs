@@ -1,6 +1,7 @@
 use hound::{WavWriter, WavSpec};
-use crate::transform::Transform;
-use crate::error::{PipelineError, Result};
+use crate::transform::{Estimate, Kind, Partial, Transform, check_format};
+use crate::error::{Detail, Offset, PipelineError, Result};
+use rayon::prelude::*;
 use std::io::Cursor;
 
 fn qam16_map(symbol: u8) -> (i8, i8) {
@@ -40,164 +41,302 @@ fn qam16_demap(i: i8, q: i8) -> u8 {
   (i_level << 2) | q_level
 }
 
-#[derive(Debug)]
-pub struct WavTransform;
+/// Samples per QAM symbol, the Nyquist limit
+const SAMPLES_PER_SYMBOL: usize = 2;
 
-impl Transform for WavTransform {
-  fn encode(&self, data: Vec<u8>) -> Result<Vec<u8>> {
-    let samples_per_symbol = 2; // Nyquist limit
+/// Interleaved I/Q samples taken by a block's 32-bit length header
+const HEADER_SAMPLES: usize = 32 * SAMPLES_PER_SYMBOL * 2;
 
-    let spec = WavSpec {
-      channels: 2, // I/Q channels for QAM
-      sample_rate: 8000,
-      bits_per_sample: 8,
-      sample_format: hound::SampleFormat::Int,
-    };
+/// Version of the format `encode` writes, in a preamble ahead of the first block.
+/// Version 0 started right with a block header, whose samples are never silent
+pub const FORMAT_VERSION: u32 = 1;
 
-    let mut cursor = Cursor::new(Vec::new());
-    let mut writer = WavWriter::new(&mut cursor, spec).unwrap();
-
-    // Header
-    let len = data.len() as u32;
-    for i in 0..32 {
-      let bit = ((len >> i) & 1) as i8;
-      let val = if bit == 1 { 127 } else { -127 };
-
-      // Repeat samples_per_symbol times (matching the data encoding)
-      for _ in 0..samples_per_symbol {
-        writer.write_sample(val).unwrap();  // I channel
-        writer.write_sample(val).unwrap();  // Q channel
-      }
-    }
+/// A silent I/Q pair, then the format version on both channels
+const PREAMBLE_SAMPLES: usize = 4;
 
-    // Each symbol encodes 4 bits (16-QAM constellation)
-    for chunk in data.chunks(1) {
-      let byte = chunk[0];
+/// Format version of the samples and where their first block starts
+fn read_preamble(samples: &[i8]) -> Result<(u32, usize)> {
+  let version = match samples {
+    [0, 0, version, _, ..] => *version as u8 as u32,
+    [0, 0, ..] => return Err(PipelineError::Wav("Not enough samples for the format version".into())),
+    _ => return Ok((0, 0)),
+  };
+  check_format(version, FORMAT_VERSION).map_err(|e| PipelineError::Wav(e.at(Offset::Sample(2))))?;
+  Ok((version, PREAMBLE_SAMPLES))
+}
 
-      // High nibble
-      let high = (byte >> 4) & 0x0F;
-      let (i_high, q_high) = qam16_map(high);
-      for _ in 0..samples_per_symbol {
-        writer.write_sample(i_high).unwrap();
-        writer.write_sample(q_high).unwrap();
-      }
+#[derive(Debug, Default)]
+pub struct WavTransform {
+  /// Modulate independent blocks of this many bytes in parallel
+  chunk_size: Option<usize>,
+}
 
-      // Low nibble
-      let low = byte & 0x0F;
-      let (i_low, q_low) = qam16_map(low);
-      for _ in 0..samples_per_symbol {
-        writer.write_sample(i_low).unwrap();
-        writer.write_sample(q_low).unwrap();
-      }
-    }
+impl WavTransform {
+  pub fn new() -> Self {
+    Self::default()
+  }
 
-    writer.finalize().unwrap();
-    Ok(cursor.into_inner())
+  pub fn chunk_size(mut self, bytes: Option<usize>) -> Self {
+    self.chunk_size = bytes;
+    self
   }
+}
 
-  fn decode(&self, data: Vec<u8>) -> Result<Vec<u8>> {
-    let mut reader = hound::WavReader::new(Cursor::new(data))
-      .map_err(|e| e.to_string())?;
+/// Interleaved I/Q samples for one block: a 32-bit length header, then the data
+fn modulate_block(data: &[u8]) -> Vec<i8> {
+  let mut samples = Vec::with_capacity(HEADER_SAMPLES + data.len() * 4 * SAMPLES_PER_SYMBOL);
 
-    let samples: Vec<i8> = reader.samples::<i8>()
-      .map(|s| s.map_err(|e| PipelineError::Wav(e.to_string())))
-      .collect::<Result<Vec<_>>>()
-      .map_err(|e| PipelineError::Wav(e.to_string()))?;
+  // Header
+  let len = data.len() as u32;
+  for i in 0..32 {
+    let bit = ((len >> i) & 1) as i8;
+    let val = if bit == 1 { 127 } else { -127 };
 
-    let samples_per_symbol = 2;
+    // Repeat samples_per_symbol times (matching the data encoding)
+    for _ in 0..SAMPLES_PER_SYMBOL {
+      samples.push(val);  // I channel
+      samples.push(val);  // Q channel
+    }
+  }
 
-    println!("Total samples: {}", samples.len());
+  // Each symbol encodes 4 bits (16-QAM constellation)
+  for &byte in data {
+    // High nibble
+    let high = (byte >> 4) & 0x0F;
+    let (i_high, q_high) = qam16_map(high);
+    for _ in 0..SAMPLES_PER_SYMBOL {
+      samples.push(i_high);
+      samples.push(q_high);
+    }
 
-    // Decode length from header
-    let mut len: u32 = 0;
-    for i in 0..32 {
-      let base_idx = i * samples_per_symbol * 2;
+    // Low nibble
+    let low = byte & 0x0F;
+    let (i_low, q_low) = qam16_map(low);
+    for _ in 0..SAMPLES_PER_SYMBOL {
+      samples.push(i_low);
+      samples.push(q_low);
+    }
+  }
 
-      // Average all I samples in this bit period
-      let mut sum = 0i32;
-      for s in 0..samples_per_symbol {
-        sum += samples[base_idx + s * 2] as i32;
-      }
-      let avg = sum / samples_per_symbol as i32;
+  samples
+}
 
-      let bit = if avg > 0 { 1 } else { 0 };
-      len |= bit << i;
-    }
+/// Samples taken by each payload byte, two symbols of interleaved I/Q
+const BYTE_SAMPLES: usize = 2 * SAMPLES_PER_SYMBOL * 2;
 
-    // Calculate expected samples needed
-    let header_samples = 32 * samples_per_symbol * 2;
-    let data_samples = len as usize * 2 * samples_per_symbol * 2; // 2 symbols per byte
-    let expected_total = header_samples + data_samples;
-    
-    if samples.len() < expected_total {
-      return Err(PipelineError::Wav(format!(
-        "Not enough samples: have {}, need {}",
-        samples.len(), expected_total
-      )));
+/// Size of the RIFF/fmt/data headers hound writes
+const WAV_HEADER_BYTES: u64 = 44;
+
+/// Payload length from the header of the block starting at `start`, if the header is all there
+fn block_len(samples: &[i8], start: usize) -> Option<u32> {
+  if samples.len() < start + HEADER_SAMPLES {
+    return None;
+  }
+
+  // Decode length from header
+  let mut len: u32 = 0;
+  for i in 0..32 {
+    let base_idx = start + i * SAMPLES_PER_SYMBOL * 2;
+
+    // Average all I samples in this bit period
+    let mut sum = 0i32;
+    for s in 0..SAMPLES_PER_SYMBOL {
+      sum += samples[base_idx + s * 2] as i32;
     }
+    let avg = sum / SAMPLES_PER_SYMBOL as i32;
 
-    let mut output = Vec::with_capacity(len as usize);
-    let mut idx = header_samples;
+    let bit = if avg > 0 { 1 } else { 0 };
+    len |= bit << i;
+  }
+  Some(len)
+}
 
-    for byte_num in 0..len {
-      let mut byte = 0u8;
+/// Read the length header of the block starting at `start`, returning (payload length, block end)
+fn block_bounds(samples: &[i8], start: usize) -> Result<(u32, usize)> {
+  let len = block_len(samples, start).ok_or_else(|| PipelineError::Wav(Detail::from(format!(
+    "Not enough samples for a block header, have {}",
+    samples.len()
+  )).at(Offset::Sample(start as u64))))?;
+
+  // Calculate expected samples needed
+  let data_samples = len as usize * BYTE_SAMPLES;
+  let expected_total = start + HEADER_SAMPLES + data_samples;
+
+  if samples.len() < expected_total {
+    return Err(PipelineError::Wav(Detail::from(format!(
+      "Not enough samples: have {}, need {}",
+      samples.len(), expected_total
+    )).at(Offset::Sample(samples.len() as u64))));
+  }
+  Ok((len, expected_total))
+}
 
-      // Decode high nibble - average samples
-      if idx + samples_per_symbol * 2 > samples.len() {
-        return Err(PipelineError::Wav(format!(
-          "Out of bounds at byte {} high nibble: idx={}, len={}",
-          byte_num, idx, samples.len()
-        )));
-      }
+/// Average the samples of one symbol starting at `idx` and map them back to a nibble
+fn demodulate_symbol(samples: &[i8], idx: usize) -> u8 {
+  let mut i_sum = 0i32;
+  let mut q_sum = 0i32;
+  for s in 0..SAMPLES_PER_SYMBOL {
+    i_sum += samples[idx + s * 2] as i32;
+    q_sum += samples[idx + s * 2 + 1] as i32;
+  }
+  let i = (i_sum / SAMPLES_PER_SYMBOL as i32) as i8;
+  let q = (q_sum / SAMPLES_PER_SYMBOL as i32) as i8;
+  qam16_demap(i, q)
+}
 
-      let mut i_sum = 0i32;
-      let mut q_sum = 0i32;
-      for s in 0..samples_per_symbol {
-        i_sum += samples[idx + s * 2] as i32;
-        q_sum += samples[idx + s * 2 + 1] as i32;
-      }
-      let i_high = (i_sum / samples_per_symbol as i32) as i8;
-      let q_high = (q_sum / samples_per_symbol as i32) as i8;
+/// First `len` payload bytes of the block starting at `start`, which the samples must cover
+fn demodulate_block(samples: &[i8], start: usize, len: u32) -> Vec<u8> {
+  let mut output = Vec::with_capacity(len as usize);
+  let mut idx = start + HEADER_SAMPLES;
 
-      let high_nibble = qam16_demap(i_high, q_high);
-      byte |= high_nibble << 4;
+  for _ in 0..len {
+    let high_nibble = demodulate_symbol(samples, idx);
+    idx += SAMPLES_PER_SYMBOL * 2;
+    let low_nibble = demodulate_symbol(samples, idx);
+    idx += SAMPLES_PER_SYMBOL * 2;
 
-      idx += samples_per_symbol * 2;
+    output.push((high_nibble << 4) | low_nibble);
+  }
 
-      // Decode low nibble - average samples
-      if idx + samples_per_symbol * 2 > samples.len() {
-        return Err(
-          PipelineError::Wav(format!(
-            "Out of bounds at byte {} low nibble: idx={}, len={}, bytes decoded so far: {}",
-            byte_num, idx, samples.len(), output.len()
-          )));
-      }
+  output
+}
 
-      let mut i_sum = 0i32;
-      let mut q_sum = 0i32;
-      for s in 0..samples_per_symbol {
-        i_sum += samples[idx + s * 2] as i32;
-        q_sum += samples[idx + s * 2 + 1] as i32;
-      }
-      let i_low = (i_sum / samples_per_symbol as i32) as i8;
-      let q_low = (q_sum / samples_per_symbol as i32) as i8;
+impl Transform for WavTransform {
+  fn encode(&self, data: Vec<u8>) -> Result<Vec<u8>> {
+    let spec = WavSpec {
+      channels: 2, // I/Q channels for QAM
+      sample_rate: 8000,
+      bits_per_sample: 8,
+      sample_format: hound::SampleFormat::Int,
+    };
 
-      let low_nibble = qam16_demap(i_low, q_low);
-      byte |= low_nibble;
+    // Blocks just follow each other, an unchunked file is a single block
+    let blocks = match self.chunk_size {
+      Some(chunk_size) if !data.is_empty() => data
+        .par_chunks(chunk_size.max(1))
+        .map(modulate_block)
+        .collect(),
+      _ => vec![modulate_block(&data)],
+    };
 
-      idx += samples_per_symbol * 2;
+    let version = FORMAT_VERSION as i8;
+    let preamble = [0, 0, version, version];
 
-      output.push(byte);
+    let mut cursor = Cursor::new(Vec::new());
+    let mut writer = WavWriter::new(&mut cursor, spec)
+      .map_err(|e| PipelineError::Wav(Detail::wrap(e)))?;
+    for sample in preamble.iter().chain(blocks.iter().flatten()) {
+      writer.write_sample(*sample)
+        .map_err(|e| PipelineError::Wav(Detail::wrap(e)))?;
     }
 
-    Ok(output)
+    writer.finalize()
+      .map_err(|e| PipelineError::Wav(Detail::wrap(e)))?;
+    Ok(cursor.into_inner())
+  }
+
+  fn decode(&self, data: Vec<u8>) -> Result<Vec<u8>> {
+    let mut reader = hound::WavReader::new(Cursor::new(data))
+      .map_err(|e| PipelineError::Wav(Detail::wrap(e)))?;
+
+    let samples: Vec<i8> = reader.samples::<i8>()
+      .enumerate()
+      .map(|(i, s)| s.map_err(|e| PipelineError::Wav(Detail::wrap(e).at(Offset::Sample(i as u64)))))
+      .collect::<Result<Vec<_>>>()?;
+
+    // Only the headers have to be read in order, the blocks themselves decode in parallel
+    let mut blocks = Vec::new();
+    let (_, mut start) = read_preamble(&samples)?;
+    loop {
+      let (len, end) = block_bounds(&samples, start)?;
+      blocks.push((start, len));
+      start = end;
+      if samples.len() < start + HEADER_SAMPLES {
+        break;
+      }
+    }
+
+    let decoded: Vec<Vec<u8>> = blocks
+      .par_iter()
+      .map(|&(start, len)| demodulate_block(&samples, start, len))
+      .collect();
+    Ok(decoded.concat())
   }
 
   fn name(&self) -> &str {
     "WAV Audio"
   }
 
+  fn decode_partial(&self, data: Vec<u8>) -> Result<Partial> {
+    let mut reader = hound::WavReader::new(Cursor::new(data))
+      .map_err(|e| PipelineError::Wav(Detail::wrap(e)))?;
+
+    // A cut off file runs out of samples before the header says it should
+    let expected = reader.len() as usize;
+    let samples: Vec<i8> = reader.samples::<i8>().map_while(|s| s.ok()).collect();
+    let mut complete = samples.len() == expected;
+
+    let mut out = Vec::new();
+    let (_, first) = read_preamble(&samples)?;
+    let mut start = first;
+    while let Some(len) = block_len(&samples, start) {
+      let available = (samples.len() - start - HEADER_SAMPLES) / BYTE_SAMPLES;
+      out.extend(demodulate_block(&samples, start, len.min(available as u32)));
+      if (len as usize) > available {
+        complete = false;
+        break;
+      }
+      start += HEADER_SAMPLES + len as usize * BYTE_SAMPLES;
+    }
+    if samples.len() < first + HEADER_SAMPLES {
+      return Err(PipelineError::Wav("Not enough samples for the length header".into()));
+    }
+    Ok(Partial { data: out, complete })
+  }
+
+  fn estimate(&self, input_bytes: u64, _sample: &[u8]) -> Result<Estimate> {
+    // One byte per 8-bit sample
+    let blocks = match self.chunk_size {
+      Some(chunk_size) if input_bytes > 0 => input_bytes.div_ceil(chunk_size.max(1) as u64),
+      _ => 1,
+    };
+    Ok(Estimate {
+      bytes: WAV_HEADER_BYTES + PREAMBLE_SAMPLES as u64 + blocks * HEADER_SAMPLES as u64
+        + input_bytes * BYTE_SAMPLES as u64,
+      model: "exact",
+    })
+  }
+
+  fn capacity(&self) -> Option<u64> {
+    // The RIFF data chunk size is a u32
+    Some((u32::MAX as u64 - WAV_HEADER_BYTES - PREAMBLE_SAMPLES as u64 - HEADER_SAMPLES as u64) / BYTE_SAMPLES as u64)
+  }
+
+  fn sniff(&self, data: &[u8]) -> Option<String> {
+    let reader = hound::WavReader::new(Cursor::new(data)).ok()?;
+    let spec = reader.spec();
+    if spec.channels != 2 || spec.bits_per_sample != 8 || spec.sample_format != hound::SampleFormat::Int {
+      return None;
+    }
+    Some(format!(
+      "WAV, 16-QAM on 2 channels at {} Hz, {} samples",
+      spec.sample_rate,
+      reader.len(),
+    ))
+  }
+
+  fn stats(&self, encoded: &[u8]) -> Vec<(&'static str, u64)> {
+    match hound::WavReader::new(Cursor::new(encoded)) {
+      Ok(reader) => vec![("total_samples", reader.len() as u64)],
+      Err(_) => vec![],
+    }
+  }
+
   fn extension(&self) -> &str {
     "wav"
   }
+
+  fn output_kind(&self) -> Kind {
+    Kind::Wav
+  }
 }
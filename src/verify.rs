@@ -0,0 +1,110 @@
+//! Check that a round trip gave back the input. Images are compared pixel by pixel, since the
+//! image stage hands back an RGBA PNG rather than the original file, anything else by SHA-256
+use crate::error::Result;
+use ::image::RgbaImage;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::Path;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Comparison {
+  /// Both are images of the same size
+  Pixels {
+    width: u32,
+    height: u32,
+    /// Pixels with any RGBA channel off
+    differing_pixels: u64,
+    /// Largest difference of any channel
+    max_difference: u8,
+    /// Peak signal-to-noise ratio over all channels, `None` for identical pixels
+    psnr_db: Option<f64>,
+  },
+  /// Both are images, of different sizes
+  Dimensions {
+    original: [u32; 2],
+    decoded: [u32; 2],
+  },
+  /// At least one isn't an image
+  Bytes {
+    original_bytes: u64,
+    decoded_bytes: u64,
+    original_sha256: String,
+    decoded_sha256: String,
+  },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Verification {
+  pub original: String,
+  pub decoded: String,
+  pub matches: bool,
+  pub comparison: Comparison,
+}
+
+pub fn verify(original: &Path, decoded: &Path) -> Result<Verification> {
+  let comparison = compare(&fs::read(original)?, &fs::read(decoded)?);
+  Ok(Verification {
+    original: original.display().to_string(),
+    decoded: decoded.display().to_string(),
+    matches: comparison.matches(),
+    comparison,
+  })
+}
+
+pub fn compare(original: &[u8], decoded: &[u8]) -> Comparison {
+  let image = |data| ::image::load_from_memory(data).ok().map(|image| image.to_rgba8());
+  match (image(original), image(decoded)) {
+    (Some(original), Some(decoded)) => compare_pixels(&original, &decoded),
+    _ => Comparison::Bytes {
+      original_bytes: original.len() as u64,
+      decoded_bytes: decoded.len() as u64,
+      original_sha256: sha256(original),
+      decoded_sha256: sha256(decoded),
+    },
+  }
+}
+
+fn compare_pixels(original: &RgbaImage, decoded: &RgbaImage) -> Comparison {
+  if original.dimensions() != decoded.dimensions() {
+    return Comparison::Dimensions {
+      original: original.dimensions().into(),
+      decoded: decoded.dimensions().into(),
+    };
+  }
+  let mut differing_pixels = 0;
+  let mut max_difference = 0;
+  let mut squared_error = 0u64;
+  for (a, b) in original.pixels().zip(decoded.pixels()) {
+    if a != b {
+      differing_pixels += 1;
+    }
+    for (a, b) in a.0.iter().zip(b.0) {
+      let difference = a.abs_diff(b);
+      max_difference = max_difference.max(difference);
+      squared_error += difference as u64 * difference as u64;
+    }
+  }
+  let (width, height) = original.dimensions();
+  let channels = width as f64 * height as f64 * 4.0;
+  let psnr_db = (squared_error > 0).then(|| {
+    let mse = squared_error as f64 / channels;
+    10.0 * (255.0 * 255.0 / mse).log10()
+  });
+  Comparison::Pixels { width, height, differing_pixels, max_difference, psnr_db }
+}
+
+fn sha256(data: &[u8]) -> String {
+  format!("{:x}", Sha256::digest(data))
+}
+
+impl Comparison {
+  pub fn matches(&self) -> bool {
+    match self {
+      Comparison::Pixels { differing_pixels, .. } => *differing_pixels == 0,
+      Comparison::Dimensions { .. } => false,
+      Comparison::Bytes { original_sha256, decoded_sha256, .. } => original_sha256 == decoded_sha256,
+    }
+  }
+}
@@ -0,0 +1,50 @@
+//! Batch runs give every file a folder of its own
+use std::fs;
+use std::path::Path;
+use wackypixels::batch::Batch;
+use wackypixels::cli::TransformOptions;
+use wackypixels::pipeline::Pipeline;
+use wackypixels::pipeline_builder::builtin;
+use wackypixels::report::{Direction, Reporter};
+
+fn gzip() -> Pipeline {
+  let transform = (builtin("gzip").unwrap().build)(&TransformOptions::default());
+  Pipeline::new().add_boxed(transform).reporter(Reporter::quiet())
+}
+
+fn batch<'a>(input_dir: &'a Path, pattern: &'a str, output_dir: &'a Path) -> Batch<'a> {
+  Batch { input_dir, pattern, output_dir, jobs: Some(2) }
+}
+
+#[test]
+fn same_stem_gets_separate_folders() {
+  let dir = tempfile::tempdir().unwrap();
+  let input = dir.path().join("imgs");
+  fs::create_dir(&input).unwrap();
+  fs::write(input.join("cat.txt"), "text").unwrap();
+  fs::write(input.join("cat.bin"), [0, 1, 2]).unwrap();
+
+  let encoded = dir.path().join("out");
+  batch(&input, "*", &encoded).run(&gzip(), Direction::Encode, None, Reporter::quiet()).unwrap();
+  let decoded = dir.path().join("back");
+  batch(&encoded, "encrypted.*", &decoded).run(&gzip(), Direction::Decode, None, Reporter::quiet()).unwrap();
+
+  assert_eq!(fs::read(decoded.join("cat.txt/decrypted.png")).unwrap(), b"text");
+  assert_eq!(fs::read(decoded.join("cat.bin/decrypted.png")).unwrap(), [0, 1, 2]);
+}
+
+#[test]
+fn files_sharing_a_folder_fail() {
+  let dir = tempfile::tempdir().unwrap();
+  let input = dir.path().join("out/cat.png");
+  fs::create_dir_all(&input).unwrap();
+  fs::write(input.join("encrypted.gz"), "one").unwrap();
+  fs::write(input.join("encrypted.wav"), "two").unwrap();
+
+  let output = dir.path().join("back");
+  let error = batch(&dir.path().join("out"), "encrypted.*", &output)
+    .run(&gzip(), Direction::Decode, None, Reporter::quiet())
+    .unwrap_err();
+  assert!(error.to_string().ends_with("2 of 2 files failed"));
+  assert!(!output.join("cat.png").exists());
+}
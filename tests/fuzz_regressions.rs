@@ -0,0 +1,30 @@
+//! Inputs the fuzz targets once crashed on, replayed on stable. Each lives in
+//! `fuzz/regressions/<transform>/`, named after the `decode_<transform>` target that found it
+use std::fs;
+use std::path::Path;
+use wackypixels::cli::TransformOptions;
+use wackypixels::pipeline_builder::builtin;
+
+#[test]
+fn decoders_survive_past_crashes() {
+  let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz/regressions");
+  let mut replayed = 0;
+  for dir in fs::read_dir(&root).unwrap() {
+    let dir = dir.unwrap().path();
+    let id = dir.file_name().unwrap().to_str().unwrap();
+    let transform = (builtin(id).unwrap_or_else(|| panic!("{} isn't a built-in transform", id)).build)(
+      &TransformOptions::default(),
+    );
+    for input in fs::read_dir(&dir).unwrap() {
+      let input = input.unwrap().path();
+      let data = fs::read(&input).unwrap();
+      // Errors are expected, getting here without a panic is the point
+      let _ = transform.decode(data.clone());
+      let _ = transform.decode_partial(data.clone());
+      let _ = transform.sniff(&data);
+      let _ = transform.stats(&data);
+      replayed += 1;
+    }
+  }
+  assert!(replayed > 0, "no regression inputs in {}", root.display());
+}
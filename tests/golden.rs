@@ -0,0 +1,218 @@
+//! Encoded outputs pinned byte for byte, so a change to any format shows up here and has to
+//! be made on purpose. Rewrite them with `UPDATE_GOLDEN=1 cargo test --test golden`.
+//! Outputs of older format versions live in `tests/fixtures/v<version>/` and must keep decoding
+use std::fs;
+use std::path::{Path, PathBuf};
+use wackypixels::cli::TransformOptions;
+use wackypixels::pipeline::Pipeline;
+use wackypixels::pipeline_builder::{DEFAULT_PIPELINE, builtin};
+use wackypixels::report::Reporter;
+use wackypixels::runs::MARKER;
+use wackypixels::transforms::pdf::PdfStrategy;
+
+/// Every byte value, then some text, so all unicode modes and QAM symbols show up
+fn input() -> Vec<u8> {
+  let mut data: Vec<u8> = (0..=255).collect();
+  data.extend_from_slice(b"The quick brown fox jumps over the lazy dog");
+  data
+}
+
+/// Raw 3x2 RGBA in the unversioned layout, which the image stage still reads
+fn raw_image() -> Vec<u8> {
+  let mut data = vec![3, 0, 0, 0, 2, 0, 0, 0, 4];
+  data.extend((0..24).map(|i| (i * 10) as u8));
+  data
+}
+
+struct Case {
+  name: &'static str,
+  id: &'static str,
+  options: TransformOptions,
+}
+
+fn cases() -> Vec<Case> {
+  // Seeded, so the PDF file identifier is the same every time
+  let default = || TransformOptions { seed: Some(0), ..TransformOptions::default() };
+  let chunked = || TransformOptions { chunk_size: Some(100), ..default() };
+  let pdf = |pdf_strategy| TransformOptions { pdf_strategy, ..default() };
+  vec![
+    Case { name: "lzma", id: "lzma", options: default() },
+    Case { name: "lzma_chunked", id: "lzma", options: chunked() },
+    Case { name: "gzip", id: "gzip", options: default() },
+    Case { name: "unicode", id: "unicode", options: default() },
+    Case { name: "unicode_chunked", id: "unicode", options: chunked() },
+    Case {
+      name: "unicode_split",
+      id: "unicode",
+      options: TransformOptions { unicode_wrap: Some(20), unicode_split: Some(100), ..default() },
+    },
+    Case { name: "wav", id: "wav", options: default() },
+    Case { name: "wav_chunked", id: "wav", options: chunked() },
+    Case { name: "pdf_info", id: "pdf", options: pdf(PdfStrategy::Info) },
+    Case { name: "pdf_attachment", id: "pdf", options: pdf(PdfStrategy::Attachment) },
+    Case { name: "pdf_xmp", id: "pdf", options: pdf(PdfStrategy::Xmp) },
+    Case { name: "pdf_image", id: "pdf", options: pdf(PdfStrategy::Image) },
+    Case { name: "pdf_text", id: "pdf", options: pdf(PdfStrategy::Text) },
+    Case {
+      name: "pdf_compressed",
+      id: "pdf",
+      options: TransformOptions { pdf_compress: true, pdf_object_streams: true, ..default() },
+    },
+  ]
+}
+
+fn golden_path(name: &str, extension: &str) -> PathBuf {
+  PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/golden").join(format!("{}.{}", name, extension))
+}
+
+/// Compare `actual` to the golden file, or write it when updating
+fn check(name: &str, extension: &str, actual: &[u8]) {
+  let path = golden_path(name, extension);
+  if std::env::var_os("UPDATE_GOLDEN").is_some() {
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(&path, actual).unwrap();
+    return;
+  }
+  let expected = fs::read(&path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+  assert!(
+    expected == actual,
+    "{} changed ({} bytes, was {}). If that's intended, rerun with UPDATE_GOLDEN=1",
+    path.display(), actual.len(), expected.len(),
+  );
+}
+
+#[test]
+fn transforms_match_golden_files() {
+  for case in cases() {
+    let transform = (builtin(case.id).unwrap().build)(&case.options);
+    let encoded = transform.encode(input()).unwrap();
+    check(case.name, transform.extension(), &encoded);
+
+    let golden = fs::read(golden_path(case.name, transform.extension())).unwrap();
+    assert_eq!(transform.decode(golden).unwrap(), input(), "{} doesn't decode", case.name);
+  }
+}
+
+#[test]
+fn image_matches_golden_file() {
+  let transform = (builtin("image").unwrap().build)(&TransformOptions::default());
+  let png = transform.decode(raw_image()).unwrap();
+  check("image", "png", &png);
+  let raw = transform.encode(png.clone()).unwrap();
+  check("image", "bin", &raw);
+  assert_eq!(transform.decode(raw).unwrap(), png);
+}
+
+/// Every file of a full pipeline run, intermediates included, by name
+fn encode_all(options: &TransformOptions, output: &Path) -> Vec<(String, Vec<u8>)> {
+  let pipeline = DEFAULT_PIPELINE.iter()
+    .map(|id| (builtin(id).unwrap().build)(options))
+    .fold(Pipeline::new(), Pipeline::add_boxed)
+    .save_intermediates(true)
+    .reporter(Reporter::quiet());
+  pipeline.encode(&golden_path("image", "png"), output).unwrap();
+  let mut files: Vec<_> = fs::read_dir(output).unwrap()
+    .map(|entry| entry.unwrap().path())
+    .filter(|path| !path.ends_with(MARKER))
+    .map(|path| (path.file_name().unwrap().to_string_lossy().into_owned(), fs::read(&path).unwrap()))
+    .collect();
+  files.sort();
+  files
+}
+
+#[test]
+fn seeded_pipeline_is_reproducible() {
+  let dir = tempfile::tempdir().unwrap();
+  let seeded = |seed| TransformOptions { seed: Some(seed), ..TransformOptions::default() };
+  let first = encode_all(&seeded(42), &dir.path().join("first"));
+  let second = encode_all(&seeded(42), &dir.path().join("second"));
+  assert_eq!(first.len(), DEFAULT_PIPELINE.len() + 1, "expected every intermediate and the final file");
+  for ((name, first), (_, second)) in first.iter().zip(&second) {
+    assert!(first == second, "{} differs between runs with the same seed", name);
+  }
+  // And across machines and builds
+  let (_, last) = first.iter().find(|(name, _)| name.starts_with("encrypted")).unwrap();
+  check("pipeline_seeded", "wav", last);
+
+  let other = encode_all(&seeded(43), &dir.path().join("other"));
+  assert_ne!(first, other, "the seed should change the PDF file identifier");
+}
+
+fn fixtures() -> PathBuf {
+  PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures")
+}
+
+/// Built-in transform a golden file was made with, from the start of its name
+fn transform_of(path: &Path) -> &str {
+  let stem = path.file_stem().unwrap().to_str().unwrap();
+  stem.split('_').next().unwrap()
+}
+
+#[test]
+fn older_formats_still_decode() {
+  let png = fs::read(golden_path("image", "png")).unwrap();
+  let mut decoded = 0;
+  for version in fs::read_dir(fixtures()).unwrap() {
+    for fixture in fs::read_dir(version.unwrap().path()).unwrap() {
+      let fixture = fixture.unwrap().path();
+      let id = transform_of(&fixture);
+      let transform = (builtin(id).unwrap().build)(&TransformOptions::default());
+      let output = transform.decode(fs::read(&fixture).unwrap())
+        .unwrap_or_else(|e| panic!("{}: {}", fixture.display(), e));
+      // Image data decodes to a PNG, everything else back to the input
+      let expected = if id == "image" { &png } else { &input() };
+      assert!(&output == expected, "{} decodes to something else", fixture.display());
+      decoded += 1;
+    }
+  }
+  assert!(decoded > 0, "no fixtures in {}", fixtures().display());
+}
+
+/// A bare `.xz` stream from before the `WPXZ` header still reads as version 0
+#[test]
+fn unmarked_lzma_is_version_0() {
+  let transform = (builtin("lzma").unwrap().build)(&TransformOptions::default());
+  for name in ["lzma.xz", "lzma_chunked.xz"] {
+    let fixture = fs::read(fixtures().join("v0").join(name)).unwrap();
+    assert!(fixture.starts_with(b"\xFD7zXZ\0"), "{} should be a bare xz stream", name);
+    assert_eq!(transform.decode(fixture.clone()).unwrap(), input(), "{}", name);
+    assert_eq!(transform.decode_partial(fixture).unwrap().data, input(), "{}", name);
+  }
+}
+
+/// Replace the first `from` in `data` with `to`
+fn patch(data: &[u8], from: &[u8], to: &[u8]) -> Vec<u8> {
+  let at = data.windows(from.len()).position(|window| window == from).expect("nothing to patch");
+  [&data[..at], to, &data[at + from.len()..]].concat()
+}
+
+#[test]
+fn newer_formats_are_rejected() {
+  let wav = fs::read(golden_path("wav", "wav")).unwrap();
+  // 8-bit samples are stored offset by 128, the version is the third
+  let data = wav.windows(4).position(|window| window == b"data").unwrap() + 8;
+  let mut future_wav = wav.clone();
+  future_wav[data + 2] += 1;
+
+  let future = [
+    ("image", patch(&fs::read(golden_path("image", "bin")).unwrap(), b"WPIM\x01", b"WPIM\x02")),
+    ("lzma", patch(&fs::read(golden_path("lzma", "xz")).unwrap(), b"WPXZ\x01", b"WPXZ\x02")),
+    ("gzip", patch(&fs::read(golden_path("gzip", "gz")).unwrap(), b"WP\x01\x00\x01", b"WP\x01\x00\x02")),
+    ("unicode", patch(&fs::read(golden_path("unicode", "txt")).unwrap(), "\u{2062}\u{FE01}".as_bytes(), "\u{2062}\u{FE02}".as_bytes())),
+    ("pdf", patch(&fs::read(golden_path("pdf_info", "pdf")).unwrap(), b"/WackyPixelsVersion 1", b"/WackyPixelsVersion 2")),
+    ("wav", future_wav),
+  ];
+  for (id, data) in future {
+    let transform = (builtin(id).unwrap().build)(&TransformOptions::default());
+    let error = transform.decode(data.clone()).expect_err(id).to_string();
+    assert!(error.contains("newer wackypixels"), "{}: {}", id, error);
+    let error = transform.decode_partial(data).expect_err(id).to_string();
+    assert!(error.contains("newer wackypixels"), "{}: {}", id, error);
+  }
+
+  // A cut off PDF doesn't load, the version has to come from scanning it
+  let pdf = patch(&fs::read(golden_path("pdf_info", "pdf")).unwrap(), b"/WackyPixelsVersion 1", b"/WackyPixelsVersion 2");
+  let transform = (builtin("pdf").unwrap().build)(&TransformOptions::default());
+  let error = transform.decode_partial(pdf[..pdf.len() * 3 / 4].to_vec()).unwrap_err().to_string();
+  assert!(error.contains("newer wackypixels"), "{}", error);
+}
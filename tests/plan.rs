@@ -0,0 +1,34 @@
+//! `plan`'s size estimates against what the transforms actually write
+use wackypixels::cli::TransformOptions;
+use wackypixels::pipeline_builder::builtin;
+use wackypixels::transforms::pdf::{PdfEncryption, PdfStrategy};
+
+/// The PDF model accounts for every strategy's layout, within AES padding and the odd digit
+#[test]
+fn pdf_estimates_follow_the_layout() {
+  let strategies = [PdfStrategy::Info, PdfStrategy::Attachment, PdfStrategy::Xmp, PdfStrategy::Image, PdfStrategy::Text];
+  for pdf_strategy in strategies {
+    for pdf_encrypt in [None, Some(PdfEncryption::Aes256)] {
+      let options = TransformOptions {
+        pdf_strategy,
+        pdf_encrypt,
+        pdf_password: pdf_encrypt.map(|_| "secret".to_string()),
+        ..Default::default()
+      };
+      let transform = (builtin("pdf").unwrap().build)(&options);
+      for len in [0, 5_000, 40_000] {
+        let data: Vec<u8> = (0..len as u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8).collect();
+        let actual = transform.encode(data.clone()).unwrap().len() as u64;
+        let estimate = transform.estimate(len as u64, &data[..len.min(1000)]).unwrap();
+        assert_eq!(estimate.model, "analytic");
+        assert!(
+          estimate.bytes.abs_diff(actual) <= 32,
+          "{:?} {:?} {} bytes: estimated {}, wrote {}", pdf_strategy, pdf_encrypt, len, estimate.bytes, actual,
+        );
+      }
+    }
+  }
+
+  let compressed = (builtin("pdf").unwrap().build)(&TransformOptions { pdf_compress: true, ..Default::default() });
+  assert_eq!(compressed.estimate(5_000, &[0; 1000]).unwrap().model, "sampled");
+}
@@ -0,0 +1,259 @@
+//! `plugins/xor` built as a shared library and as a WASM module, loaded and run through a
+//! pipeline, plus the ways loading and running a plugin can go wrong
+#![cfg(any(feature = "native-plugins", feature = "wasm-plugins"))]
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::OnceLock;
+use wackypixels::cli::TransformOptions;
+use wackypixels::error::PipelineError;
+use wackypixels::registry::Registry;
+use wackypixels::report::Reporter;
+
+const WASM_TARGET: &str = "wasm32-unknown-unknown";
+
+/// Where plugins get built, apart from `target/` which the running `cargo test` holds
+fn build_dir() -> PathBuf {
+  PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("plugins")
+}
+
+fn run(command: &mut Command) {
+  let output = command.output().unwrap_or_else(|e| panic!("{:?}: {}", command, e));
+  assert!(output.status.success(), "{:?}: {}", command, String::from_utf8_lossy(&output.stderr));
+}
+
+/// `plugins/xor` for the host, or for `target`
+fn build_xor(target: Option<&str>) -> PathBuf {
+  let mut cargo = Command::new(env!("CARGO"));
+  cargo.args(["build", "--release", "-p", "wackypixels-xor", "--target-dir"]).arg(build_dir());
+  cargo.current_dir(env!("CARGO_MANIFEST_DIR"));
+  if let Some(target) = target {
+    cargo.args(["--target", target]);
+  }
+  run(&mut cargo);
+
+  let release = match target {
+    Some(target) => build_dir().join(target).join("release"),
+    None => build_dir().join("release"),
+  };
+  let name = match target {
+    Some(_) => "wackypixels_xor.wasm".to_string(),
+    None => format!("{}wackypixels_xor{}", std::env::consts::DLL_PREFIX, std::env::consts::DLL_SUFFIX),
+  };
+  release.join(name)
+}
+
+/// Compile a single file plugin with plain `rustc`
+fn build_source(name: &str, source: &str, target: Option<&str>) -> PathBuf {
+  let dir = build_dir().join("sources");
+  fs::create_dir_all(&dir).unwrap();
+  let source_path = dir.join(format!("{}.rs", name));
+  fs::write(&source_path, source).unwrap();
+  let output = match target {
+    Some(_) => dir.join(format!("{}.wasm", name)),
+    None => dir.join(format!("{}{}{}", std::env::consts::DLL_PREFIX, name, std::env::consts::DLL_SUFFIX)),
+  };
+
+  let mut rustc = Command::new(std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string()));
+  rustc.args(["--edition", "2024", "--crate-type", "cdylib", "-O", "-o"]).arg(&output).arg(&source_path);
+  if let Some(target) = target {
+    rustc.args(["--target", target]);
+  }
+  run(&mut rustc);
+  output
+}
+
+/// Building for WASM needs `rustup target add wasm32-unknown-unknown`
+fn wasm_target_installed() -> bool {
+  let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+  let installed = Command::new(rustc)
+    .args(["--print", "sysroot"])
+    .output()
+    .ok()
+    .map(|output| PathBuf::from(String::from_utf8_lossy(&output.stdout).trim()))
+    .is_some_and(|sysroot| sysroot.join("lib/rustlib").join(WASM_TARGET).is_dir());
+  if !installed {
+    eprintln!("Skipping, the {} target isn't installed", WASM_TARGET);
+  }
+  installed
+}
+
+fn native_xor() -> &'static Path {
+  static PATH: OnceLock<PathBuf> = OnceLock::new();
+  PATH.get_or_init(|| build_xor(None))
+}
+
+fn wasm_xor() -> &'static Path {
+  static PATH: OnceLock<PathBuf> = OnceLock::new();
+  PATH.get_or_init(|| build_xor(Some(WASM_TARGET)))
+}
+
+fn with_key(key: &str) -> TransformOptions {
+  TransformOptions { plugin_params: vec![format!("xor.key={}", key)], ..Default::default() }
+}
+
+/// Encode and decode a file through `xor,lzma,xor`, checking the plugin actually changed it
+fn round_trip(plugin: &Path) {
+  let registry = Registry::with_plugins(&[plugin.to_path_buf()]).unwrap();
+  let ids = ["xor", "lzma", "xor"].map(String::from);
+  let pipeline = registry.pipeline(Some(&ids), &with_key("7")).unwrap().reporter(Reporter::quiet());
+
+  let xor = registry.build("xor", &with_key("7")).unwrap();
+  assert_eq!(xor.encode(b"abc".to_vec()).unwrap(), [b'a' ^ 7, b'b' ^ 7, b'c' ^ 7]);
+
+  let dir = tempfile::tempdir().unwrap();
+  let input = dir.path().join("input.bin");
+  let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+  fs::write(&input, &data).unwrap();
+  let encoded = pipeline.encode(&input, &dir.path().join("encoded")).unwrap();
+  let decoded = pipeline.decode(&encoded, &dir.path().join("decoded"), None).unwrap();
+  assert_eq!(fs::read(decoded).unwrap(), data);
+}
+
+/// A bad key is the plugin's own error, reported as such
+fn rejects_bad_key(plugin: &Path) {
+  let registry = Registry::with_plugins(&[plugin.to_path_buf()]).unwrap();
+  let error = registry.build("xor", &with_key("300")).unwrap().encode(vec![1, 2, 3]).unwrap_err();
+  assert!(matches!(error, PipelineError::Plugin(_)), "{:?}", error);
+  assert!(error.to_string().contains("key must be 0-255"), "{}", error);
+}
+
+fn load_error(path: &Path) -> String {
+  let error = Registry::with_plugins(&[path.to_path_buf()]).map(|_| ()).unwrap_err();
+  assert!(matches!(error, PipelineError::Plugin(_)), "{:?}", error);
+  error.to_string()
+}
+
+#[cfg(feature = "native-plugins")]
+#[test]
+fn native_plugin_round_trips() {
+  round_trip(native_xor());
+  rejects_bad_key(native_xor());
+}
+
+#[cfg(feature = "wasm-plugins")]
+#[test]
+fn wasm_plugin_round_trips() {
+  if !wasm_target_installed() {
+    return;
+  }
+  round_trip(wasm_xor());
+  rejects_bad_key(wasm_xor());
+}
+
+#[cfg(feature = "native-plugins")]
+#[test]
+fn native_plugin_without_entry_point() {
+  let path = build_source("no_entry_point", r#"
+    #[unsafe(no_mangle)]
+    pub extern "C" fn something_else() -> u32 { 1 }
+  "#, None);
+  let error = load_error(&path);
+  assert!(error.contains("wackypixels_plugin"), "{}", error);
+}
+
+#[cfg(feature = "native-plugins")]
+#[test]
+fn native_plugin_from_another_abi() {
+  let path = build_source("abi_mismatch", r#"
+    use std::ffi::c_char;
+
+    #[repr(C)]
+    pub struct PluginBuffer { ptr: *mut u8, len: usize, cap: usize }
+
+    type TransformFn = unsafe extern "C" fn(*const u8, usize, *const c_char, *mut PluginBuffer) -> i32;
+
+    #[repr(C)]
+    pub struct PluginVTable {
+      abi_version: u32,
+      info: *const c_char,
+      encode: TransformFn,
+      decode: TransformFn,
+      free: unsafe extern "C" fn(PluginBuffer),
+    }
+
+    unsafe impl Sync for PluginVTable {}
+
+    unsafe extern "C" fn run(_: *const u8, _: usize, _: *const c_char, _: *mut PluginBuffer) -> i32 { 1 }
+    unsafe extern "C" fn free(_: PluginBuffer) {}
+
+    static VTABLE: PluginVTable = PluginVTable {
+      abi_version: 999,
+      info: c"{}".as_ptr(),
+      encode: run,
+      decode: run,
+      free,
+    };
+
+    #[unsafe(no_mangle)]
+    pub extern "C" fn wackypixels_plugin() -> *const PluginVTable { &VTABLE }
+  "#, None);
+  let error = load_error(&path);
+  assert!(error.contains("built for plugin ABI 999"), "{}", error);
+}
+
+#[cfg(feature = "wasm-plugins")]
+#[test]
+fn wasm_plugin_that_traps() {
+  if !wasm_target_installed() {
+    return;
+  }
+  let path = build_source("trap", r##"
+    const INFO: &str = r#"{"id":"trap","name":"Trap","extension":"trap","description":"Traps"}"#;
+
+    #[unsafe(no_mangle)]
+    pub extern "C" fn wp_alloc(len: i32) -> i32 {
+      Vec::<u8>::with_capacity(len as usize).leak().as_mut_ptr() as i32
+    }
+
+    #[unsafe(no_mangle)]
+    pub extern "C" fn wp_info() -> i64 {
+      ((INFO.as_ptr() as i64) << 32) | INFO.len() as i64
+    }
+
+    #[unsafe(no_mangle)]
+    pub extern "C" fn wp_encode(_: i32, _: i32, _: i32, _: i32) -> i64 {
+      core::arch::wasm32::unreachable()
+    }
+
+    #[unsafe(no_mangle)]
+    pub extern "C" fn wp_decode(_: i32, _: i32, _: i32, _: i32) -> i64 {
+      core::arch::wasm32::unreachable()
+    }
+  "##, Some(WASM_TARGET));
+
+  let registry = Registry::with_plugins(std::slice::from_ref(&path)).unwrap();
+  let transform = registry.build("trap", &TransformOptions::default()).unwrap();
+  let error = transform.encode(vec![1, 2, 3]).unwrap_err();
+  assert!(matches!(error, PipelineError::Plugin(_)), "{:?}", error);
+  assert!(error.to_string().contains(&path.display().to_string()), "{}", error);
+  assert!(transform.decode(vec![1, 2, 3]).is_err());
+}
+
+#[cfg(feature = "wasm-plugins")]
+#[test]
+fn wasm_module_that_is_not_a_plugin() {
+  if !wasm_target_installed() {
+    return;
+  }
+  let path = build_source("not_a_plugin", r#"
+    #[unsafe(no_mangle)]
+    pub extern "C" fn add(a: i32, b: i32) -> i32 { a + b }
+  "#, Some(WASM_TARGET));
+  let error = load_error(&path);
+  assert!(error.contains("wp_info"), "{}", error);
+}
+
+#[cfg(feature = "wasm-plugins")]
+#[test]
+fn wasm_plugin_returning_a_region_past_its_memory() {
+  if !wasm_target_installed() {
+    return;
+  }
+  let path = build_source("oversized_info", r#"
+    #[unsafe(no_mangle)]
+    pub extern "C" fn wp_info() -> i64 { 0xffff_ffff }
+  "#, Some(WASM_TARGET));
+  let error = load_error(&path);
+  assert!(error.contains("past the end of its"), "{}", error);
+}
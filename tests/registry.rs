@@ -0,0 +1,26 @@
+//! Built-in transforms as `list` describes them
+use clap::{Args, Command};
+use wackypixels::cli::TransformOptions;
+use wackypixels::pipeline_builder::BUILTINS;
+use wackypixels::registry::Registry;
+
+/// `Builtin.flags` is kept by hand, a typo or a renamed option would only panic once `list` runs
+#[test]
+fn builtin_flags_are_transform_options() {
+  let command = TransformOptions::augment_args(Command::new("options"));
+  for builtin in BUILTINS {
+    for flag in builtin.flags {
+      assert!(
+        command.get_arguments().any(|arg| arg.get_long() == Some(flag)),
+        "`{}` lists --{}, which TransformOptions doesn't have", builtin.id, flag,
+      );
+    }
+  }
+
+  let registry = Registry::builtin();
+  for (builtin, entry) in BUILTINS.iter().zip(registry.entries()) {
+    let parameters: Vec<&str> = entry.info.parameters.iter().map(|parameter| parameter.name.as_str()).collect();
+    assert_eq!(parameters, builtin.flags, "{}", builtin.id);
+    assert!(entry.info.parameters.iter().all(|parameter| !parameter.description.is_empty()), "{}", builtin.id);
+  }
+}
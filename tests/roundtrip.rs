@@ -0,0 +1,374 @@
+//! `decode(encode(x)) == x` for every transform and for random pipelines of them
+use proptest::prelude::*;
+use std::fs;
+use unicode_segmentation::UnicodeSegmentation;
+use wackypixels::chaos::Manifest;
+use wackypixels::cli::TransformOptions;
+use wackypixels::pipeline::Pipeline;
+use wackypixels::pipeline_builder::builtin;
+use wackypixels::registry::Registry;
+use wackypixels::report::Reporter;
+use wackypixels::transform::Transform;
+use wackypixels::transforms::pdf::{PdfEncryption, PdfStrategy};
+
+/// Transforms that take any bytes, so they can go anywhere in a pipeline
+const BYTE_TRANSFORMS: [&str; 5] = ["pdf", "lzma", "unicode", "wav", "gzip"];
+
+fn build(id: &str, options: &TransformOptions) -> Box<dyn Transform> {
+  (builtin(id).unwrap().build)(options)
+}
+
+/// Empty, a single byte, odd lengths and now and then something big
+fn payload() -> impl Strategy<Value = Vec<u8>> {
+  prop_oneof![
+    1 => Just(Vec::new()),
+    1 => any::<u8>().prop_map(|byte| vec![byte]),
+    6 => prop::collection::vec(any::<u8>(), 0..2048),
+    1 => prop::collection::vec(any::<u8>(), 64 * 1024..256 * 1024),
+  ]
+}
+
+fn chunk_size() -> impl Strategy<Value = Option<usize>> {
+  prop_oneof![Just(None), (1usize..4096).prop_map(Some)]
+}
+
+fn pdf_strategy() -> impl Strategy<Value = PdfStrategy> {
+  prop_oneof![
+    Just(PdfStrategy::Info),
+    Just(PdfStrategy::Attachment),
+    Just(PdfStrategy::Xmp),
+    Just(PdfStrategy::Image),
+    Just(PdfStrategy::Text),
+  ]
+}
+
+fn roundtrip(id: &str, options: &TransformOptions, data: &[u8]) -> Vec<u8> {
+  let transform = build(id, options);
+  let encoded = transform.encode(data.to_vec()).unwrap();
+  transform.decode(encoded).unwrap()
+}
+
+/// Raw RGBA the way `ImageTransform` serializes it
+fn raw_image() -> impl Strategy<Value = Vec<u8>> {
+  (1u32..48, 1u32..48).prop_flat_map(|(width, height)| {
+    prop::collection::vec(any::<u8>(), (width * height * 4) as usize).prop_map(move |pixels| {
+      let mut raw = b"WPIM".to_vec();
+      raw.push(wackypixels::transforms::image::FORMAT_VERSION as u8);
+      raw.extend_from_slice(&width.to_le_bytes());
+      raw.extend_from_slice(&height.to_le_bytes());
+      raw.push(4);
+      raw.extend_from_slice(&pixels);
+      raw
+    })
+  })
+}
+
+proptest! {
+  #![proptest_config(ProptestConfig::with_cases(32))]
+
+  #[test]
+  fn lzma(data in payload(), chunk_size in chunk_size()) {
+    let options = TransformOptions { chunk_size, ..Default::default() };
+    prop_assert_eq!(roundtrip("lzma", &options, &data), data);
+  }
+
+  #[test]
+  fn gzip(data in payload()) {
+    prop_assert_eq!(roundtrip("gzip", &TransformOptions::default(), &data), data);
+  }
+
+  #[test]
+  fn wav(data in payload(), chunk_size in chunk_size()) {
+    let options = TransformOptions { chunk_size, ..Default::default() };
+    prop_assert_eq!(roundtrip("wav", &options, &data), data);
+  }
+
+  #[test]
+  fn unicode(
+    data in payload(),
+    chunk_size in chunk_size(),
+    unicode_wrap in prop::option::of(1usize..200),
+    unicode_split in prop::option::of(40usize..4000),
+    unicode_headers in any::<bool>(),
+  ) {
+    let options = TransformOptions { chunk_size, unicode_wrap, unicode_split, unicode_headers, ..Default::default() };
+    prop_assert_eq!(roundtrip("unicode", &options, &data), data);
+  }
+
+  #[test]
+  fn pdf(
+    data in payload(),
+    pdf_strategy in pdf_strategy(),
+    pdf_compress in any::<bool>(),
+    pdf_object_streams in any::<bool>(),
+  ) {
+    let options = TransformOptions { pdf_strategy, pdf_compress, pdf_object_streams, ..Default::default() };
+    prop_assert_eq!(roundtrip("pdf", &options, &data), data);
+  }
+
+  #[test]
+  fn encrypted_pdf(data in prop::collection::vec(any::<u8>(), 0..2048), pdf_strategy in pdf_strategy()) {
+    let options = TransformOptions {
+      pdf_strategy,
+      pdf_encrypt: Some(PdfEncryption::Aes256),
+      pdf_password: Some("hunter2".to_string()),
+      ..Default::default()
+    };
+    prop_assert_eq!(roundtrip("pdf", &options, &data), data);
+  }
+
+  /// The image stage goes PNG -> raw, so its round trip starts from raw pixels
+  #[test]
+  fn image(raw in raw_image()) {
+    let transform = build("image", &TransformOptions::default());
+    let png = transform.decode(raw.clone()).unwrap();
+    prop_assert_eq!(transform.encode(png).unwrap(), raw);
+  }
+}
+
+proptest! {
+  #![proptest_config(ProptestConfig::with_cases(16))]
+
+  /// Random orders of the byte transforms, through the files `Pipeline` reads and writes
+  #[test]
+  fn pipeline(
+    data in payload(),
+    ids in prop::collection::vec(prop::sample::select(&BYTE_TRANSFORMS[..]), 1..4),
+    chunk_size in chunk_size(),
+  ) {
+    let options = TransformOptions { chunk_size, ..Default::default() };
+    let pipeline = ids.iter()
+      .map(|id| build(id, &options))
+      .fold(Pipeline::new(), Pipeline::add_boxed)
+      .reporter(Reporter::quiet());
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("input.bin");
+    fs::write(&input, &data).unwrap();
+
+    let encoded = pipeline.encode(&input, &dir.path().join("encoded")).unwrap();
+    let decoded = pipeline.decode(&encoded, &dir.path().join("decoded"), None).unwrap();
+    prop_assert_eq!(fs::read(decoded).unwrap(), data, "pipeline {}", ids.join(","));
+  }
+
+  /// `--chaos` pipelines, which have to decode like any other and come out the same for a seed
+  #[test]
+  fn chaos(data in payload(), seed in any::<u64>(), stages in 1usize..8) {
+    let max_bytes = 16 * 1024 * 1024;
+    let manifest = Manifest::generate(stages, seed, max_bytes, &data).unwrap();
+    prop_assert_eq!(&Manifest::generate(stages, seed, max_bytes, &data).unwrap(), &manifest);
+    prop_assert_eq!(manifest.stages.len(), stages);
+
+    let pipeline = manifest.pipeline(&Registry::builtin()).unwrap().reporter(Reporter::quiet());
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("input.bin");
+    fs::write(&input, &data).unwrap();
+
+    let encoded = pipeline.encode(&input, &dir.path().join("encoded")).unwrap();
+    let decoded = pipeline.decode(&encoded, &dir.path().join("decoded"), None).unwrap();
+    prop_assert_eq!(fs::read(decoded).unwrap(), data, "pipeline {}", manifest.ids().join(","));
+  }
+}
+
+/// Zeros encode to runs of invisible glyphs, which are variation selectors and merge into one
+/// grapheme. Parts still cut through them, lines can't
+#[test]
+fn unicode_layout_of_zeros() {
+  let data = vec![0; 3000];
+
+  let options = TransformOptions { unicode_split: Some(280), ..Default::default() };
+  let encoded = build("unicode", &options).encode(data.clone()).unwrap();
+  let text = String::from_utf8(encoded).unwrap();
+  let parts: Vec<&str> = text.split("\n\n").collect();
+  assert!(parts.len() > 1);
+  assert!(parts.iter().all(|part| part.chars().count() <= 280));
+  assert_eq!(roundtrip("unicode", &options, &data), data);
+
+  // The run is one grapheme stuck to the last length glyph, so there's nowhere to break it
+  let options = TransformOptions { unicode_wrap: Some(40), ..Default::default() };
+  let encoded = build("unicode", &options).encode(data.clone()).unwrap();
+  let text = String::from_utf8(encoded).unwrap();
+  assert_eq!(text.lines().count(), 1);
+  assert_eq!(roundtrip("unicode", &options, &data), data);
+}
+
+/// Lines are as many graphemes wide as asked for, however many code points that takes
+#[test]
+fn unicode_wraps_by_grapheme() {
+  let data: Vec<u8> = (0..3000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8).collect();
+  let options = TransformOptions { unicode_wrap: Some(40), ..Default::default() };
+  let text = String::from_utf8(build("unicode", &options).encode(data.clone()).unwrap()).unwrap();
+  let lines: Vec<&str> = text.lines().collect();
+  assert!(lines.len() > 1);
+  assert!(lines.iter().all(|line| line.graphemes(true).count() <= 40));
+  assert!(lines[1..lines.len() - 1].iter().all(|line| line.graphemes(true).count() == 40));
+  assert!(lines.iter().any(|line| line.chars().count() > 40));
+  assert_eq!(roundtrip("unicode", &options, &data), data);
+}
+
+/// Parts pasted back out of order, quoted as a reply would quote them
+#[test]
+fn unicode_parts_shuffled_and_quoted() {
+  let options = TransformOptions { unicode_wrap: Some(20), unicode_split: Some(60), ..Default::default() };
+  let transform = build("unicode", &options);
+  let data: Vec<u8> = (0..=255).collect();
+  let text = String::from_utf8(transform.encode(data.clone()).unwrap()).unwrap();
+  let mut parts: Vec<&str> = text.split("\n\n").collect();
+  assert!(parts.len() > 3);
+
+  parts.reverse();
+  parts.swap(0, 1);
+  assert_eq!(transform.decode(parts.join("\n\n").into_bytes()).unwrap(), data);
+
+  let quoted: Vec<String> = parts.iter()
+    .map(|part| part.lines().map(|line| format!("> {}", line)).collect::<Vec<_>>().join("\n"))
+    .collect();
+  assert_eq!(transform.decode(quoted.join("\n>\n").into_bytes()).unwrap(), data);
+}
+
+/// A PDF cut off before its cross-reference table still gives up the start of its payload
+#[test]
+fn truncated_pdf_yields_a_prefix() {
+  let data: Vec<u8> = (0..20_000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8).collect();
+  for pdf_strategy in [PdfStrategy::Info, PdfStrategy::Attachment, PdfStrategy::Xmp, PdfStrategy::Image, PdfStrategy::Text] {
+    for pdf_compress in [false, true] {
+      let options = TransformOptions { pdf_strategy, pdf_compress, ..Default::default() };
+      let transform = build("pdf", &options);
+      let mut encoded = transform.encode(data.clone()).unwrap();
+      encoded.truncate(encoded.len() * 9 / 10);
+
+      let partial = transform.decode_partial(encoded).unwrap();
+      assert!(!partial.complete);
+      assert!(!partial.data.is_empty(), "{:?}, compressed: {}", pdf_strategy, pdf_compress);
+      assert!(data.starts_with(&partial.data), "{:?}, compressed: {}", pdf_strategy, pdf_compress);
+    }
+  }
+}
+
+/// The size in a cut off image's header isn't trusted with the canvas
+#[test]
+fn truncated_image_canvas_follows_the_data() {
+  let transform = build("image", &TransformOptions::default());
+  let header = |width: u32, height: u32| {
+    let mut raw = b"WPIM".to_vec();
+    raw.push(wackypixels::transforms::image::FORMAT_VERSION as u8);
+    raw.extend_from_slice(&width.to_le_bytes());
+    raw.extend_from_slice(&height.to_le_bytes());
+    raw.push(4);
+    raw
+  };
+
+  let mut huge = header(60_000, 60_000);
+  huge.extend(vec![255; 60_000 * 4 * 2]);
+  let partial = transform.decode_partial(huge).unwrap();
+  let preview = image::load_from_memory(&partial.data).unwrap();
+  assert_eq!((preview.width(), preview.height()), (60_000, 32));
+
+  let mut half = header(4, 10);
+  half.extend(vec![255; 4 * 4 * 5]);
+  let preview = image::load_from_memory(&transform.decode_partial(half).unwrap().data).unwrap();
+  assert_eq!((preview.width(), preview.height()), (4, 10));
+
+  let mut too_wide = header(u32::MAX, 1);
+  too_wide.extend([1, 2, 3]);
+  assert!(transform.decode_partial(too_wide).is_err());
+}
+
+/// Progressive decoding picks up from an intermediate and saves its own, like a plain decode
+#[test]
+fn progressive_decode_resumes() {
+  let pipeline = ["lzma", "unicode"].iter()
+    .map(|id| build(id, &TransformOptions::default()))
+    .fold(Pipeline::new(), Pipeline::add_boxed)
+    .save_intermediates(true)
+    .reporter(Reporter::quiet());
+  let dir = tempfile::tempdir().unwrap();
+  let input = dir.path().join("input.bin");
+  let data: Vec<u8> = (0..20_000u32).map(|i| (i % 97) as u8).collect();
+  fs::write(&input, &data).unwrap();
+  let encoded = dir.path().join("encoded");
+  pipeline.encode(&input, &encoded).unwrap();
+
+  let decoded = dir.path().join("decoded");
+  let pipeline = pipeline.progressive(true);
+  let output = pipeline.decode(&encoded.join("001_lzma_compression.xz"), &decoded, None).unwrap();
+  assert_eq!(fs::read(output).unwrap(), data);
+  assert!(decoded.join("001_lzma_compression_decoded.png").exists());
+  assert!(!decoded.join("002_unicode_encoding_decoded.xz").exists());
+}
+
+/// A cover that already hides something would decode to the old payload, so it's refused
+#[test]
+fn cover_with_a_payload_is_rejected() {
+  use lopdf::{Document, Object, Stream, dictionary};
+
+  let dir = tempfile::tempdir().unwrap();
+  let mut clean = Document::with_version("1.5");
+  let pages_id = clean.new_object_id();
+  let content_id = clean.add_object(Stream::new(dictionary! {}, b"0 0 m 10 10 l S".to_vec()));
+  let page_id = clean.add_object(dictionary! {
+    "Type" => "Page",
+    "Parent" => pages_id,
+    "Contents" => content_id,
+    "MediaBox" => vec![0.into(), 0.into(), 612.into(), 792.into()],
+  });
+  clean.objects.insert(pages_id, Object::Dictionary(dictionary! {
+    "Type" => "Pages",
+    "Kids" => vec![page_id.into()],
+    "Count" => 1,
+  }));
+  let catalog_id = clean.add_object(dictionary! { "Type" => "Catalog", "Pages" => pages_id });
+  clean.trailer.set("Root", catalog_id);
+  let clean_path = dir.path().join("clean.pdf");
+  clean.save(&clean_path).unwrap();
+
+  let with_cover = |path: &std::path::Path| TransformOptions { pdf_cover: Some(path.to_path_buf()), ..Default::default() };
+  let used = build("pdf", &with_cover(&clean_path)).encode(b"old".to_vec()).unwrap();
+  assert_eq!(build("pdf", &TransformOptions::default()).decode(used.clone()).unwrap(), b"old");
+
+  let used_path = dir.path().join("used.pdf");
+  fs::write(&used_path, used).unwrap();
+  let error = build("pdf", &with_cover(&used_path)).encode(b"new".to_vec()).unwrap_err();
+  assert!(error.to_string().contains("already hides a payload"), "{}", error);
+}
+
+/// Captions use the standard Helvetica font, so they're limited to what WinAnsiEncoding has
+#[test]
+fn captions_are_win_ansi() {
+  let caption = |text: &str| TransformOptions { pdf_caption: Some(text.to_string()), ..Default::default() };
+  let pdf = build("pdf", &caption("Café – (2024)")).encode(b"data".to_vec()).unwrap();
+  let doc = lopdf::Document::load_mem(&pdf).unwrap();
+  let text = doc.extract_text(&[1]).unwrap();
+  assert!(text.contains("Café – (2024)"), "{:?}", text);
+
+  let error = build("pdf", &caption("猫")).encode(b"data".to_vec()).unwrap_err();
+  assert!(error.to_string().contains("can't show '猫'"), "{}", error);
+}
+
+/// Parts that don't add up are reported as what they are, not as a missing part
+#[test]
+fn unicode_parts_beyond_the_total() {
+  let options = TransformOptions { unicode_split: Some(100), ..Default::default() };
+  let transform = build("unicode", &options);
+  let data: Vec<u8> = (0..=255).collect();
+  let text = String::from_utf8(transform.encode(data.clone()).unwrap()).unwrap();
+  let parts: Vec<&str> = text.split("\n\n").collect();
+  let total = parts.len();
+  assert!(total > 2);
+  let decode = |parts: &[String]| transform.decode(parts.join("\n\n").into_bytes());
+  let body = |part: &str| part.split_once('\n').unwrap().1.to_string();
+
+  // Pasting the same part twice is harmless
+  let mut twice: Vec<String> = parts.iter().map(|part| part.to_string()).collect();
+  twice.push(parts[1].to_string());
+  assert_eq!(decode(&twice).unwrap(), data);
+
+  let mut extra = twice.clone();
+  extra.push(format!("[wackypixels {}/{}]\n{}", total + 1, total, body(parts[1])));
+  let error = decode(&extra).unwrap_err().to_string();
+  assert!(error.contains(&format!("Part {} doesn't fit in {} parts", total + 1, total)), "{}", error);
+
+  let mut clash = twice;
+  clash.push(format!("[wackypixels 2/{}]\n{}", total, body(parts[2])));
+  let error = decode(&clash).unwrap_err().to_string();
+  assert!(error.contains(&format!("Part 2 of {} shows up twice", total)), "{}", error);
+}
@@ -0,0 +1,65 @@
+//! `clean` only ever deletes directories wackypixels marked as its own
+use std::fs;
+use wackypixels::runs::{MARKER, create_output_dir, create_run_dir, is_marked, plan_cleanup, remove};
+
+#[test]
+fn only_directories_wackypixels_created_get_marked() {
+  let dir = tempfile::tempdir().unwrap();
+  let fresh = dir.path().join("fresh");
+  create_output_dir(&fresh).unwrap();
+  assert!(is_marked(&fresh));
+  // Using it again keeps the marker
+  create_output_dir(&fresh).unwrap();
+  assert!(is_marked(&fresh));
+
+  let nested = dir.path().join("parent/nested");
+  create_output_dir(&nested).unwrap();
+  assert!(is_marked(&nested) && !is_marked(&dir.path().join("parent")));
+
+  let empty = dir.path().join("empty");
+  fs::create_dir(&empty).unwrap();
+  create_output_dir(&empty).unwrap();
+  assert!(!is_marked(&empty));
+  assert!(remove(&empty).is_err());
+
+  let used = dir.path().join("used");
+  fs::create_dir(&used).unwrap();
+  fs::write(used.join("notes.txt"), "mine").unwrap();
+  create_output_dir(&used).unwrap();
+  assert!(!is_marked(&used));
+  assert!(remove(&used).is_err());
+  assert!(used.join("notes.txt").exists());
+}
+
+#[test]
+fn cleanup_keeps_unmarked_and_newest_directories() {
+  let dir = tempfile::tempdir().unwrap();
+  let runs = dir.path().join("runs");
+  let mut created = Vec::new();
+  for input in [b"one", b"two", b"six"] {
+    created.push(create_run_dir(&runs, input).unwrap());
+    // Marker times tell the runs apart
+    std::thread::sleep(std::time::Duration::from_millis(20));
+  }
+  fs::create_dir(runs.join("unmarked")).unwrap();
+  let user = dir.path().join("user");
+  fs::create_dir(&user).unwrap();
+  fs::write(user.join("notes.txt"), "mine").unwrap();
+  let missing = dir.path().join("missing");
+
+  let cleanup = plan_cleanup(&[runs.clone(), user.clone(), missing.clone()], 1).unwrap();
+  assert_eq!(cleanup.keep, vec![created[2].clone()]);
+  let mut removed = cleanup.remove.clone();
+  removed.sort();
+  let mut older = created[..2].to_vec();
+  older.sort();
+  assert_eq!(removed, older);
+  assert_eq!(cleanup.unmarked, vec![user.clone()]);
+  assert_eq!(cleanup.missing, vec![missing]);
+
+  for dir in &cleanup.remove {
+    remove(dir).unwrap();
+    assert!(!dir.exists());
+  }
+  assert!(runs.join("unmarked").exists() && user.join("notes.txt").exists() && created[2].join(MARKER).exists());
+}
@@ -0,0 +1,56 @@
+//! What `verify` and `run` count as getting the input back
+use image::{ImageFormat, RgbaImage};
+use std::io::Cursor;
+use wackypixels::verify::{Comparison, compare};
+
+fn png(image: &RgbaImage) -> Vec<u8> {
+  let mut png = Vec::new();
+  image.write_to(&mut Cursor::new(&mut png), ImageFormat::Png).unwrap();
+  png
+}
+
+fn gradient(width: u32, height: u32) -> RgbaImage {
+  RgbaImage::from_fn(width, height, |x, y| image::Rgba([x as u8, y as u8, (x * y) as u8, 255]))
+}
+
+#[test]
+fn same_pixels_in_another_format_match() {
+  let image = gradient(16, 8);
+  let mut bmp = Vec::new();
+  image.write_to(&mut Cursor::new(&mut bmp), ImageFormat::Bmp).unwrap();
+
+  let comparison = compare(&bmp, &png(&image));
+  assert!(comparison.matches());
+  assert!(matches!(comparison, Comparison::Pixels { differing_pixels: 0, psnr_db: None, .. }));
+}
+
+#[test]
+fn changed_pixels_are_counted() {
+  let image = gradient(16, 8);
+  let mut changed = image.clone();
+  changed.put_pixel(3, 4, image::Rgba([0, 0, 0, 0]));
+
+  let comparison = compare(&png(&image), &png(&changed));
+  assert!(!comparison.matches());
+  let Comparison::Pixels { differing_pixels, max_difference, psnr_db, .. } = comparison else {
+    panic!("expected a pixel comparison, got {:?}", comparison);
+  };
+  assert_eq!(differing_pixels, 1);
+  assert_eq!(max_difference, 255);
+  assert!(psnr_db.is_some_and(|psnr| psnr > 20.0 && psnr.is_finite()));
+}
+
+#[test]
+fn resized_images_dont_match() {
+  let comparison = compare(&png(&gradient(16, 8)), &png(&gradient(8, 16)));
+  assert!(!comparison.matches());
+  assert!(matches!(comparison, Comparison::Dimensions { original: [16, 8], decoded: [8, 16] }));
+}
+
+#[test]
+fn other_data_is_compared_by_hash() {
+  assert!(compare(b"payload", b"payload").matches());
+  let comparison = compare(b"payload", b"pay1oad");
+  assert!(!comparison.matches());
+  assert!(matches!(comparison, Comparison::Bytes { original_bytes: 7, decoded_bytes: 7, .. }));
+}